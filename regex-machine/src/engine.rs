@@ -1,15 +1,136 @@
-use crate::helper::DynError;
+use crate::helper::HashMap;
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{
+    error::Error,
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+#[cfg(feature = "std")]
+use std::io::{self, BufRead, Write};
 
+pub use class::{Category, CharClass, Property};
+pub use codegen::{CodeGenError, CodeStats};
+pub use dfa::DfaCache;
+pub use evaluator::{match_at, Captures, EvalConfig, EvalContext, EvalError, EvalStats, Strategy};
+pub use parser::{ParseConfig, ParseError, PatternError};
+pub use serialize::{deserialize, serialize, DeserializeError};
+
+mod bytes;
+mod class;
 mod codegen;
+mod dfa;
 mod evaluator;
+mod optimize;
 mod parser;
+mod serialize;
+mod stream;
+#[cfg(test)]
+mod testing;
+
+/// パターンのコンパイルやマッチングで起きたエラー
+///
+/// どの段階で失敗したかで分かれるので、パターンの誤りと評価時の上限超過などを区別できる
+///
+/// ```
+/// use regex_machine::{do_matching, engine::RegexError};
+/// assert!(matches!(do_matching("+b", "b", true), Err(RegexError::Parse(_))));
+/// ```
+#[derive(Debug)]
+pub enum RegexError {
+    /// パターンの構文が正しくない
+    Parse(PatternError),
+    /// コードを生成できない
+    CodeGen(CodeGenError),
+    /// 評価に失敗した
+    Eval(EvalError),
+    /// 結果の書き出しに失敗した
+    #[cfg(feature = "std")]
+    Io(io::Error),
+}
+
+impl fmt::Display for RegexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegexError::Parse(e) => write!(f, "{e}"),
+            RegexError::CodeGen(e) => write!(f, "{e}"),
+            RegexError::Eval(e) => write!(f, "{e}"),
+            #[cfg(feature = "std")]
+            RegexError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for RegexError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RegexError::Parse(e) => Some(e),
+            RegexError::CodeGen(e) => Some(e),
+            RegexError::Eval(e) => Some(e),
+            #[cfg(feature = "std")]
+            RegexError::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<PatternError> for RegexError {
+    fn from(e: PatternError) -> Self {
+        RegexError::Parse(e)
+    }
+}
+
+impl From<CodeGenError> for RegexError {
+    fn from(e: CodeGenError) -> Self {
+        RegexError::CodeGen(e)
+    }
+}
+
+impl From<EvalError> for RegexError {
+    fn from(e: EvalError) -> Self {
+        RegexError::Eval(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for RegexError {
+    fn from(e: io::Error) -> Self {
+        RegexError::Io(e)
+    }
+}
+
+/// 名前付きグループの名前と番号の対応
+type GroupNames = HashMap<String, usize>;
+
+/// 正規表現全体に掛かるフラグ
+///
+/// `(?i)`のように、パターンの先頭で指定する
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flags {
+    /// `i`: 大文字と小文字を区別しない
+    pub case_insensitive: bool,
+    /// `x`: エスケープされていない空白を無視する
+    pub extended: bool,
+    /// `s`: `.`が改行にもマッチする
+    pub dot_all: bool,
+    /// `m`: `^`と`$`が各行の先頭と末尾にもマッチする
+    pub multi_line: bool,
+}
 
 /// 内部的に扱う疑似アセンブリの型  
 /// P131を参照のこと
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Instruction {
     /// 入力を1文字使って、`char`と等しいか検証する
     Char(char),
+    /// 入力を`String`の文字数だけ使って、`String`と等しいか検証する
+    StringMatch(String),
     /// マッチ成功
     Match,
     /// `usize`までジャンプ
@@ -22,19 +143,836 @@ pub enum Instruction {
     Start,
     /// 行末
     End,
+    /// 入力の先頭か、改行の直後
+    LineStart,
+    /// 入力の末尾か、改行の直前
+    LineEnd,
+    /// 単語境界。入力は消費しない
+    WordBoundary,
+    /// 入力を1文字使って、文字クラスに含まれるか検証する
+    CharClass(CharClass),
+    /// 入力を1文字使って、1つ目の`char`から2つ目の`char`の範囲に含まれるか検証する
+    RangeChar(char, char),
+    /// 入力を1文字使って、Unicodeの一般カテゴリに含まれるか検証する
+    Property(Property),
+    /// 次の行から`match`までを評価し、マッチした位置から`usize`へジャンプする。中身へのバックトラックはしない
+    Atomic(usize),
+    /// 次の行から`match`までがマッチする場合に`usize`へジャンプする。入力は消費しない
+    LookAhead(usize),
+    /// 次の行から`match`までがマッチしない場合に`usize`へジャンプする。入力は消費しない
+    NegLookAhead(usize),
+    /// 1つ目の`usize`文字前から、次の行から`match`までがマッチする場合に2つ目の`usize`へジャンプする。入力は消費しない
+    LookBehind(usize, usize),
+    /// 1つ目の`usize`文字前から、次の行から`match`までがマッチしない場合に2つ目の`usize`へジャンプする。入力は消費しない
+    NegLookBehind(usize, usize),
+    /// 現在の位置を`usize`番目のキャプチャ位置として記録する
+    Save(usize),
+    /// `usize`番目のグループがキャプチャした文字列と一致するか検証する
+    BackRef(usize),
 }
 
-impl std::fmt::Display for Instruction {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl core::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Instruction::Char(c) => write!(f, "char {c}"),
+            Instruction::StringMatch(s) => write!(f, "string {s}"),
             Instruction::Match => write!(f, "match"),
             Instruction::Jump(x) => write!(f, "jmp {x:>04}"),
             Instruction::Split(x, y) => write!(f, "split {x:>04}, {y:>04}"),
             Instruction::Any => write!(f, "any"),
             Instruction::Start => write!(f, "start"),
             Instruction::End => write!(f, "end"),
+            Instruction::LineStart => write!(f, "linestart"),
+            Instruction::LineEnd => write!(f, "lineend"),
+            Instruction::WordBoundary => write!(f, "wordb"),
+            Instruction::CharClass(class) => write!(f, "class {class}"),
+            Instruction::RangeChar(lo, hi) => write!(f, "range {lo}-{hi}"),
+            Instruction::Property(prop) => write!(f, "prop {prop}"),
+            Instruction::Atomic(x) => write!(f, "atomic {x:>04}"),
+            Instruction::LookAhead(x) => write!(f, "lookahead {x:>04}"),
+            Instruction::NegLookAhead(x) => write!(f, "neglookahead {x:>04}"),
+            Instruction::LookBehind(w, x) => write!(f, "lookbehind {w}, {x:>04}"),
+            Instruction::NegLookBehind(w, x) => write!(f, "neglookbehind {w}, {x:>04}"),
+            Instruction::Save(n) => write!(f, "save {n}"),
+            Instruction::BackRef(n) => write!(f, "backref {n}"),
+        }
+    }
+}
+
+/// コンパイルした正規表現
+///
+/// 一度コンパイルしておけば、多くの入力に対してパースやコード生成をやり直さずに評価できる
+///
+/// ```
+/// use regex_machine::engine::Regex;
+/// let re = Regex::new(r"[0-9]+").unwrap();
+/// assert!(re.is_match("abc123").unwrap());
+/// assert!(!re.is_match("abc").unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Regex {
+    /// 元のパターン
+    expr: String,
+    code: Box<[Instruction]>,
+    /// パターン中で指定されたフラグ
+    flags: Flags,
+    /// マッチの1文字目になりうる文字。分からない場合は`None`
+    first: Option<CharClass>,
+    /// 名前付きグループの名前と番号
+    names: GroupNames,
+    /// コンパイルするたびに振る番号。`DfaCache`がどのプログラムで作られたかを見分けるのに使う
+    id: usize,
+}
+
+/// 次にコンパイルする`Regex`の番号
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+impl Regex {
+    /// パターンをコンパイルする
+    pub fn new(expr: &str) -> Result<Regex, RegexError> {
+        Regex::with_config(expr, &ParseConfig::default())
+    }
+
+    /// `config`の設定でパースして、パターンをコンパイルする
+    pub fn with_config(expr: &str, config: &ParseConfig) -> Result<Regex, RegexError> {
+        Regex::with_flags(expr, config, Flags::default())
+    }
+
+    /// `flags`を指定した状態からパースして、パターンをコンパイルする
+    fn with_flags(expr: &str, config: &ParseConfig, flags: Flags) -> Result<Regex, RegexError> {
+        let (code, flags, names) = compile_with_names(expr, config, flags)?;
+        let first = codegen::first_chars(&code, &flags);
+        Ok(Regex {
+            expr: expr.to_string(),
+            code,
+            flags,
+            first,
+            names,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        })
+    }
+
+    /// コンパイルする前のパターン
+    pub fn as_str(&self) -> &str {
+        &self.expr
+    }
+
+    /// 入力のどこかにマッチする部分があるか検証する
+    ///
+    /// マッチの範囲は求めずに、見つけた時点で返す。後方参照がなければ、Pike VMで入力を1度だけ読む
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// assert!(Regex::new("bc").unwrap().is_match("abc").unwrap());
+    /// ```
+    pub fn is_match(&self, line: &str) -> Result<bool, EvalError> {
+        let line = line.chars().collect::<Vec<char>>();
+        let has_back_ref = self
+            .code
+            .iter()
+            .any(|inst| matches!(inst, Instruction::BackRef(_)));
+        if !has_back_ref {
+            return evaluator::pike_search(&self.code, &line, &self.flags, self.first.as_ref());
+        }
+
+        let span = find_at(
+            &self.code,
+            &line,
+            &self.flags,
+            self.first.as_ref(),
+            0,
+            Strategy::Depth,
+            &EvalConfig::default(),
+        )?;
+        Ok(span.is_some())
+    }
+
+    /// `cache`に残っているDFAを使って、`Regex::is_match`と同じ検証をする
+    ///
+    /// 同じ`Regex`で何度も評価する場合に速くなる。別の`Regex`で使った`cache`は作り直す。
+    /// DFAにできないパターンの場合は、`Regex::is_match`で評価する
+    ///
+    /// ```
+    /// use regex_machine::engine::{DfaCache, Regex};
+    /// let re = Regex::new("(ab|cd)+x").unwrap();
+    /// let mut cache = DfaCache::default();
+    /// assert!(re.is_match_with_cache("zzcdabx", &mut cache).unwrap());
+    /// assert!(!re.is_match_with_cache("abcd", &mut cache).unwrap());
+    /// ```
+    pub fn is_match_with_cache(&self, line: &str, cache: &mut DfaCache) -> Result<bool, EvalError> {
+        let chars = line.chars().collect::<Vec<char>>();
+        match dfa::search_dfa(self.id, &self.code, &chars, &self.flags, cache) {
+            Some(result) => result,
+            None => self.is_match(line),
+        }
+    }
+
+    /// 入力の先頭から始まるマッチがあるか検証する
+    ///
+    /// パターンの先頭に`^`を付けたのと同じ結果になるが、パターンは書き換えずに先頭の位置だけを試す
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new("abc").unwrap();
+    /// assert!(re.anchored_at_start("abcdef").unwrap());
+    /// assert!(!re.anchored_at_start("xabc").unwrap());
+    /// ```
+    pub fn anchored_at_start(&self, line: &str) -> Result<bool, EvalError> {
+        let line = line.chars().collect::<Vec<char>>();
+        let span = find_at(
+            &self.code,
+            &line,
+            &self.flags,
+            self.first.as_ref(),
+            0,
+            Strategy::Depth,
+            &EvalConfig::default().anchored(true),
+        )?;
+        Ok(span.is_some())
+    }
+
+    /// 入力の中で最も左にあるマッチを返す
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let m = Regex::new("bc").unwrap().find("abc").unwrap().unwrap();
+    /// assert_eq!((m.start(), m.end(), m.as_str()), (1, 3, "bc"));
+    /// ```
+    pub fn find<'h>(&self, line: &'h str) -> Result<Option<Match<'h>>, EvalError> {
+        self.find_iter(line).next().transpose()
+    }
+
+    /// 入力の中の重ならないマッチを、左から順に返すイテレータを作る
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new("ab").unwrap();
+    /// let matches = re.find_iter("abxab").collect::<Result<Vec<_>, _>>().unwrap();
+    /// let spans = matches.iter().map(|m| (m.start(), m.end())).collect::<Vec<_>>();
+    /// assert_eq!(spans, vec![(0, 2), (3, 5)]);
+    /// ```
+    pub fn find_iter<'h>(&self, line: &'h str) -> Matches<'_, 'h> {
+        Matches::new(self, line, Strategy::Depth)
+    }
+
+    /// 入力の中の重ならないマッチの数を返す
+    ///
+    /// `find_iter`と同じ順にマッチを探すが、`Match`は作らずに数えるだけ
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// assert_eq!(Regex::new(r"[0-9]").unwrap().count_matches("a1b2c3").unwrap(), 3);
+    /// ```
+    pub fn count_matches(&self, line: &str) -> Result<usize, EvalError> {
+        let mut matches = self.find_iter(line);
+        let mut count = 0;
+        while let Some(span) = matches.next_span() {
+            span?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// `find`と同じく最も左にあるマッチを探すが、その位置から始まる最も短いマッチを返す
+    ///
+    /// Pike VMで入力を前から読み、最初に`match`に到達した時点で止める。後方参照があるパターンには使えない
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new("a+").unwrap();
+    /// assert_eq!(re.find("aaa").unwrap().unwrap().range(), (0, 3));
+    /// assert_eq!(re.find_shortest("aaa").unwrap().unwrap().range(), (0, 1));
+    /// ```
+    pub fn find_shortest<'h>(&self, line: &'h str) -> Result<Option<Match<'h>>, EvalError> {
+        self.find_iter_shortest(line).next().transpose()
+    }
+
+    /// `find_iter`と同じく重ならないマッチを左から順に返すが、それぞれ最も短いマッチにする
+    pub fn find_iter_shortest<'h>(&self, line: &'h str) -> Matches<'_, 'h> {
+        Matches {
+            shortest: true,
+            ..Matches::new(self, line, Strategy::Pike)
+        }
+    }
+
+    /// 入力の中で最初にマッチした部分について、各グループがキャプチャした部分を返す
+    ///
+    /// グループには番号でも、`(?<name>...)`で付けた名前でもアクセスできる
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new(r"(?<user>[[:word:]]+)@([[:word:]]+)").unwrap();
+    /// let caps = re.captures("mail: foo@example").unwrap().unwrap();
+    /// assert_eq!(caps.get(0), Some("foo@example"));
+    /// assert_eq!(caps.name("user"), Some("foo"));
+    /// assert_eq!(caps.get(2), Some("example"));
+    /// ```
+    pub fn captures<'h>(&self, line: &'h str) -> Result<Option<CaptureGroups<'_, 'h>>, EvalError> {
+        let chars = line.chars().collect::<Vec<char>>();
+        let captures = self.captures_at(&chars, 0)?;
+        Ok(captures.map(|captures| CaptureGroups::new(line, captures, &self.names)))
+    }
+
+    /// 入力の`from`以降で最も左にあるマッチについて、各グループがキャプチャした範囲を返す
+    fn captures_at(&self, chars: &[char], from: usize) -> Result<Option<Captures>, EvalError> {
+        for start in start_positions(self.first.as_ref(), chars, from, false) {
+            let captures = evaluator::eval_captures(
+                &self.code,
+                chars,
+                start,
+                &self.flags,
+                &EvalConfig::default(),
+            )?;
+            if captures.is_some() {
+                return Ok(captures);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// 入力の中の重ならないマッチを、すべて`replacement`に置き換えた文字列を返す
+    ///
+    /// `replacement`の`$1`や`${name}`は、そのグループがキャプチャした部分に置き換える。`$`そのものは`$$`と書く。
+    /// 存在しないグループや、マッチに加わらなかったグループは空文字列になる
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new(r"[0-9]+").unwrap();
+    /// assert_eq!(re.replace_all("a1b22", "#").unwrap(), "a#b#");
+    /// let re = Regex::new(r"(?<user>[[:word:]]+)@([[:word:]]+)").unwrap();
+    /// assert_eq!(re.replace_all("foo@bar", "$2: ${user}").unwrap(), "bar: foo");
+    /// ```
+    pub fn replace_all(&self, line: &str, replacement: &str) -> Result<String, EvalError> {
+        let template = Template::parse(replacement);
+        let chars = line.chars().collect::<Vec<char>>();
+        let offsets = byte_offsets(line);
+        let mut replaced = String::with_capacity(line.len());
+        // 直前のマッチの終わり
+        let mut last = 0;
+        // `find_iter`と同じ順にマッチを探すが、マッチの範囲はキャプチャと一緒に1度の評価で求める
+        let mut from = 0;
+        while let Some(captures) = self.captures_at(&chars, from)? {
+            // 0番目のグループはマッチ全体なので、必ずある
+            let Some((start, end)) = captures[0] else {
+                return Err(EvalError::InvalidContext);
+            };
+            replaced.push_str(&line[offsets[last]..offsets[start]]);
+            if template.has_groups() {
+                let groups = CaptureGroups::with_offsets(line, &offsets, captures, &self.names);
+                template.expand(&groups, &mut replaced);
+            } else {
+                template.expand_literal(&mut replaced);
+            }
+            last = end;
+            // 空文字列にマッチした場合は、1文字進めてから探す
+            from = if end > start { end } else { end + 1 };
+        }
+        replaced.push_str(&line[offsets[last]..]);
+
+        Ok(replaced)
+    }
+
+    /// 入力を重ならないマッチの位置で区切った文字列を返す
+    ///
+    /// 区切りが並んでいたり、入力の先頭や末尾にある場合は、そこに空の文字列を入れる
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new(r"[0-9]").unwrap();
+    /// assert_eq!(re.split("a1b2c").unwrap(), vec!["a", "b", "c"]);
+    /// ```
+    pub fn split(&self, line: &str) -> Result<Vec<String>, EvalError> {
+        let offsets = byte_offsets(line);
+        let mut pieces = Vec::new();
+        // 直前のマッチの終わり
+        let mut last = 0;
+        for m in self.find_iter(line) {
+            let (start, end) = m?.range();
+            pieces.push(line[offsets[last]..offsets[start]].to_string());
+            last = end;
+        }
+        pieces.push(line[offsets[last]..].to_string());
+
+        Ok(pieces)
+    }
+
+    /// 入力の先頭から`strategy`の方式で評価して、マッチするか検証する
+    ///
+    /// `do_matching`と同じく、入力の先頭から始まるマッチだけを探す
+    pub fn eval(
+        &self,
+        line: &str,
+        strategy: Strategy,
+        config: &EvalConfig,
+    ) -> Result<bool, EvalError> {
+        let flags = config.apply(&self.flags);
+        let line = line.chars().collect::<Vec<char>>();
+        evaluator::eval(&self.code, &line, strategy, &flags, config)
+    }
+
+    /// コンパイルした結果をバイト列にする。`Regex::from_bytes`で、パースし直さずに読み込める
+    ///
+    /// ```
+    /// use regex_machine::engine::Regex;
+    /// let re = Regex::new(r"(?<y>[0-9]+)-x").unwrap();
+    /// let loaded = Regex::from_bytes(&re.to_bytes()).unwrap();
+    /// assert_eq!(loaded, re);
+    /// assert_eq!(loaded.captures("12-x").unwrap().unwrap().name("y"), Some("12"));
+    /// ```
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serialize::serialize_regex(&self.expr, &self.code, &self.flags, &self.names)
+    }
+
+    /// `Regex::to_bytes`で作ったバイト列から読み込む
+    ///
+    /// 命令列は`deserialize`と同じく構造を確かめるが、元のパターンと一致するかは確かめない
+    pub fn from_bytes(bytes: &[u8]) -> Result<Regex, DeserializeError> {
+        let (expr, code, flags, names) = serialize::deserialize_regex(bytes)?;
+        let first = codegen::first_chars(&code, &flags);
+        Ok(Regex {
+            expr,
+            code: code.into_boxed_slice(),
+            flags,
+            first,
+            names,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        })
+    }
+}
+
+/// 各文字が始まるバイト位置。最後に入力の長さを加えるので、文字単位の位置からバイト単位の位置を引ける
+fn byte_offsets(line: &str) -> Vec<usize> {
+    line.char_indices()
+        .map(|(i, _)| i)
+        .chain([line.len()])
+        .collect()
+}
+
+/// 元のパターンとフラグが同じときに等しい
+///
+/// 比べるのはパターンの文字列で、コンパイルした結果ではない。`a`と`(?:a)`は同じ入力にマッチするが、等しくない。
+/// `RegexBuilder`で指定したフラグも比べる
+impl PartialEq for Regex {
+    fn eq(&self, other: &Self) -> bool {
+        self.expr == other.expr && self.flags == other.flags
+    }
+}
+
+impl Eq for Regex {}
+
+/// `PartialEq`と同じく、元のパターンとフラグからハッシュ値を求める
+impl Hash for Regex {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.expr.hash(state);
+        self.flags.hash(state);
+    }
+}
+
+/// `Regex::new`と同じくパターンをコンパイルする
+///
+/// ```
+/// use regex_machine::Regex;
+/// let re: Regex = "abc".parse().unwrap();
+/// assert!(re.is_match("xabc").unwrap());
+/// ```
+impl FromStr for Regex {
+    type Err = RegexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Regex::new(s)
+    }
+}
+
+/// フラグなどを指定して`Regex`を作るビルダー
+///
+/// パターンの先頭に`(?i)`のように書く代わりに、フラグを指定できる。パターン中で指定したフラグも有効になる
+///
+/// ```
+/// use regex_machine::engine::RegexBuilder;
+/// let re = RegexBuilder::new("^b.c").multiline(true).dot_all(true).build().unwrap();
+/// assert!(re.is_match("a\nb\nc").unwrap());
+/// ```
+#[derive(Debug, Clone)]
+pub struct RegexBuilder {
+    expr: String,
+    flags: Flags,
+    config: ParseConfig,
+}
+
+impl RegexBuilder {
+    /// パターン`expr`のビルダーを作る。フラグは何も指定しない状態
+    pub fn new(expr: &str) -> Self {
+        RegexBuilder {
+            expr: expr.to_string(),
+            flags: Flags::default(),
+            config: ParseConfig::default(),
+        }
+    }
+
+    /// 大文字と小文字を区別しない。`(?i)`と同じ
+    pub fn case_insensitive(mut self, yes: bool) -> Self {
+        self.flags.case_insensitive = yes;
+        self
+    }
+
+    /// `^`と`$`が各行の先頭と末尾にもマッチする。`(?m)`と同じ
+    pub fn multiline(mut self, yes: bool) -> Self {
+        self.flags.multi_line = yes;
+        self
+    }
+
+    /// `.`が改行にもマッチする。`(?s)`と同じ
+    pub fn dot_all(mut self, yes: bool) -> Self {
+        self.flags.dot_all = yes;
+        self
+    }
+
+    /// パースの設定
+    pub fn config(mut self, config: ParseConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// 指定した設定でパターンをコンパイルする
+    pub fn build(&self) -> Result<Regex, RegexError> {
+        Regex::with_flags(&self.expr, &self.config, self.flags)
+    }
+}
+
+/// `replace_all`の置き換える文字列の部品
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Piece {
+    /// そのまま使う文字列
+    Literal(String),
+    /// `$1`や`${1}`
+    Group(usize),
+    /// `${name}`
+    Name(String),
+}
+
+/// `$1`や`${name}`を含む、置き換える文字列
+///
+/// 一度だけパースしておき、マッチごとにグループがキャプチャした部分を埋め込む
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    /// `$`で始まる参照を読み取る。参照として読めない`$`は、ただの文字として扱う
+    fn parse(replacement: &str) -> Self {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = replacement.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                literal.push(c);
+                continue;
+            }
+            let piece = match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    literal.push('$');
+                    continue;
+                }
+                Some(d) if d.is_ascii_digit() => {
+                    let mut n = String::new();
+                    while let Some(d) = chars.next_if(char::is_ascii_digit) {
+                        n.push(d);
+                    }
+                    // 大きすぎる番号のグループは存在しない
+                    Piece::Group(n.parse().unwrap_or(usize::MAX))
+                }
+                Some('{') => {
+                    // `}`で閉じられていない`${`は、ただの文字
+                    let Some(len) = chars.clone().skip(1).position(|c| c == '}') else {
+                        literal.push('$');
+                        continue;
+                    };
+                    chars.next();
+                    let name = chars.by_ref().take(len).collect::<String>();
+                    chars.next();
+                    match name.parse() {
+                        Ok(n) => Piece::Group(n),
+                        Err(_) => Piece::Name(name),
+                    }
+                }
+                _ => {
+                    literal.push('$');
+                    continue;
+                }
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(core::mem::take(&mut literal)));
+            }
+            pieces.push(piece);
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Template { pieces }
+    }
+
+    /// グループを参照しているか。参照がなければ、キャプチャを求めずに済む
+    fn has_groups(&self) -> bool {
+        self.pieces
+            .iter()
+            .any(|piece| !matches!(piece, Piece::Literal(_)))
+    }
+
+    /// 参照をキャプチャした部分に置き換えて、`out`に追加する
+    fn expand(&self, groups: &CaptureGroups, out: &mut String) {
+        for piece in &self.pieces {
+            let s = match piece {
+                Piece::Literal(s) => Some(s.as_str()),
+                Piece::Group(n) => groups.get(*n),
+                Piece::Name(name) => groups.name(name),
+            };
+            out.push_str(s.unwrap_or_default());
+        }
+    }
+
+    /// 参照がないときに、文字列をそのまま`out`に追加する
+    fn expand_literal(&self, out: &mut String) {
+        for piece in &self.pieces {
+            if let Piece::Literal(s) = piece {
+                out.push_str(s);
+            }
+        }
+    }
+}
+
+/// `Regex::captures`が返す、各グループがキャプチャした部分
+///
+/// グループの名前は`Regex`から、キャプチャした文字列は入力から借りる。
+/// 取り出した文字列は入力と同じだけ使えるので、`Regex`より長く持っておける
+#[derive(Debug, Clone)]
+pub struct CaptureGroups<'r, 'h> {
+    line: &'h str,
+    /// 各グループがキャプチャしたバイト単位の範囲。0番目はマッチ全体
+    spans: Vec<Option<(usize, usize)>>,
+    names: &'r GroupNames,
+}
+
+impl<'r, 'h> CaptureGroups<'r, 'h> {
+    fn new(line: &'h str, captures: Captures, names: &'r GroupNames) -> Self {
+        CaptureGroups::with_offsets(line, &byte_offsets(line), captures, names)
+    }
+
+    /// `offsets`は`byte_offsets(line)`で、同じ入力で何度も作る場合に求め直さずに済む
+    fn with_offsets(
+        line: &'h str,
+        offsets: &[usize],
+        captures: Captures,
+        names: &'r GroupNames,
+    ) -> Self {
+        let spans = captures
+            .into_iter()
+            .map(|span| span.map(|(start, end)| (offsets[start], offsets[end])))
+            .collect();
+        CaptureGroups { line, spans, names }
+    }
+
+    /// `i`番目のグループがキャプチャした部分。0番目はマッチ全体
+    ///
+    /// グループがマッチに加わらなかった場合や、`i`番目のグループがない場合は`None`
+    pub fn get(&self, i: usize) -> Option<&'h str> {
+        let (start, end) = (*self.spans.get(i)?)?;
+        Some(&self.line[start..end])
+    }
+
+    /// `name`という名前のグループがキャプチャした部分
+    pub fn name(&self, name: &str) -> Option<&'h str> {
+        self.get(*self.names.get(name)?)
+    }
+
+    /// マッチ全体を含めたグループの数
+    pub fn len(&self) -> usize {
+        self.spans.len()
+    }
+
+    /// グループが1つもないか
+    ///
+    /// マッチ全体の0番目が必ずあるので常に`false`だが、`len`と対にするため(clippyの`len_without_is_empty`)に用意している
+    pub fn is_empty(&self) -> bool {
+        self.spans.is_empty()
+    }
+}
+
+/// 入力の中でマッチした部分
+///
+/// 位置は文字単位で、終わりの位置は含まない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match<'h> {
+    start: usize,
+    end: usize,
+    /// 入力から切り出したマッチした部分
+    text: &'h str,
+}
+
+impl<'h> Match<'h> {
+    /// マッチの始まりの位置
+    pub fn start(&self) -> usize {
+        self.start
+    }
+
+    /// マッチの終わりの位置
+    pub fn end(&self) -> usize {
+        self.end
+    }
+
+    /// マッチの始まりと終わりの位置
+    pub fn range(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+
+    /// マッチした部分の文字列
+    pub fn as_str(&self) -> &'h str {
+        self.text
+    }
+}
+
+/// `Regex::find_iter`が返すイテレータ
+///
+/// 重ならないマッチを、`next`を呼ぶたびに左から1つずつ探して返す。評価でエラーになった場合は、そのエラーを返して終わる
+#[derive(Debug)]
+pub struct Matches<'r, 'h> {
+    regex: &'r Regex,
+    line: &'h str,
+    chars: Vec<char>,
+    /// 文字単位の位置に対応するバイト単位の位置
+    offsets: Vec<usize>,
+    strategy: Strategy,
+    /// `true`のときは、優先される分岐ではなく最も短いマッチを返す
+    shortest: bool,
+    /// 次に探し始める位置。探し終わった場合は`None`
+    from: Option<usize>,
+}
+
+impl<'r, 'h> Matches<'r, 'h> {
+    fn new(regex: &'r Regex, line: &'h str, strategy: Strategy) -> Self {
+        Matches {
+            regex,
+            line,
+            chars: line.chars().collect(),
+            offsets: byte_offsets(line),
+            strategy,
+            shortest: false,
+            from: Some(0),
+        }
+    }
+}
+
+impl Matches<'_, '_> {
+    /// 次のマッチの範囲を探し、その次に探し始める位置へ進める
+    fn next_span(&mut self) -> Option<Result<(usize, usize), EvalError>> {
+        let from = self.from?;
+        let regex = self.regex;
+        let span = if self.shortest {
+            find_shortest_at(
+                &regex.code,
+                &self.chars,
+                &regex.flags,
+                regex.first.as_ref(),
+                from,
+            )
+        } else {
+            find_at(
+                &regex.code,
+                &self.chars,
+                &regex.flags,
+                regex.first.as_ref(),
+                from,
+                self.strategy,
+                &EvalConfig::default(),
+            )
+        };
+        match span {
+            Ok(Some((start, end))) => {
+                // 空文字列にマッチした場合は、1文字進めてから探す
+                self.from = Some(if end > start { end } else { end + 1 });
+                Some(Ok((start, end)))
+            }
+            Ok(None) => {
+                self.from = None;
+                None
+            }
+            Err(e) => {
+                self.from = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<'h> Iterator for Matches<'_, 'h> {
+    type Item = Result<Match<'h>, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let span = self.next_span()?;
+        Some(span.map(|(start, end)| Match {
+            start,
+            end,
+            text: &self.line[self.offsets[start]..self.offsets[end]],
+        }))
+    }
+}
+
+/// `reader`から読んだ行のうち、`regex`にマッチする行を行番号と一緒に返すイテレータを作る
+///
+/// `grep`のように1行ずつ読むので、入力全体をメモリに読み込まない。行番号は1から数え、行末の改行は含めない
+///
+/// ```
+/// use std::io::Cursor;
+/// use regex_machine::engine::{grep, Regex};
+/// let re = Regex::new(r"[0-9]+").unwrap();
+/// let lines = grep(&re, Cursor::new("a1\nb\nc22\n")).collect::<Result<Vec<_>, _>>().unwrap();
+/// assert_eq!(lines, vec![(1, "a1".to_string()), (3, "c22".to_string())]);
+/// ```
+#[cfg(feature = "std")]
+pub fn grep<R: BufRead>(regex: &Regex, reader: R) -> Grep<'_, R> {
+    Grep {
+        regex,
+        lines: reader.lines().enumerate(),
+        done: false,
+    }
+}
+
+/// `grep`が返すイテレータ
+///
+/// 読み込みや評価でエラーになった場合は、そのエラーを返して終わる
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct Grep<'r, R> {
+    regex: &'r Regex,
+    lines: core::iter::Enumerate<io::Lines<R>>,
+    /// エラーを返した後は`true`
+    done: bool,
+}
+
+#[cfg(feature = "std")]
+impl<R: BufRead> Iterator for Grep<'_, R> {
+    type Item = Result<(usize, String), RegexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
+        for (i, line) in self.lines.by_ref() {
+            let matched = line
+                .map_err(RegexError::from)
+                .and_then(|line| Ok((self.regex.is_match(&line)?, line)));
+            match matched {
+                Ok((true, line)) => return Some(Ok((i + 1, line))),
+                Ok((false, _)) => {}
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
     }
 }
 
@@ -48,24 +986,109 @@ impl std::fmt::Display for Instruction {
 /// ## 返値
 /// 与えられた正規表現にエラーがある場合、`Err`を返す。そうでない場合、出力は標準出力に出るため返値はない
 ///
-pub fn print(expr: &str) -> Result<(), DynError> {
-    let ast = parser::parse(expr)?;
-
-    println!("Ast: {ast:?}");
+#[cfg(feature = "std")]
+pub fn print(expr: &str) -> Result<(), RegexError> {
+    print_to(expr, &mut io::stdout())
+}
 
-    let code = codegen::get_code(&ast).map_err(Box::new)?;
-    let code = code.iter().map(|inst| inst.to_string()).collect::<Vec<_>>();
-    println!("code:");
-    println!("{}", code.join("\n"));
+/// `print`と同じ内容を`out`に書き出す
+///
+/// ```
+/// use regex_machine::engine::print_to;
+/// let mut out = Vec::new();
+/// print_to("a|b", &mut out).unwrap();
+/// assert!(String::from_utf8(out).unwrap().starts_with("Ast: "));
+/// ```
+#[cfg(feature = "std")]
+pub fn print_to<W: Write>(expr: &str, out: &mut W) -> Result<(), RegexError> {
+    let listing = listing(expr)?;
+    out.write_all(listing.as_bytes())?;
 
     Ok(())
 }
 
+/// `print`が出力する、Astとコードを並べた文字列を返す
+///
+/// ```
+/// use regex_machine::engine::listing;
+/// let text = listing("a*").unwrap();
+/// assert!(text.starts_with("Ast: "));
+/// assert!(text.ends_with("0003: match\n"));
+/// ```
+pub fn listing(expr: &str) -> Result<String, RegexError> {
+    let ast = parser::parse(expr).map_err(|e| e.with_pattern(expr))?;
+    let ast = optimize::optimize(ast);
+    let code = codegen::get_code(&ast)?;
+
+    Ok(format!("Ast: {ast:?}\ncode:\n{}\n", code_listing(&code)))
+}
+
+/// 正規表現をコンパイルした命令列を、Graphvizのdot形式で返す
+///
+/// 命令1つが1つのノードになり、`split`からは2本、`jmp`からは1本の辺が出る
+///
+/// ```
+/// use regex_machine::engine::to_dot;
+/// let dot = to_dot("ab|cd").unwrap();
+/// assert!(dot.starts_with("digraph regex {"));
+/// ```
+pub fn to_dot(expr: &str) -> Result<String, RegexError> {
+    let ast = parser::parse(expr).map_err(|e| e.with_pattern(expr))?;
+    let ast = optimize::optimize(ast);
+    let code = codegen::get_code(&ast)?;
+
+    let mut dot = String::from("digraph regex {\n");
+    for (pc, inst) in code.iter().enumerate() {
+        let label = format!("{pc:>04}: {inst}");
+        dot.push_str(&format!(
+            "    n{pc} [label=\"{}\"];\n",
+            label.escape_debug()
+        ));
+    }
+    for (pc, inst) in code.iter().enumerate() {
+        for addr in codegen::successors(inst, pc) {
+            dot.push_str(&format!("    n{pc} -> n{addr};\n"));
+        }
+    }
+    dot.push('}');
+
+    Ok(dot)
+}
+
+/// 正規表現をコンパイルしたプログラムの、命令の数を返す
+///
+/// 大きな入力に使う前に、どれくらいの大きさのプログラムになるかを確かめられる
+///
+/// ```
+/// use regex_machine::engine::code_stats;
+/// let stats = code_stats("a{3}").unwrap();
+/// assert_eq!(stats.insts, 4);
+/// ```
+pub fn code_stats(expr: &str) -> Result<CodeStats, RegexError> {
+    let ast = parser::parse(expr).map_err(|e| e.with_pattern(expr))?;
+    let ast = optimize::optimize(ast);
+    let stats = codegen::get_code_stats(&ast)?;
+
+    Ok(stats)
+}
+
+/// 命令の前にアドレスを付けて、1行に1命令ずつ並べる
+fn code_listing(code: &[Instruction]) -> String {
+    code.iter()
+        .enumerate()
+        .map(|(pc, inst)| format!("{pc:>04}: {inst}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
 /// 正規表現を用いて、文字列とマッチングを行う
 ///
+/// 空のパターンや`a*`のように何も消費せずにマッチできるパターンは、空の入力にもマッチする
+///
 /// ```
 /// use regex_machine::do_matching;
 /// assert!(do_matching("abc|(de|cd)+","decddede",true).unwrap());
+/// assert!(do_matching("", "", true).unwrap());
 /// ```
 ///
 /// ## 引数
@@ -76,22 +1099,558 @@ pub fn print(expr: &str) -> Result<(), DynError> {
 /// ## 返値
 /// エラーなく実行でき、かつマッチした場合は`Ok(true)`を返す。エラーなく実行でき、マッチしなかった場合は`Ok(false)`を返す
 ///
-pub fn do_matching(expr: &str, line: &str, is_depth: bool) -> Result<bool, DynError> {
-    let ast = parser::parse(expr)?;
-    let code = codegen::get_code(&ast)?;
-    let line = line.chars().collect::<Vec<char>>();
-    let result = evaluator::eval(&code, &line, is_depth)?;
+pub fn do_matching(expr: &str, line: &str, is_depth: bool) -> Result<bool, RegexError> {
+    do_matching_with_config(expr, line, is_depth, &ParseConfig::default())
+}
 
-    Ok(result)
+/// `config`の設定でパースして、`do_matching`と同じようにマッチングを行う
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_config, ParseConfig};
+/// let config = ParseConfig::default().max_repeat(10);
+/// assert!(do_matching_with_config("a{100}", "a", true, &config).is_err());
+/// ```
+pub fn do_matching_with_config(
+    expr: &str,
+    line: &str,
+    is_depth: bool,
+    config: &ParseConfig,
+) -> Result<bool, RegexError> {
+    let strategy = if is_depth {
+        Strategy::Depth
+    } else {
+        Strategy::Width
+    };
+    matching(expr, line, strategy, config, &EvalConfig::default())
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// `do_matching`と同じようにマッチングを行う。`case_insensitive`が`true`のときは大文字と小文字を区別しない
+///
+/// ```
+/// use regex_machine::engine::do_matching_opts;
+/// assert!(do_matching_opts("hello", "HELLO", true, true).unwrap());
+/// assert!(!do_matching_opts("hello", "HELLO", true, false).unwrap());
+/// ```
+pub fn do_matching_opts(
+    expr: &str,
+    line: &str,
+    is_depth: bool,
+    case_insensitive: bool,
+) -> Result<bool, RegexError> {
+    let strategy = if is_depth {
+        Strategy::Depth
+    } else {
+        Strategy::Width
+    };
+    let config = EvalConfig::default().case_insensitive(case_insensitive);
+    matching(expr, line, strategy, &ParseConfig::default(), &config)
+}
 
-    #[test]
-    fn test_do_matching() {
-        // パースエラー
+/// `strategy`の方式で、`do_matching`と同じようにマッチングを行う
+///
+/// `Strategy::Pike`は`(a|a)*b`のようなパターンでも、入力の長さに対して線形時間で終わる
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_strategy, Strategy};
+/// assert!(do_matching_with_strategy("abc|(de|cd)+", "decddede", Strategy::Pike).unwrap());
+/// ```
+pub fn do_matching_with_strategy(
+    expr: &str,
+    line: &str,
+    strategy: Strategy,
+) -> Result<bool, RegexError> {
+    matching(
+        expr,
+        line,
+        strategy,
+        &ParseConfig::default(),
+        &EvalConfig::default(),
+    )
+}
+
+/// `config`の設定で評価して、`do_matching_with_strategy`と同じようにマッチングを行う
+///
+/// 信頼できないパターンや入力を扱うときに、評価に掛かる時間を制限できる
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_eval_config, EvalConfig, Strategy};
+/// let config = EvalConfig::default().step_limit(1000);
+/// let line = format!("{}b", "a".repeat(100));
+/// assert!(do_matching_with_eval_config("(a|aa)+$", &line, Strategy::Depth, &config).is_err());
+/// ```
+pub fn do_matching_with_eval_config(
+    expr: &str,
+    line: &str,
+    strategy: Strategy,
+    config: &EvalConfig,
+) -> Result<bool, RegexError> {
+    matching(expr, line, strategy, &ParseConfig::default(), config)
+}
+
+fn matching(
+    expr: &str,
+    line: &str,
+    strategy: Strategy,
+    config: &ParseConfig,
+    eval_config: &EvalConfig,
+) -> Result<bool, RegexError> {
+    let regex = Regex::with_config(expr, config)?;
+    let result = regex.eval(line, strategy, eval_config)?;
+
+    Ok(result)
+}
+
+/// `strategy`の方式でマッチングを行い、結果と評価中に実行した命令の数を返す
+///
+/// 同じパターンと入力で、評価の方式ごとの手間を比べられる
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_stats, Strategy};
+/// let stats = do_matching_with_stats("(a|b)*c", "ababc", Strategy::Pike).unwrap();
+/// assert!(stats.matched);
+/// ```
+pub fn do_matching_with_stats(
+    expr: &str,
+    line: &str,
+    strategy: Strategy,
+) -> Result<EvalStats, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let line = line.chars().collect::<Vec<char>>();
+    let stats = evaluator::eval_with_stats(&code, &line, strategy, &flags)?;
+
+    Ok(stats)
+}
+
+/// 文字に変換せずにバイト列のまま、`do_matching`と同じようにマッチングを行う
+///
+/// 1バイトを1文字として扱うので、UTF-8ではない入力にも使える。パターンはASCIIの文字だけで書く必要があり、
+/// 先読み・後読み、アトミックグループ、後方参照は使えない
+///
+/// ```
+/// use regex_machine::engine::do_matching_bytes;
+/// assert!(do_matching_bytes(r"GET /[[:word:]]+", b"GET /index HTTP/1.1").unwrap());
+/// assert!(do_matching_bytes("a.c", b"a\xffc").unwrap());
+/// ```
+pub fn do_matching_bytes(expr: &str, line: &[u8]) -> Result<bool, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let result = bytes::eval_bytes(&code, line, &flags)?;
+
+    Ok(result)
+}
+
+/// 入力を`Vec`に集めずに、イテレータから1文字ずつ読みながら`do_matching`と同じようにマッチングを行う
+///
+/// 大きな入力や少しずつ届く入力でも、すべてを読み込まずに評価できる。
+/// 先読み・後読み、アトミックグループ、後方参照は使えない
+///
+/// ```
+/// use regex_machine::engine::do_matching_iter;
+/// let line = "ab".chars().cycle().take(10000).chain(['c']);
+/// assert!(do_matching_iter("(ab)+c", line).unwrap());
+/// ```
+pub fn do_matching_iter(
+    expr: &str,
+    line: impl IntoIterator<Item = char>,
+) -> Result<bool, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let result = stream::eval_iter(&code, line, &flags)?;
+
+    Ok(result)
+}
+
+/// 先頭の選択肢を複数のスレッドで並列に評価して、`do_matching`と同じようにマッチングを行う(実験的)
+///
+/// 分岐ごとに深さ優先探索で評価し、どれかがマッチした時点で返す。使うスレッドの数は`EvalConfig::max_threads`で指定する。
+/// 重い分岐がいくつもあるパターンでなければ、スレッドを作る分だけ遅くなる
+///
+/// ```
+/// use regex_machine::engine::{do_matching_parallel, EvalConfig};
+/// let config = EvalConfig::default().max_threads(2);
+/// assert!(do_matching_parallel("abc|(de|cd)+", "decddede", &config).unwrap());
+/// ```
+#[cfg(feature = "std")]
+pub fn do_matching_parallel(
+    expr: &str,
+    line: &str,
+    config: &EvalConfig,
+) -> Result<bool, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let flags = config.apply(&flags);
+    let line = line.chars().collect::<Vec<char>>();
+    let result = evaluator::eval_parallel(&code, &line, &flags, config)?;
+
+    Ok(result)
+}
+
+/// `context`のバッファを使い回して、幅優先探索で`do_matching`と同じようにマッチングを行う
+///
+/// 多くの行を同じように評価するときに、評価のたびにメモリを確保しなくて済む
+pub fn do_matching_with_context(
+    expr: &str,
+    line: &str,
+    context: &mut EvalContext,
+) -> Result<bool, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let line = line.chars().collect::<Vec<char>>();
+    let result = evaluator::eval_width_with_context(&code, &line, &flags, context)?;
+
+    Ok(result)
+}
+
+/// `cache`に残っているDFAを使って、`do_matching`と同じようにマッチングを行う
+///
+/// 同じパターンで何度も評価する場合に速くなる。DFAにできないパターンの場合は、Pike VMで評価する。
+/// パターンは呼ぶたびにパースし直すので、`Regex`にしておける場合は`Regex::is_match_with_cache`を使う
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_cache, DfaCache};
+/// let mut cache = DfaCache::default();
+/// assert!(do_matching_with_cache("(abc)+", "abcabc", &mut cache).unwrap());
+/// ```
+pub fn do_matching_with_cache(
+    expr: &str,
+    line: &str,
+    cache: &mut DfaCache,
+) -> Result<bool, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let line = line.chars().collect::<Vec<char>>();
+    let result = dfa::eval_dfa(&code, &line, &flags, cache)?;
+
+    Ok(result)
+}
+
+/// 入力の中で最も左にあるマッチを探し、その範囲を返す
+///
+/// 範囲は文字単位の位置で、終わりの位置は含まない。マッチしない場合は`None`
+///
+/// 深さ優先探索では優先される分岐のマッチを、幅優先探索では最初に`match`へ到達したマッチを返す
+///
+/// ```
+/// use regex_machine::engine::match_span;
+/// assert_eq!(match_span("bc", "abcd", true).unwrap(), Some((1, 3)));
+/// ```
+///
+/// ## 引数
+/// `do_matching`と同じ
+pub fn match_span(
+    expr: &str,
+    line: &str,
+    is_depth: bool,
+) -> Result<Option<(usize, usize)>, RegexError> {
+    let strategy = if is_depth {
+        Strategy::Depth
+    } else {
+        Strategy::Width
+    };
+    match_span_with_config(expr, line, strategy, &EvalConfig::default())
+}
+
+/// `config`の設定で評価して、`match_span`と同じようにマッチを探す
+///
+/// `EvalConfig::anchored`を指定した場合は入力の先頭からのマッチだけを試すので、範囲は必ず0から始まる
+///
+/// ```
+/// use regex_machine::engine::{match_span_with_config, EvalConfig, Strategy};
+/// let config = EvalConfig::default().anchored(true);
+/// assert_eq!(match_span_with_config("bc", "abc", Strategy::Depth, &config).unwrap(), None);
+/// assert_eq!(match_span_with_config("ab", "abc", Strategy::Depth, &config).unwrap(), Some((0, 2)));
+/// ```
+pub fn match_span_with_config(
+    expr: &str,
+    line: &str,
+    strategy: Strategy,
+    config: &EvalConfig,
+) -> Result<Option<(usize, usize)>, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let flags = config.apply(&flags);
+    let line = line.chars().collect::<Vec<char>>();
+    let first = codegen::first_chars(&code, &flags);
+    let span = find_at(&code, &line, &flags, first.as_ref(), 0, strategy, config)?;
+
+    Ok(span)
+}
+
+/// 入力の中のマッチを左から順に探し、重ならないものの範囲をすべて返す
+///
+/// 次のマッチは前のマッチの終わりから探す。空文字列にマッチした場合は、1文字進めてから探す
+///
+/// ```
+/// use regex_machine::engine::match_spans;
+/// assert_eq!(match_spans("ab", "ababab", true).unwrap(), vec![(0, 2), (2, 4), (4, 6)]);
+/// ```
+///
+/// ## 引数
+/// `do_matching`と同じ
+pub fn match_spans(
+    expr: &str,
+    line: &str,
+    is_depth: bool,
+) -> Result<Vec<(usize, usize)>, RegexError> {
+    let strategy = if is_depth {
+        Strategy::Depth
+    } else {
+        Strategy::Width
+    };
+    let regex = Regex::new(expr)?;
+    let spans = Matches::new(&regex, line, strategy)
+        .map(|m| m.map(|m| m.range()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(spans)
+}
+
+/// 入力の`from`以降で最も左にあるマッチを探し、その範囲を返す
+fn find_at(
+    code: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    first: Option<&CharClass>,
+    from: usize,
+    strategy: Strategy,
+    config: &EvalConfig,
+) -> Result<Option<(usize, usize)>, EvalError> {
+    // Pike VMは各位置から始め直さずに、1度だけ入力を読んで最も左のマッチを探す
+    if strategy == Strategy::Pike {
+        let span = evaluator::pike_find(code, line, flags, from, config, |sp| {
+            can_start(first, line, sp)
+        })?;
+        return match span {
+            // 最初に`match`に到達した分岐で止まるので、優先される分岐の終わりは深さ優先探索で求める
+            Some((start, _)) if !config.is_longest() => {
+                let end = evaluator::eval_from(code, line, start, Strategy::Depth, flags, config)?;
+                Ok(end.map(|end| (start, end)))
+            }
+            span => Ok(span),
+        };
+    }
+
+    for start in start_positions(first, line, from, config.is_anchored()) {
+        let end = evaluator::eval_from(code, line, start, strategy, flags, config)?;
+        if let Some(end) = end {
+            return Ok(Some((start, end)));
+        }
+    }
+
+    Ok(None)
+}
+
+/// 入力の`from`以降で最も左にあるマッチを探し、その位置から始まる最も短いマッチの範囲を返す
+///
+/// Pike VMで、`from`以降を1度だけ読む
+fn find_shortest_at(
+    code: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    first: Option<&CharClass>,
+    from: usize,
+) -> Result<Option<(usize, usize)>, EvalError> {
+    evaluator::pike_find(code, line, flags, from, &EvalConfig::default(), |sp| {
+        can_start(first, line, sp)
+    })
+}
+
+/// 入力のどこかに正規表現にマッチする部分があるか検証する
+///
+/// `do_matching`は入力の先頭からしかマッチしないが、こちらは各位置から順に試す
+///
+/// ```
+/// use regex_machine::{do_matching, search};
+/// assert!(!do_matching("bc", "abc", true).unwrap());
+/// assert!(search("bc", "abc", true).unwrap());
+/// ```
+///
+/// ## 引数
+/// `do_matching`と同じ
+pub fn search(expr: &str, line: &str, is_depth: bool) -> Result<bool, RegexError> {
+    Ok(match_span(expr, line, is_depth)?.is_some())
+}
+
+/// 入力の中で最も左にあるマッチを探し、各グループがキャプチャした範囲を返す
+///
+/// 0番目はマッチ全体の範囲で、`(...)`の`n`番目のグループが`n`番目に入る。マッチに使われなかったグループは`None`。
+/// 後方参照を扱えるよう、深さ優先探索で評価する
+///
+/// ```
+/// use regex_machine::engine::captures;
+/// let groups = captures(r"([0-9]+)-([0-9]+)", "12-34").unwrap().unwrap();
+/// assert_eq!(groups, vec![Some((0, 5)), Some((0, 2)), Some((3, 5))]);
+/// ```
+pub fn captures(expr: &str, line: &str) -> Result<Option<Captures>, RegexError> {
+    let (code, flags) = compile(expr, &ParseConfig::default())?;
+    let line = line.chars().collect::<Vec<char>>();
+    let first = codegen::first_chars(&code, &flags);
+
+    for start in start_positions(first.as_ref(), &line, 0, false) {
+        let captures =
+            evaluator::eval_captures(&code, &line, start, &flags, &EvalConfig::default())?;
+        if captures.is_some() {
+            return Ok(captures);
+        }
+    }
+
+    Ok(None)
+}
+
+/// `captures`と同じだが、範囲の代わりに入力から切り出した文字列を返す
+///
+/// ```
+/// use regex_machine::engine::capture_strs;
+/// let groups = capture_strs(r"([[:word:]]+)@([[:word:]]+)", "mail: foo@example").unwrap().unwrap();
+/// assert_eq!(groups, vec![Some("foo@example"), Some("foo"), Some("example")]);
+/// ```
+pub fn capture_strs<'a>(
+    expr: &str,
+    line: &'a str,
+) -> Result<Option<Vec<Option<&'a str>>>, RegexError> {
+    let Some(captures) = captures(expr, line)? else {
+        return Ok(None);
+    };
+
+    // 文字単位の位置をバイト単位の位置に直す
+    let offsets = byte_offsets(line);
+    let strs = captures
+        .into_iter()
+        .map(|span| span.map(|(start, end)| &line[offsets[start]..offsets[end]]))
+        .collect();
+
+    Ok(Some(strs))
+}
+
+/// `from`以降で探索を始める位置の候補。`anchored`のときは`from`だけ
+///
+/// マッチの1文字目になりうる文字`first`が分かっている場合は、それ以外の文字の位置を飛ばして評価器を動かさずに済ませる
+fn start_positions<'a>(
+    first: Option<&'a CharClass>,
+    line: &'a [char],
+    from: usize,
+    anchored: bool,
+) -> impl Iterator<Item = usize> + 'a {
+    let last = if anchored { from } else { line.len() };
+    (from..=last.min(line.len())).filter(move |&sp| can_start(first, line, sp))
+}
+
+/// `sp`がマッチの始まりになりうるか。`first`が分からない場合は、どの位置もなりうる
+fn can_start(first: Option<&CharClass>, line: &[char], sp: usize) -> bool {
+    match first {
+        Some(class) => line.get(sp).is_some_and(|c| class.contains(*c)),
+        None => true,
+    }
+}
+
+/// 正規表現をパースしてコードを生成する。パターン中で指定されたフラグも返す
+fn compile(expr: &str, config: &ParseConfig) -> Result<(Box<[Instruction]>, Flags), RegexError> {
+    compile_with_names(expr, config, Flags::default()).map(|(code, flags, _)| (code, flags))
+}
+
+/// `compile`と同じだが、`flags`を指定した状態からパースし、名前付きグループの名前と番号の対応も返す
+fn compile_with_names(
+    expr: &str,
+    config: &ParseConfig,
+    mut flags: Flags,
+) -> Result<(Box<[Instruction]>, Flags, GroupNames), RegexError> {
+    let (ast, names) =
+        parser::parse_with_names(expr, &mut flags, config).map_err(|e| e.with_pattern(expr))?;
+    let ast = optimize::optimize(ast);
+    let code = codegen::get_program(&ast)?;
+
+    Ok((code, flags, names))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    #[test]
+    fn test_instruction_display() {
+        assert_eq!(Instruction::Char('a').to_string(), "char a");
+        assert_eq!(Instruction::Match.to_string(), "match");
+        assert_eq!(
+            Instruction::StringMatch("abc".to_string()).to_string(),
+            "string abc"
+        );
+        assert_eq!(Instruction::Jump(3).to_string(), "jmp 0003");
+        assert_eq!(Instruction::Split(1, 12).to_string(), "split 0001, 0012");
+        assert_eq!(Instruction::Any.to_string(), "any");
+        assert_eq!(Instruction::Start.to_string(), "start");
+        assert_eq!(Instruction::End.to_string(), "end");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_instruction_serde() {
+        for expr in ["a|b", "ab|cd", r"(?<=a)\b(b+?)\1?", r"[^a-z]\p{L}"] {
+            let (code, _) = compile(expr, &ParseConfig::default()).unwrap();
+            let json = serde_json::to_string(&code).unwrap();
+            let decoded = serde_json::from_str::<Vec<Instruction>>(&json).unwrap();
+            assert_eq!(decoded, code.to_vec(), "{expr}");
+        }
+
+        let json = serde_json::to_string(&[Instruction::Split(1, 3), Instruction::Match]).unwrap();
+        assert_eq!(json, r#"[{"Split":[1,3]},"Match"]"#);
+    }
+
+    #[test]
+    fn test_code_listing() {
+        let code = codegen::get_code(&parser::parse("a*").unwrap()).unwrap();
+
+        assert_eq!(
+            code_listing(&code),
+            "0000: split 0001, 0003\n0001: char a\n0002: jmp 0000\n0003: match"
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let text = listing("abc|(de|cd)+").unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("Ast: "));
+        assert_eq!(lines[1], "code:");
+        assert!(lines.last().unwrap().ends_with(": match"));
+
+        assert!(matches!(listing("a)"), Err(RegexError::Parse(_))));
+    }
+
+    #[test]
+    fn test_code_stats() {
+        // 1文字の選択肢は文字クラスにまとめられるので、分岐は残らない
+        let stats = code_stats("a|b|c").unwrap();
+        assert_eq!(stats.insts, 2);
+        assert_eq!(stats.splits, 0);
+        assert_eq!(stats.jumps, 0);
+
+        // split, split, ab, jmp, cd, jmp, match
+        let stats = code_stats("(?:ab|cd)*").unwrap();
+        assert_eq!(stats.insts, 7);
+        assert_eq!(stats.splits, 2);
+        assert_eq!(stats.jumps, 2);
+
+        assert!(code_stats("a|*").is_err());
+    }
+
+    #[test]
+    fn test_to_dot() {
+        let dot = to_dot("ab|cd").unwrap();
+        let nodes = dot.lines().filter(|l| l.contains("[label=")).count();
+        let edges = dot.lines().filter(|l| l.contains("->")).count();
+
+        // split, ab, jmp, cd, match
+        assert_eq!(nodes, 5);
+        // splitから2本、jmpから1本、ab,cdから1本ずつ
+        assert_eq!(edges, 5);
+        assert!(dot.contains("n0 -> n1;\n    n0 -> n3;"));
+        assert!(dot.contains(r#"n4 [label="0004: match"];"#));
+
+        // `a|b`は1つの範囲にまとめられる
+        let dot = to_dot("a|b").unwrap();
+        assert_eq!(dot.lines().filter(|l| l.contains("->")).count(), 1);
+
+        assert!(to_dot("a|*").is_err());
+    }
+
+    #[test]
+    fn test_do_matching() {
+        // パースエラー
         assert!(do_matching("+b", "bbb", true).is_err());
         assert!(do_matching("*b", "bbb", true).is_err());
         assert!(do_matching("|b", "bbb", true).is_err());
@@ -102,10 +1661,1100 @@ mod tests {
         assert!(do_matching("(abc)*", "abcabc", true).unwrap());
         assert!(do_matching("(ab|cd)+", "abcd", true).unwrap());
         assert!(do_matching("abc?", "abcd", true).unwrap());
+        assert!(do_matching("a{3}", "aaa", true).unwrap());
+        assert!(do_matching("a{2,}", "aaaa", true).unwrap());
+        assert!(do_matching("^a{2,4}$", "aaa", true).unwrap());
+        assert!(do_matching(r"\x41", "A", true).unwrap());
+        assert!(do_matching(r"\u{3042}", "あ", true).unwrap());
+        assert!(do_matching(r"\u{1F600}+", "😀😀", true).unwrap());
+        assert!(do_matching(r"[[:digit:]]+$", "123", true).unwrap());
+        assert!(do_matching(r"[[:alpha:][:space:]]+$", "ab c", true).unwrap());
+        assert!(do_matching(r"^a\{b\}$", "a{b}", true).unwrap());
+        assert!(do_matching(r"^\[a\]$", "[a]", true).unwrap());
+        assert!(do_matching(r"^a{b$", "a{b", true).unwrap());
 
         // パース成功、マッチ失敗
         assert!(!do_matching("abc|def", "abd", true).unwrap());
         assert!(!do_matching("(ab|cd)+", "", true).unwrap());
         assert!(!do_matching("abc?", "acd", true).unwrap());
+        assert!(!do_matching("a{3}", "aa", true).unwrap());
+        assert!(!do_matching("a{2,}", "a", true).unwrap());
+        assert!(!do_matching("^a{2,4}$", "a", true).unwrap());
+        assert!(!do_matching("^a{2,4}$", "aaaaa", true).unwrap());
+        assert!(!do_matching(r"[[:digit:]]+$", "12a", true).unwrap());
+    }
+
+    #[test]
+    fn test_error_message() {
+        let err = do_matching("ab)c", "abc", true).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("ab)c\n  ^"));
+
+        #[cfg(feature = "std")]
+        {
+            let err = print("a|*b").unwrap_err();
+            assert!(err.to_string().contains("a|*b"));
+            // 元の`ParseError`も取り出せる
+            let RegexError::Parse(err) = err else {
+                panic!("{err:?}");
+            };
+            assert_eq!(err.error, ParseError::NoPrev(2));
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_print_to() {
+        let mut out = Vec::new();
+        print_to("abc|(de|cd)+", &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("Ast: "));
+        assert_eq!(lines[1], "code:");
+        assert_eq!(
+            lines.last(),
+            Some(&format!("{:>04}: match", lines.len() - 3).as_str())
+        );
+        // `listing`と同じ内容
+        assert_eq!(out, listing("abc|(de|cd)+").unwrap());
+
+        // パースに失敗したときは何も書き出さない
+        let mut out = Vec::new();
+        assert!(matches!(
+            print_to("a|*b", &mut out),
+            Err(RegexError::Parse(_))
+        ));
+        assert!(out.is_empty());
+
+        // 書き出しに失敗したとき
+        let mut out = [0u8; 4];
+        assert!(matches!(
+            print_to("abc", &mut out.as_mut_slice()),
+            Err(RegexError::Io(_))
+        ));
+    }
+
+    /// `std`なしでも、パースから評価まで一通り動く
+    #[cfg(not(feature = "std"))]
+    #[test]
+    fn test_no_std() {
+        assert!(do_matching("abc|(de|cd)+", "decddede", true).unwrap());
+        assert!(do_matching("abc|(de|cd)+", "decddede", false).unwrap());
+
+        let re = Regex::new(r"(?<year>[0-9]{4})-(?<month>[0-9]{2})").unwrap();
+        let caps = re.captures("2024-05").unwrap().unwrap();
+        assert_eq!(caps.name("month"), Some("05"));
+
+        let mut cache = DfaCache::new(16);
+        assert!(do_matching_with_cache("a+b", "aab", &mut cache).unwrap());
+        assert!(listing("a*").unwrap().ends_with("match\n"));
+    }
+
+    #[test]
+    fn test_regex_error() {
+        assert!(matches!(
+            do_matching("+b", "b", true),
+            Err(RegexError::Parse(PatternError {
+                error: ParseError::NoPrev(0),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            Regex::new("(?<=a+)b"),
+            Err(RegexError::CodeGen(CodeGenError::VariableLookBehind))
+        ));
+        assert!(matches!(
+            do_matching(r"(a)\1", "aa", false),
+            Err(RegexError::Eval(EvalError::BackRefNotSupported))
+        ));
+        // `?`で`RegexError`に変換できる
+        let eval = || -> Result<bool, RegexError> {
+            let re = Regex::new("a")?;
+            Ok(re.eval("a", Strategy::Depth, &EvalConfig::default())?)
+        };
+        assert!(eval().unwrap());
+    }
+
+    #[test]
+    fn test_repeat_too_large() {
+        let err = do_matching("a{10000000}", "a", true).unwrap_err();
+        let RegexError::Parse(err) = err else {
+            panic!("{err:?}");
+        };
+        assert_eq!(err.error, ParseError::RepeatTooLarge(1));
+    }
+
+    #[test]
+    fn test_nesting_too_deep() {
+        let regex = format!("{}a{}", "(".repeat(5000), ")".repeat(5000));
+        let err = do_matching(&regex, "a", true).unwrap_err();
+        let RegexError::Parse(err) = err else {
+            panic!("{err:?}");
+        };
+        assert!(matches!(err.error, ParseError::NestingTooDeep(_)));
+
+        let regex = format!("{}a{}", "(".repeat(100), ")".repeat(100));
+        assert!(do_matching(&regex, "a", true).unwrap());
+    }
+
+    #[test]
+    fn test_nested_quantifier() {
+        for is_depth in [true, false] {
+            assert!(do_matching("(a*)*b", "aab", is_depth).unwrap());
+            assert!(do_matching("(a+)+b", "aab", is_depth).unwrap());
+            assert!(!do_matching("(a+)+b", "aac", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_strategy() {
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            assert!(do_matching_with_strategy("abc|(de|cd)+", "decddede", strategy).unwrap());
+            assert!(do_matching_with_strategy("(?i)x(a|b)", "XB", strategy).unwrap());
+            assert!(!do_matching_with_strategy("^(a|b)+$", "abc", strategy).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_step_limit() {
+        // 長い入力で、失敗するまでに多くの分岐を試す
+        let line = format!("{}b", "a".repeat(100));
+        let config = EvalConfig::default().step_limit(1000);
+
+        let err = do_matching_with_eval_config("(a|aa)+$", &line, Strategy::Depth, &config)
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "EvaluationError: step limit exceeded");
+
+        // 上限に達しなければ、普通に評価できる
+        let line = "a".repeat(10);
+        assert!(do_matching_with_eval_config("(a|aa)+$", &line, Strategy::Depth, &config).unwrap());
+    }
+
+    #[test]
+    fn test_match_span() {
+        for is_depth in [true, false] {
+            assert_eq!(match_span("bc", "abcd", is_depth).unwrap(), Some((1, 3)));
+            assert_eq!(match_span("abc", "abc", is_depth).unwrap(), Some((0, 3)));
+            assert_eq!(match_span("x", "abc", is_depth).unwrap(), None);
+            // 空文字列にマッチする場合は、範囲も空
+            assert_eq!(match_span("x*", "abc", is_depth).unwrap(), Some((0, 0)));
+            // 入力の先頭でしかマッチしない
+            assert_eq!(match_span("^b", "ab", is_depth).unwrap(), None);
+            assert_eq!(
+                match_span("(?m)^b", "a\nb", is_depth).unwrap(),
+                Some((2, 3))
+            );
+            assert_eq!(match_span("éé", "caféé!", is_depth).unwrap(), Some((3, 5)));
+
+            // どの方式でも、優先される分岐のマッチの終わりを返す
+            assert_eq!(match_span("a+", "baaa", is_depth).unwrap(), Some((1, 4)));
+            assert_eq!(match_span("a+", "xaaa", is_depth).unwrap(), Some((1, 4)));
+            assert_eq!(match_span("a|ab", "ab", is_depth).unwrap(), Some((0, 1)));
+            assert_eq!(match_span("a+?", "baaa", is_depth).unwrap(), Some((1, 2)));
+            assert_eq!(
+                match_span("a{1,3}?", "aaa", is_depth).unwrap(),
+                Some((0, 1))
+            );
+            assert_eq!(
+                match_span("a{2,}?", "aaaa", is_depth).unwrap(),
+                Some((0, 2))
+            );
+            assert_eq!(match_span("a{1,3}", "aaa", is_depth).unwrap(), Some((0, 3)));
+            assert_eq!(match_spans("a+", "aaa", is_depth).unwrap(), vec![(0, 3)]);
+        }
+
+        let config = EvalConfig::default();
+        assert_eq!(
+            match_span_with_config("a|ab", "xab", Strategy::Pike, &config).unwrap(),
+            Some((1, 2))
+        );
+        assert_eq!(
+            match_span_with_config("a+", "xaaa", Strategy::Pike, &config).unwrap(),
+            Some((1, 4))
+        );
+    }
+
+    #[test]
+    fn test_empty_match() {
+        // 何も消費せずに`match`へ到達するパターンは、空の入力にもマッチする
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            for expr in ["", "a*", "a?b*", "()", "(a|b)*$", "^$", r"\b?"] {
+                assert!(
+                    do_matching_with_strategy(expr, "", strategy).unwrap(),
+                    "{expr}"
+                );
+            }
+            assert!(do_matching_with_strategy("", "abc", strategy).unwrap());
+            assert!(!do_matching_with_strategy("a+", "", strategy).unwrap());
+        }
+        assert!(do_matching("", "", true).unwrap());
+        assert!(do_matching("a*", "", false).unwrap());
+        assert!(do_matching_iter("a*", "".chars()).unwrap());
+        assert!(do_matching_bytes("", b"").unwrap());
+        assert_eq!(match_span("", "abc", true).unwrap(), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_search() {
+        for is_depth in [true, false] {
+            assert!(!do_matching("bc", "abc", is_depth).unwrap());
+            assert!(search("bc", "abc", is_depth).unwrap());
+            assert!(search("b|x", "abc", is_depth).unwrap());
+            assert!(search("c$", "abc", is_depth).unwrap());
+            assert!(!search("^bc", "abc", is_depth).unwrap());
+            assert!(!search("bd", "abc", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_captures() {
+        let groups = captures(r"([0-9]+)-([0-9]+)", "12-34").unwrap().unwrap();
+        assert_eq!(groups, vec![Some((0, 5)), Some((0, 2)), Some((3, 5))]);
+
+        let groups = capture_strs(r"([0-9]+)-([0-9]+)", "tel: 12-34")
+            .unwrap()
+            .unwrap();
+        assert_eq!(groups, vec![Some("12-34"), Some("12"), Some("34")]);
+
+        // 文字単位の位置とバイト単位の位置が違っても、正しく切り出せる
+        let groups = capture_strs("(é+)(x)?", "caféé").unwrap().unwrap();
+        assert_eq!(groups, vec![Some("éé"), Some("éé"), None]);
+
+        assert_eq!(captures("x", "abc").unwrap(), None);
+        assert_eq!(capture_strs("x", "abc").unwrap(), None);
+
+        // 繰り返しの中のグループは、最後に繰り返したときの範囲になる
+        let groups = captures("(a+)?b", "b").unwrap().unwrap();
+        assert_eq!(groups, vec![Some((0, 1)), None]);
+        let groups = capture_strs("(a?)+b", "aab").unwrap().unwrap();
+        assert_eq!(groups[1], Some("a"));
+        assert!(!do_matching(r"^(a?)+x\1$", "aaxaa", true).unwrap());
+        assert!(do_matching(r"^(a?)+x\1$", "aaxa", true).unwrap());
+    }
+
+    #[test]
+    fn test_start_positions() {
+        let (code, flags) = compile("xyz", &ParseConfig::default()).unwrap();
+        let first = codegen::first_chars(&code, &flags);
+
+        // `x`がなければ、評価器を1度も動かさない
+        let line = "a".repeat(100000).chars().collect::<Vec<_>>();
+        assert_eq!(start_positions(first.as_ref(), &line, 0, false).count(), 0);
+        assert!(!search("xyz", &"a".repeat(100000), true).unwrap());
+
+        let line = "axbxyz".chars().collect::<Vec<_>>();
+        let starts = start_positions(first.as_ref(), &line, 0, false).collect::<Vec<_>>();
+        assert_eq!(starts, vec![1, 3]);
+        assert_eq!(match_span("xyz", "axbxyz", true).unwrap(), Some((3, 6)));
+
+        // 絞り込めない場合は、すべての位置を試す
+        assert_eq!(start_positions(None, &line, 0, false).count(), 7);
+        assert_eq!(start_positions(None, &line, 5, false).count(), 2);
+        assert_eq!(start_positions(None, &line, 7, false).count(), 0);
+        assert_eq!(match_span("(?i)xyz", "aXYZ", true).unwrap(), Some((1, 4)));
+        assert_eq!(match_span("x*", "abc", true).unwrap(), Some((0, 0)));
+    }
+
+    #[test]
+    fn test_regex() {
+        // 一度コンパイルしたものを、いくつもの行に使う
+        let re = Regex::new("(ab|cd)+e").unwrap();
+        for (line, expected) in [
+            ("abcde", true),
+            ("xabe", true),
+            ("cdcdcdx", false),
+            ("", false),
+        ] {
+            assert_eq!(re.is_match(line).unwrap(), expected, "{line}");
+        }
+        assert_eq!(re.as_str(), "(ab|cd)+e");
+
+        // 先頭からのマッチだけを試す
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            assert!(re.eval("abe", strategy, &EvalConfig::default()).unwrap());
+            assert!(!re.eval("xabe", strategy, &EvalConfig::default()).unwrap());
+        }
+
+        let re = Regex::new("bc").unwrap();
+        assert!(re.is_match("abc").unwrap());
+        assert!(!re.is_match("acb").unwrap());
+        // 後方参照がある場合は、深さ優先探索で探す
+        let re = Regex::new(r"([[:word:]])\1").unwrap();
+        assert!(re.is_match("abccd").unwrap());
+        assert!(!re.is_match("abcd").unwrap());
+        // 長い入力でも、再帰せずに探す
+        let re = Regex::new("(a|b)*c").unwrap();
+        assert!(re.is_match(&format!("{}c", "ab".repeat(1000))).unwrap());
+
+        assert!(Regex::new("(a").is_err());
+        let config = ParseConfig::default().max_repeat(10);
+        assert!(Regex::with_config("a{100}", &config).is_err());
+    }
+
+    #[test]
+    fn test_is_match_with_cache() {
+        // 違う`Regex`で同じキャッシュを使っても、`is_match`と同じ結果になる
+        let mut cache = DfaCache::new(16);
+        let lines = ["", "abcde", "xabe", "cdcdcdx", "aab", "ba", "xaa"];
+        for pattern in ["(ab|cd)+e", "a+b", "a*", "(?i)AB", r"(.)\1", "^a"] {
+            let re = Regex::new(pattern).unwrap();
+            for line in lines {
+                assert_eq!(
+                    re.is_match_with_cache(line, &mut cache).unwrap(),
+                    re.is_match(line).unwrap(),
+                    "{pattern} {line}"
+                );
+            }
+        }
+
+        // 同じパターンでも、コンパイルし直したものはキャッシュを作り直す
+        let re = Regex::new("a+b").unwrap();
+        assert!(re.is_match_with_cache("xab", &mut cache).unwrap());
+        let re = RegexBuilder::new("a+b")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(re.is_match_with_cache("xAB", &mut cache).unwrap());
+        assert!(!do_matching_with_cache("a+b", "xab", &mut cache).unwrap());
+    }
+
+    #[test]
+    fn test_find_iter() {
+        let re = Regex::new(r"[0-9]+").unwrap();
+        let matches = re
+            .find_iter("a1b22c333")
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let spans = matches.iter().map(Match::range).collect::<Vec<_>>();
+        assert_eq!(spans, vec![(1, 2), (3, 5), (6, 9)]);
+        let strs = matches.iter().map(Match::as_str).collect::<Vec<_>>();
+        assert_eq!(strs, vec!["1", "22", "333"]);
+
+        // 1つずつ探す
+        let mut matches = re.find_iter("12ab3");
+        assert_eq!(matches.next().unwrap().unwrap().range(), (0, 2));
+        assert_eq!(matches.next().unwrap().unwrap().range(), (4, 5));
+        assert_eq!(matches.next(), None);
+        assert_eq!(matches.next(), None);
+        assert_eq!(re.find_iter("abc").count(), 0);
+
+        // エラーを返したら終わる
+        let re = Regex::new(r"(a)\1").unwrap();
+        let mut matches = re.find_iter_shortest("aaaa");
+        assert_eq!(matches.next(), Some(Err(EvalError::BackRefNotSupported)));
+        assert_eq!(matches.next(), None);
+    }
+
+    #[test]
+    fn test_regex_eq() {
+        assert_eq!(Regex::new("a+b").unwrap(), Regex::new("a+b").unwrap());
+        assert_eq!(Regex::new("a+b").unwrap(), "a+b".parse().unwrap());
+        // パターンの文字列で比べる
+        assert_ne!(Regex::new("a").unwrap(), Regex::new("(?:a)").unwrap());
+        // フラグも比べる
+        let re = RegexBuilder::new("abc")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert_ne!(re, Regex::new("abc").unwrap());
+        assert_eq!(
+            re,
+            RegexBuilder::new("abc")
+                .case_insensitive(true)
+                .build()
+                .unwrap()
+        );
+
+        #[cfg(feature = "std")]
+        {
+            use std::collections::{hash_map::DefaultHasher, HashSet};
+            let hash = |re: &Regex| {
+                let mut hasher = DefaultHasher::new();
+                re.hash(&mut hasher);
+                hasher.finish()
+            };
+            assert_eq!(
+                hash(&Regex::new("a+b").unwrap()),
+                hash(&Regex::new("a+b").unwrap())
+            );
+
+            let set = ["a+b", "c", "a+b"]
+                .into_iter()
+                .map(|expr| Regex::new(expr).unwrap())
+                .collect::<HashSet<_>>();
+            assert_eq!(set.len(), 2);
+            assert!(set.contains(&Regex::new("c").unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_regex_from_str() {
+        let re = "a(b|c)+".parse::<Regex>().unwrap();
+        assert_eq!(re.as_str(), "a(b|c)+");
+        assert!(re.is_match("xabcb").unwrap());
+
+        let err = "a(b".parse::<Regex>().unwrap_err();
+        assert!(matches!(
+            err,
+            RegexError::Parse(PatternError {
+                error: ParseError::NoRightParen,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_regex_builder() {
+        let re = RegexBuilder::new("abc")
+            .case_insensitive(true)
+            .build()
+            .unwrap();
+        assert!(re.is_match("ABC").unwrap());
+        assert!(!RegexBuilder::new("abc")
+            .build()
+            .unwrap()
+            .is_match("ABC")
+            .unwrap());
+
+        let re = RegexBuilder::new("^b$").multiline(true).build().unwrap();
+        assert!(re.is_match("a\nb\nc").unwrap());
+        assert!(!Regex::new("^b$").unwrap().is_match("a\nb\nc").unwrap());
+
+        let re = RegexBuilder::new("a.b").dot_all(true).build().unwrap();
+        assert!(re.is_match("a\nb").unwrap());
+        assert!(!Regex::new("a.b").unwrap().is_match("a\nb").unwrap());
+
+        // パターン中のフラグも有効
+        let re = RegexBuilder::new("(?i)a.b").dot_all(true).build().unwrap();
+        assert!(re.is_match("A\nB").unwrap());
+
+        let config = ParseConfig::default().max_repeat(10);
+        assert!(RegexBuilder::new("a{100}").config(config).build().is_err());
+    }
+
+    #[test]
+    fn test_regex_bytes() {
+        let re = RegexBuilder::new(r"(?<word>[a-z]+)-(?<n>[0-9]+)$")
+            .case_insensitive(true)
+            .multiline(true)
+            .build()
+            .unwrap();
+        let bytes = re.to_bytes();
+        // 名前付きグループの順番によらず、同じバイト列になる
+        assert_eq!(bytes, re.clone().to_bytes());
+
+        let loaded = Regex::from_bytes(&bytes).unwrap();
+        assert_eq!(loaded, re);
+        assert_eq!(loaded.as_str(), re.as_str());
+        assert_eq!(loaded.code, re.code);
+        assert_eq!(loaded.names, re.names);
+        let groups = loaded.captures("x AB-12\nz").unwrap().unwrap();
+        assert_eq!(
+            (groups.name("word"), groups.name("n")),
+            (Some("AB"), Some("12"))
+        );
+
+        // 命令列だけのバイト列とは区別する
+        assert_eq!(
+            Regex::from_bytes(&serialize(&re.code)).err().unwrap(),
+            DeserializeError::InvalidMagic
+        );
+        assert_eq!(
+            Regex::from_bytes(&bytes[..bytes.len() - 1]).err().unwrap(),
+            DeserializeError::Truncated
+        );
+    }
+
+    #[test]
+    fn test_find() {
+        let re = Regex::new("bc").unwrap();
+        let m = re.find("abc").unwrap().unwrap();
+        assert_eq!(m.as_str(), "bc");
+        assert_eq!((m.start(), m.end()), (1, 3));
+        assert_eq!(re.find("abd").unwrap(), None);
+
+        // 位置は文字単位
+        let m = Regex::new("い+")
+            .unwrap()
+            .find("あいいう")
+            .unwrap()
+            .unwrap();
+        assert_eq!((m.start(), m.end(), m.as_str()), (1, 3, "いい"));
+
+        // 空文字列へのマッチ
+        let m = Regex::new("x*").unwrap().find("ab").unwrap().unwrap();
+        assert_eq!((m.range(), m.as_str()), ((0, 0), ""));
+    }
+
+    #[test]
+    fn test_count_matches() {
+        let re = Regex::new(r"[0-9]").unwrap();
+        assert_eq!(re.count_matches("a1b2c3").unwrap(), 3);
+        assert_eq!(re.count_matches("abc").unwrap(), 0);
+        assert_eq!(re.count_matches("").unwrap(), 0);
+        // 重なるマッチは数えない
+        assert_eq!(Regex::new("aa").unwrap().count_matches("aaaaa").unwrap(), 2);
+        // 空文字列へのマッチは、各文字の間で数える
+        assert_eq!(Regex::new("x*").unwrap().count_matches("ab").unwrap(), 3);
+
+        // `find_iter`と同じ数
+        let re = Regex::new("い+|b").unwrap();
+        let line = "あいいbうい";
+        assert_eq!(re.count_matches(line).unwrap(), re.find_iter(line).count());
+
+        let re = Regex::new("(a|b)*c").unwrap();
+        assert_eq!(re.count_matches(&"ab".repeat(200)), Ok(0));
+    }
+
+    #[test]
+    fn test_anchored_at_start() {
+        let re = Regex::new("abc").unwrap();
+        assert!(re.anchored_at_start("abc").unwrap());
+        assert!(re.anchored_at_start("abcdef").unwrap());
+        assert!(!re.anchored_at_start("xabc").unwrap());
+        assert!(!re.anchored_at_start("").unwrap());
+        // `is_match`は途中からのマッチも探す
+        assert!(re.is_match("xabc").unwrap());
+
+        // `^`を付けたパターンと同じ結果
+        let re = Regex::new("a*b|c").unwrap();
+        let anchored = Regex::new("^(?:a*b|c)").unwrap();
+        for line in ["aab", "cx", "xc", "ab\nc", ""] {
+            assert_eq!(
+                re.anchored_at_start(line).unwrap(),
+                anchored.is_match(line).unwrap(),
+                "{line}"
+            );
+        }
+        // 空文字列にマッチするパターンは、どの入力でも先頭でマッチする
+        assert!(Regex::new("x*").unwrap().anchored_at_start("ab").unwrap());
+    }
+
+    #[test]
+    fn test_find_shortest() {
+        let re = Regex::new("a+").unwrap();
+        let m = re.find_shortest("aaa").unwrap().unwrap();
+        assert_eq!((m.range(), m.as_str()), ((0, 1), "a"));
+        // 既定の`find`は最長一致のまま
+        assert_eq!(re.find("aaa").unwrap().unwrap().range(), (0, 3));
+
+        let spans = re
+            .find_iter_shortest("aa-a")
+            .map(|m| m.map(|m| m.range()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(spans, vec![(0, 1), (1, 2), (3, 4)]);
+
+        // 最も左の位置から始まるマッチの中で、最も短いもの
+        let re = Regex::new("abc|b").unwrap();
+        assert_eq!(re.find_shortest("xabc").unwrap().unwrap().range(), (1, 4));
+        let re = Regex::new("ab|a").unwrap();
+        assert_eq!(re.find_shortest("ab").unwrap().unwrap().range(), (0, 1));
+        let re = Regex::new("a*").unwrap();
+        assert_eq!(re.find_shortest("aa").unwrap().unwrap().range(), (0, 0));
+        assert_eq!(Regex::new("x").unwrap().find_shortest("aa").unwrap(), None);
+
+        let re = Regex::new(r"(a)\1").unwrap();
+        assert_eq!(
+            re.find_shortest("aa").unwrap_err(),
+            EvalError::BackRefNotSupported
+        );
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let re = Regex::new(r"[0-9]+").unwrap();
+        assert_eq!(re.replace_all("a1b22", "#").unwrap(), "a#b#");
+        assert_eq!(re.replace_all("abc", "#").unwrap(), "abc");
+        assert_eq!(re.replace_all("", "#").unwrap(), "");
+        // 存在しないグループは空文字列
+        assert_eq!(re.replace_all("x1", "$1").unwrap(), "x");
+        assert_eq!(re.replace_all("x1", "<$0>").unwrap(), "x<1>");
+
+        // 文字の位置とバイトの位置が違う入力
+        let re = Regex::new("い+").unwrap();
+        assert_eq!(re.replace_all("あいいうい", "-").unwrap(), "あ-う-");
+
+        // 空文字列へのマッチは、各文字の間に入れる
+        let re = Regex::new("x*").unwrap();
+        assert_eq!(re.replace_all("ab", "-").unwrap(), "-a-b-");
+
+        // 置き換える範囲は`find_iter`と同じく、優先される分岐のマッチ
+        for (pattern, line, expected) in [
+            ("a|ab", "abab", "<a>b<a>b"),
+            ("a+?", "aab", "<a><a>b"),
+            (r"(a)\1|b", "aaab", "<aa>a<b>"),
+        ] {
+            assert_eq!(
+                Regex::new(pattern)
+                    .unwrap()
+                    .replace_all(line, "<$0>")
+                    .unwrap(),
+                expected,
+                "{pattern}"
+            );
+        }
+        let re = Regex::new(r"(a)\1|b").unwrap();
+        assert_eq!(re.replace_all("aaab", "<$1>").unwrap(), "<a>a<>");
+    }
+
+    #[test]
+    fn test_replace_template() {
+        let re = Regex::new(r"([[:word:]]+)@([[:word:]]+)").unwrap();
+        assert_eq!(re.replace_all("user@host", "$2.$1").unwrap(), "host.user");
+        assert_eq!(re.replace_all("a@b, c@d", "${2}${1}").unwrap(), "ba, dc");
+        assert_eq!(re.replace_all("a@b", "$$1 costs $").unwrap(), "$1 costs $");
+
+        let re = Regex::new(r"(?<year>[0-9]{4})-(?<month>[0-9]{2})").unwrap();
+        assert_eq!(
+            re.replace_all("2024-05, 1999-12", "${month}/${year}")
+                .unwrap(),
+            "05/2024, 12/1999"
+        );
+        // 存在しない名前や、マッチに加わらなかったグループは空文字列
+        assert_eq!(re.replace_all("2024-05", "[${day}]").unwrap(), "[]");
+        let re = Regex::new("(x)?(é+)").unwrap();
+        assert_eq!(re.replace_all("aéé", "<$1|$2>").unwrap(), "a<|éé>");
+    }
+
+    #[test]
+    fn test_template_parse() {
+        let pieces = |s| Template::parse(s).pieces;
+        assert_eq!(
+            pieces("a$1b${name}c${12}$$"),
+            vec![
+                Piece::Literal("a".to_string()),
+                Piece::Group(1),
+                Piece::Literal("b".to_string()),
+                Piece::Name("name".to_string()),
+                Piece::Literal("c".to_string()),
+                Piece::Group(12),
+                Piece::Literal("$".to_string()),
+            ]
+        );
+        // 参照として読めない`$`は、ただの文字
+        assert_eq!(
+            pieces("$ $x ${a"),
+            vec![Piece::Literal("$ $x ${a".to_string())]
+        );
+        assert_eq!(
+            pieces("$10a"),
+            vec![Piece::Group(10), Piece::Literal("a".to_string())]
+        );
+        assert_eq!(pieces(""), vec![]);
+        assert!(!Template::parse("$$").has_groups());
+        assert!(Template::parse("${0}").has_groups());
+    }
+
+    #[test]
+    fn test_regex_captures() {
+        let re = Regex::new(r"(?<year>[0-9]{4})-(?<month>[0-9]{2})").unwrap();
+        let caps = re.captures("date: 2024-05-17").unwrap().unwrap();
+        assert_eq!(caps.get(0), Some("2024-05"));
+        assert_eq!(caps.name("year"), Some("2024"));
+        assert_eq!(caps.name("month"), Some("05"));
+        assert_eq!(caps.get(1), Some("2024"));
+        assert_eq!(caps.get(2), Some("05"));
+        assert_eq!(caps.len(), 3);
+        assert_eq!(caps.get(3), None);
+        assert_eq!(caps.name("day"), None);
+        assert!(re.captures("no date").unwrap().is_none());
+
+        // マッチに加わらなかったグループは`None`
+        let re = Regex::new("(?<a>x)?(?<b>é+)").unwrap();
+        let caps = re.captures("aéé").unwrap().unwrap();
+        assert_eq!(caps.get(0), Some("éé"));
+        assert_eq!(caps.name("a"), None);
+        assert_eq!(caps.name("b"), Some("éé"));
+
+        // キャプチャした文字列は`Regex`を捨てた後も使える
+        let line = String::from("key=value");
+        let value = {
+            let re = Regex::new("=(.*)").unwrap();
+            re.captures(&line).unwrap().unwrap().get(1)
+        };
+        assert_eq!(value, Some("value"));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_grep() {
+        let re = Regex::new(r"^[[:word:]]+@[[:word:]]+$").unwrap();
+        let input = "foo@example\nnot a mail\r\n\nbar@test\nbaz@\n";
+        let lines = grep(&re, io::Cursor::new(input))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            lines,
+            vec![(1, "foo@example".to_string()), (4, "bar@test".to_string())]
+        );
+        assert_eq!(grep(&re, io::Cursor::new("")).count(), 0);
+
+        // 読み込みでエラーになったら、エラーを返して終わる
+        let re = Regex::new("a").unwrap();
+        let input: &[u8] = b"a\n\xff\na\n";
+        let mut lines = grep(&re, input);
+        assert_eq!(lines.next().unwrap().unwrap(), (1, "a".to_string()));
+        assert!(matches!(lines.next(), Some(Err(RegexError::Io(_)))));
+        assert!(lines.next().is_none());
+
+        // 深さ優先探索で評価する長い行
+        let re = Regex::new(r"(a|b)*c\1").unwrap();
+        let input = format!("xc\n{}cb\n", "ab".repeat(1000));
+        let mut lines = grep(&re, io::Cursor::new(input));
+        assert_eq!(lines.next().unwrap().unwrap().0, 2);
+        assert!(lines.next().is_none());
+    }
+
+    #[test]
+    fn test_long_or() {
+        // 選択肢が多いだけのパターンは、入れ子が深いことにはならない
+        let words = (0..10000).map(|i| format!("w{i};")).collect::<Vec<_>>();
+        let re = Regex::new(&words.join("|")).unwrap();
+        assert_eq!(re.find("x w9999; y").unwrap().unwrap().as_str(), "w9999;");
+        assert!(Regex::new(&vec!["ab"; 3000].join("|")).is_ok());
+    }
+
+    #[test]
+    fn test_long_line() {
+        // 繰り返しの回数が多くても、評価の上限には掛からない
+        let line = "a".repeat(5000);
+        assert!(do_matching("a*", &line, true).unwrap());
+        let m = Regex::new(".*").unwrap().find(&line).unwrap().unwrap();
+        assert_eq!(m.range(), (0, 5000));
+        let re = Regex::new("a*").unwrap();
+        assert_eq!(
+            re.split(&format!("{line}b{line}")).unwrap(),
+            vec!["", "", "b", "", ""]
+        );
+    }
+
+    #[test]
+    fn test_split() {
+        let re = Regex::new(r"[0-9]").unwrap();
+        assert_eq!(re.split("a1b2c").unwrap(), vec!["a", "b", "c"]);
+        assert_eq!(re.split("abc").unwrap(), vec!["abc"]);
+        assert_eq!(re.split("").unwrap(), vec![""]);
+        // 並んだ区切りや、先頭と末尾の区切りの前後には空の文字列が入る
+        assert_eq!(re.split("1a23b4").unwrap(), vec!["", "a", "", "b", ""]);
+
+        let re = Regex::new(r", *").unwrap();
+        assert_eq!(re.split("あ, い,う").unwrap(), vec!["あ", "い", "う"]);
+
+        // 空文字列で区切ると、1文字ずつになる
+        let re = Regex::new("").unwrap();
+        assert_eq!(re.split("abc").unwrap(), vec!["", "a", "b", "c", ""]);
+    }
+
+    #[test]
+    fn test_match_spans() {
+        for is_depth in [true, false] {
+            assert_eq!(
+                match_spans("ab", "ababab", is_depth).unwrap(),
+                vec![(0, 2), (2, 4), (4, 6)]
+            );
+            assert_eq!(
+                match_spans("aba", "ababab", is_depth).unwrap(),
+                vec![(0, 3)]
+            );
+            assert!(match_spans("x", "abc", is_depth).unwrap().is_empty());
+        }
+        assert_eq!(
+            match_spans(r"[0-9]+", "a1b22c333", true).unwrap(),
+            vec![(1, 2), (3, 5), (6, 9)]
+        );
+
+        // 空文字列にマッチした場合は、1文字進めて探す
+        assert_eq!(
+            match_spans("a*", "baab", true).unwrap(),
+            vec![(0, 0), (1, 3), (3, 3), (4, 4)]
+        );
+        assert_eq!(match_spans("", "", true).unwrap(), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn test_do_matching_opts() {
+        for is_depth in [true, false] {
+            assert!(do_matching_opts("hello", "HELLO", is_depth, true).unwrap());
+            assert!(do_matching_opts("h[a-z]+o", "HeLLo", is_depth, true).unwrap());
+            assert!(!do_matching_opts("hello", "HELLO", is_depth, false).unwrap());
+            assert!(do_matching_opts("hello", "hello", is_depth, false).unwrap());
+            // パターン中の`(?i)`は、引数が`false`でも有効
+            assert!(do_matching_opts("(?i)hello", "HELLO", is_depth, false).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive_config() {
+        let config = EvalConfig::default().case_insensitive(true);
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            assert!(do_matching_with_eval_config("abc", "ABC", strategy, &config).unwrap());
+            assert!(
+                !do_matching_with_eval_config("abc", "ABC", strategy, &EvalConfig::default())
+                    .unwrap()
+            );
+        }
+        // 先頭の文字で候補を絞るときも、大文字と小文字を区別しない
+        assert_eq!(
+            match_span_with_config("bc", "xBC", Strategy::Depth, &config).unwrap(),
+            Some((1, 3))
+        );
+    }
+
+    #[test]
+    fn test_dot_all_config() {
+        let config = EvalConfig::default().dot_all(true);
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            assert!(do_matching_with_eval_config("a.b", "a\nb", strategy, &config).unwrap());
+            assert!(
+                !do_matching_with_eval_config("a.b", "a\nb", strategy, &EvalConfig::default())
+                    .unwrap()
+            );
+            assert!(
+                do_matching_with_eval_config("a.b", "axb", strategy, &EvalConfig::default())
+                    .unwrap()
+            );
+        }
+        assert_eq!(
+            match_span_with_config(".+", "a\nb", Strategy::Depth, &config).unwrap(),
+            Some((0, 3))
+        );
+        assert_eq!(
+            match_span_with_config(".+", "\nb", Strategy::Depth, &EvalConfig::default()).unwrap(),
+            Some((1, 2))
+        );
+    }
+
+    #[test]
+    fn test_anchored() {
+        let anchored = EvalConfig::default().anchored(true);
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            for (regex, line) in [("bc", "abc"), ("ab", "abc"), ("a+", "aab"), ("b|a", "ab")] {
+                // `anchored`のときは、先頭の`^`があってもなくても同じ
+                let with_caret = match_span_with_config(
+                    &format!("^(?:{regex})"),
+                    line,
+                    strategy,
+                    &EvalConfig::default(),
+                )
+                .unwrap();
+                let res = match_span_with_config(regex, line, strategy, &anchored).unwrap();
+                assert_eq!(res, with_caret, "{regex}");
+            }
+        }
+
+        // `anchored`でない場合は、途中からのマッチも探す
+        let unanchored = EvalConfig::default();
+        let res = match_span_with_config("bc", "abc", Strategy::Depth, &unanchored).unwrap();
+        assert_eq!(res, Some((1, 3)));
+    }
+
+    #[test]
+    fn test_longest() {
+        let longest = EvalConfig::default().longest(true);
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            let res = match_span_with_config("a|ab", "ab", strategy, &longest).unwrap();
+            assert_eq!(res, Some((0, 2)));
+            let res = match_span_with_config("a+?", "xaaa", strategy, &longest).unwrap();
+            assert_eq!(res, Some((1, 4)));
+            let res = match_span_with_config("(?=ab)a|abc", "abc", strategy, &longest).unwrap();
+            assert_eq!(res, Some((0, 3)));
+            let res = match_span_with_config("x", "ab", strategy, &longest).unwrap();
+            assert_eq!(res, None);
+        }
+
+        // デフォルトでは、優先される分岐のマッチを返す
+        let res = match_span_with_config("a|ab", "ab", Strategy::Depth, &EvalConfig::default());
+        assert_eq!(res.unwrap(), Some((0, 1)));
+    }
+
+    #[test]
+    fn test_fold_char_or() {
+        for is_depth in [true, false] {
+            assert!(do_matching("^(a|b|c|d)+$", "cab", is_depth).unwrap());
+            assert!(!do_matching("^(a|b|c|d)+$", "cabe", is_depth).unwrap());
+            assert!(do_matching("(?i)x(a|b)", "XB", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_anchor() {
+        for is_depth in [true, false] {
+            assert!(do_matching("^abc$", "abc", is_depth).unwrap());
+            assert!(!do_matching("^abc$", "xabc", is_depth).unwrap());
+            assert!(!do_matching("^abc$", "abcx", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_absolute_anchor() {
+        for is_depth in [true, false] {
+            assert!(do_matching(r"\Aabc\z", "abc", is_depth).unwrap());
+            assert!(!do_matching(r"\Aabc\z", "abcd", is_depth).unwrap());
+            assert!(!do_matching(r"\Aabc\z", "abc\n", is_depth).unwrap());
+            assert!(!do_matching(r"a\Ab", "ab", is_depth).unwrap());
+            assert!(do_matching(r"abc\Z", "abc", is_depth).unwrap());
+            assert!(do_matching(r"abc\Z", "abc\n", is_depth).unwrap());
+            assert!(!do_matching(r"abc\Z", "abc\nd", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_multi_line() {
+        for is_depth in [true, false] {
+            assert!(do_matching("(?m)a\n^b", "a\nb", is_depth).unwrap());
+            assert!(!do_matching("a\n^b", "a\nb", is_depth).unwrap());
+            assert!(do_matching("(?m)a$\nb$", "a\nb", is_depth).unwrap());
+            assert!(!do_matching("a$\nb", "a\nb", is_depth).unwrap());
+            // `\A`と`\z`は`(?m)`の影響を受けない
+            assert!(do_matching(r"(?m)\Aabc\z", "abc", is_depth).unwrap());
+            assert!(!do_matching("(?m)a\n\\Ab", "a\nb", is_depth).unwrap());
+            assert!(!do_matching(r"(?m)a\z", "a\nb", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_property() {
+        for is_depth in [true, false] {
+            assert!(do_matching(r"^\p{L}+$", "héllo", is_depth).unwrap());
+            assert!(!do_matching(r"^\p{L}+$", "hé1lo", is_depth).unwrap());
+            assert!(do_matching(r"\p{L}+\p{N}", "hé1lo", is_depth).unwrap());
+            assert!(do_matching(r"\p{Lu}\p{Ll}", "Éa", is_depth).unwrap());
+            assert!(do_matching(r"^\P{N}+$", "abc", is_depth).unwrap());
+            assert!(!do_matching(r"^\P{N}+$", "a1c", is_depth).unwrap());
+            assert!(do_matching(r"\p{Foo}", "a", is_depth).is_err());
+        }
+
+        // `(?i)`のときは、大文字と小文字のどちらかが性質を持てばよい
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            assert!(do_matching_with_strategy(r"(?i)\p{Lu}", "a", strategy).unwrap());
+            assert!(do_matching_with_strategy(r"(?i)^\p{Ll}+$", "ABC", strategy).unwrap());
+            assert!(!do_matching_with_strategy(r"\p{Lu}", "a", strategy).unwrap());
+        }
+        assert!(do_matching_iter(r"(?i)\p{Lu}", "a".chars()).unwrap());
+        assert!(do_matching_bytes(r"(?i)\p{Lu}", b"a").unwrap());
+        let mut cache = DfaCache::default();
+        assert!(do_matching_with_cache(r"(?i)\p{Lu}", "a", &mut cache).unwrap());
+        assert!(Regex::new(r"(?i)\p{Lu}").unwrap().is_match("1a").unwrap());
+    }
+
+    #[test]
+    fn test_class_intersection() {
+        for is_depth in [true, false] {
+            assert!(do_matching(r"^[a-z&&[^aeiou]]+$", "bcd", is_depth).unwrap());
+            assert!(!do_matching(r"^[a-z&&[^aeiou]]+$", "a", is_depth).unwrap());
+            assert!(!do_matching(r"^[a-z&&[^aeiou]]+$", "bad", is_depth).unwrap());
+            assert!(do_matching(r"^[[:word:]&&[^[:digit:]]]$", "x", is_depth).unwrap());
+            assert!(!do_matching(r"^[[:alnum:]&&[:digit:]]$", "x", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        for is_depth in [true, false] {
+            assert!(do_matching("(?i)abc", "ABC", is_depth).unwrap());
+            assert!(do_matching("(?i)abc", "AbC", is_depth).unwrap());
+            assert!(do_matching("(?i)ABC", "abc", is_depth).unwrap());
+            assert!(!do_matching("abc", "ABC", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_extended() {
+        for is_depth in [true, false] {
+            assert!(do_matching("(?x) a b c", "abc", is_depth).unwrap());
+            assert!(do_matching("(?x)\n  a+\n  b\n", "aab", is_depth).unwrap());
+            assert!(do_matching(r"(?x) a\ b [ ]c", "a b c", is_depth).unwrap());
+            assert!(!do_matching("(?x) a b c", "a b c", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_dot_all() {
+        for is_depth in [true, false] {
+            assert!(do_matching("(?s)a.b", "a\nb", is_depth).unwrap());
+            assert!(!do_matching("a.b", "a\nb", is_depth).unwrap());
+            assert!(do_matching("a.b", "a b", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_atomic() {
+        for is_depth in [true, false] {
+            assert!(!do_matching("(?>a*)a", "aaa", is_depth).unwrap());
+            assert!(do_matching("(a*)a", "aaa", is_depth).unwrap());
+            assert!(!do_matching("(?>a|ab)c", "abc", is_depth).unwrap());
+            assert!(do_matching("(?>ab|a)c", "abc", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_look_ahead() {
+        for is_depth in [true, false] {
+            assert!(do_matching("foo(?!bar)", "foobaz", is_depth).unwrap());
+            assert!(!do_matching("foo(?!bar)", "foobar", is_depth).unwrap());
+            assert!(do_matching("foo(?=bar)bar", "foobar", is_depth).unwrap());
+            assert!(!do_matching("foo(?=bar)", "foobaz", is_depth).unwrap());
+            // 空の否定先読みは必ず失敗する
+            assert!(!do_matching("a(?!)", "a", is_depth).unwrap());
+            assert!(do_matching("a(?=)", "a", is_depth).unwrap());
+            // `(?:)`も空文字列にマッチするパターンとして繰り返せる
+            assert!(do_matching("(?:)*b", "b", is_depth).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_look_behind() {
+        for is_depth in [true, false] {
+            assert!(do_matching(r".(?<=\$)[0-9]+", "$100", is_depth).unwrap());
+            assert!(!do_matching(r".(?<=\$)[0-9]+", "#100", is_depth).unwrap());
+            assert!(do_matching(r".(?<!\$)[0-9]+", "#100", is_depth).unwrap());
+            assert!(!do_matching(r".(?<!\$)[0-9]+", "$100", is_depth).unwrap());
+            // 先頭より前は読めないので、否定後読みは成功する
+            assert!(do_matching(r"(?<!a)b", "b", is_depth).unwrap());
+            assert!(!do_matching(r"a(?<!)", "a", is_depth).unwrap());
+            assert!(do_matching(r"(?<=a|bc)d", "d", is_depth).is_err());
+        }
+    }
+
+    #[test]
+    fn test_back_ref() {
+        assert!(do_matching(r"(ab)\1", "abab", true).unwrap());
+        assert!(!do_matching(r"(ab)\1", "abcd", true).unwrap());
+        assert!(do_matching(r"^(a|b)c\1$", "bcb", true).unwrap());
+        assert!(!do_matching(r"^(a|b)c\1$", "bca", true).unwrap());
+        assert!(do_matching(r"(?i)(ab)\1", "abAB", true).unwrap());
+        // 開いていないグループは参照できない
+        assert!(do_matching(r"(a)\2", "aa", true).is_err());
+        assert!(do_matching(r"(?<w>[[:word:]]+)[[:space:]]\k<w>", "hi hi", true).unwrap());
+        assert!(!do_matching(r"(?<w>[[:word:]]+)[[:space:]]\k<w>$", "hi ho", true).unwrap());
+        assert!(do_matching(r"(?<w>a)\k<v>", "aa", true).is_err());
+        assert!(do_matching(r"(?:a|b)(c)\1", "acc", true).unwrap());
+        // 幅優先探索では後方参照を使えない
+        assert!(do_matching(r"(ab)\1", "abab", false).is_err());
+    }
+
+    #[test]
+    fn test_word_boundary() {
+        for is_depth in [true, false] {
+            assert!(do_matching(r".*\bword\b", "a word here", is_depth).unwrap());
+            assert!(do_matching(r"\bword\b", "word", is_depth).unwrap());
+            assert!(!do_matching(r".*\bword\b", "awordb", is_depth).unwrap());
+        }
+
+        // `\b`は`[[:word:]]`と同じ文字を単語の文字とみなす
+        let m = Regex::new(r"[[:word:]]+\b")
+            .unwrap()
+            .find("café")
+            .unwrap()
+            .unwrap();
+        assert_eq!(m.as_str(), "caf");
+        let re = Regex::new(r"\b[[:word:]]+\b").unwrap();
+        let words = re
+            .find_iter("naïve x")
+            .map(|m| m.unwrap().as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(words, vec!["na", "ve", "x"]);
     }
 }
@@ -0,0 +1,196 @@
+use crate::helper::safe_add;
+use alloc::{vec, vec::Vec};
+
+use super::{
+    class::is_word_char,
+    evaluator::{eq_char, in_class, in_property, in_range, is_any, EvalError},
+    Flags, Instruction,
+};
+
+/// バイトを文字として見る。ASCII以外のバイトは`None`
+fn byte_char(b: &u8) -> Option<char> {
+    b.is_ascii().then_some(*b as char)
+}
+
+/// 単語を構成するバイトか。`[:word:]`と同じく、ASCIIの英数字と`_`
+fn is_word_byte(b: &u8) -> bool {
+    byte_char(b).as_ref().is_some_and(is_word_char)
+}
+
+/// 入力の`sp`から`s`が続いていれば、その次の位置を返す
+fn match_bytes(s: &str, line: &[u8], sp: usize, flags: &Flags) -> Option<usize> {
+    let mut next = sp;
+    for c in s.chars() {
+        let sp_c = line.get(next).and_then(byte_char)?;
+        if !eq_char(&c, &sp_c, flags) {
+            return None;
+        }
+        next += 1;
+    }
+    Some(next)
+}
+
+/// バイト列に対して評価できるプログラムか
+///
+/// パターンの文字はすべてASCIIでなければならない。先読み・後読み、アトミックグループ、後方参照は使えない
+fn is_supported(insts: &[Instruction]) -> bool {
+    insts.iter().all(|inst| match inst {
+        Instruction::Char(c) => c.is_ascii(),
+        Instruction::StringMatch(s) => s.is_ascii(),
+        Instruction::CharClass(class) => class
+            .ranges
+            .iter()
+            .all(|(lo, hi)| lo.is_ascii() && hi.is_ascii()),
+        Instruction::RangeChar(lo, hi) => lo.is_ascii() && hi.is_ascii(),
+        Instruction::Property(_)
+        | Instruction::Any
+        | Instruction::Start
+        | Instruction::End
+        | Instruction::LineStart
+        | Instruction::LineEnd
+        | Instruction::WordBoundary
+        | Instruction::Save(_)
+        | Instruction::Match
+        | Instruction::Jump(_)
+        | Instruction::Split(..) => true,
+        _ => false,
+    })
+}
+
+/// 文字に変換せずに、バイト列の先頭からPike VMで評価する
+///
+/// 1バイトを1文字として扱う。ASCII以外のバイトは、否定された文字クラスと`.`にだけマッチする
+pub fn eval_bytes(insts: &[Instruction], line: &[u8], flags: &Flags) -> Result<bool, EvalError> {
+    if !is_supported(insts) {
+        return Err(EvalError::BytesNotSupported);
+    }
+
+    // `pending[sp]`は、`sp`から評価を始めるスレッドのプログラムカウンタ
+    let mut pending = vec![Vec::<usize>::new(); line.len() + 1];
+    pending[0].push(0);
+
+    for sp in 0..=line.len() {
+        let mut visited = vec![false; insts.len()];
+        let mut stack = core::mem::take(&mut pending[sp]);
+        // 入力の終わりでは`None`、ASCII以外のバイトでは`Some(None)`
+        let sp_c = line.get(sp).map(byte_char);
+
+        while let Some(pc) = stack.pop() {
+            let Some(inst) = insts.get(pc) else {
+                return Err(EvalError::InvalidPC);
+            };
+            if core::mem::replace(&mut visited[pc], true) {
+                continue;
+            }
+            let mut next = pc;
+            safe_add(&mut next, &1, || EvalError::PCOverFlow)?;
+
+            // 入力を消費する命令は、消費し終わった位置とプログラムカウンタを返す
+            let advance = match inst {
+                Instruction::Char(c) => {
+                    matches!(sp_c, Some(Some(sp_c)) if eq_char(c, &sp_c, flags))
+                        .then_some((sp + 1, next))
+                }
+                Instruction::StringMatch(s) => {
+                    match_bytes(s, line, sp, flags).map(|end| (end, next))
+                }
+                Instruction::CharClass(class) => match sp_c {
+                    Some(Some(sp_c)) => in_class(class, &sp_c, flags),
+                    Some(None) => class.negated,
+                    None => false,
+                }
+                .then_some((sp + 1, next)),
+                Instruction::RangeChar(lo, hi) => {
+                    matches!(sp_c, Some(Some(sp_c)) if in_range(lo, hi, &sp_c, flags))
+                        .then_some((sp + 1, next))
+                }
+                Instruction::Property(prop) => match sp_c {
+                    Some(Some(sp_c)) => in_property(prop, &sp_c, flags),
+                    Some(None) => prop.negated,
+                    None => false,
+                }
+                .then_some((sp + 1, next)),
+                Instruction::Any => match sp_c {
+                    Some(Some(sp_c)) => is_any(&sp_c, flags),
+                    Some(None) => true,
+                    None => false,
+                }
+                .then_some((sp + 1, next)),
+                Instruction::Start => (sp == 0).then_some((sp, next)),
+                Instruction::End => (sp == line.len()).then_some((sp, next)),
+                Instruction::LineStart => {
+                    (sp == 0 || line.get(sp - 1) == Some(&b'\n')).then_some((sp, next))
+                }
+                Instruction::LineEnd => {
+                    (sp == line.len() || line.get(sp) == Some(&b'\n')).then_some((sp, next))
+                }
+                Instruction::WordBoundary => {
+                    let before = sp
+                        .checked_sub(1)
+                        .and_then(|i| line.get(i))
+                        .is_some_and(is_word_byte);
+                    let after = line.get(sp).is_some_and(is_word_byte);
+                    (before != after).then_some((sp, next))
+                }
+                // 位置を記録しないので、何もしない
+                Instruction::Save(_) => Some((sp, next)),
+                Instruction::Match => return Ok(true),
+                Instruction::Jump(addr) => Some((sp, *addr)),
+                Instruction::Split(addr1, addr2) => {
+                    stack.push(*addr2);
+                    Some((sp, *addr1))
+                }
+                _ => return Err(EvalError::BytesNotSupported),
+            };
+
+            match advance {
+                Some((end, pc)) if end == sp => stack.push(pc),
+                Some((end, pc)) => pending[end].push(pc),
+                None => {}
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::testing::{assert_same_as_pike, to_insts, PATTERNS};
+
+    use super::*;
+
+    #[test]
+    fn same_as_chars() {
+        assert_same_as_pike(PATTERNS, |insts, line, flags| {
+            eval_bytes(insts, line.as_bytes(), flags)
+        });
+    }
+
+    #[test]
+    fn non_ascii_bytes() {
+        let flags = Flags::default();
+        let line: &[u8] = b"ab\xffc";
+
+        assert_eq!(eval_bytes(&to_insts("ab.c"), line, &flags), Ok(true));
+        assert_eq!(eval_bytes(&to_insts("ab[^x]c"), line, &flags), Ok(true));
+        assert_eq!(eval_bytes(&to_insts(r"ab\P{L}c"), line, &flags), Ok(true));
+        assert_eq!(eval_bytes(&to_insts("ab[a-z]c"), line, &flags), Ok(false));
+        assert_eq!(
+            eval_bytes(&to_insts(r"ab[[:word:]]"), line, &flags),
+            Ok(false)
+        );
+    }
+
+    #[test]
+    fn not_supported() {
+        let flags = Flags::default();
+        for pattern in ["é", "[à-ä]", "(?=a)a", r"(a)\1", "(?>a)"] {
+            assert_eq!(
+                eval_bytes(&to_insts(pattern), b"a", &flags),
+                Err(EvalError::BytesNotSupported),
+                "{pattern}"
+            );
+        }
+    }
+}
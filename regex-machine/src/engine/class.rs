@@ -0,0 +1,312 @@
+use alloc::vec::Vec;
+use core::fmt::{self, Display};
+
+/// 文字クラス`[...]`が表す文字の集合
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CharClass {
+    /// 含まれる文字の範囲。両端を含む
+    pub ranges: Vec<(char, char)>,
+    /// `[^...]`のように否定されているか
+    pub negated: bool,
+}
+
+impl CharClass {
+    /// `c`がこのクラスにマッチするか
+    pub fn contains(&self, c: char) -> bool {
+        let in_ranges = self.ranges.iter().any(|(lo, hi)| *lo <= c && c <= *hi);
+        in_ranges != self.negated
+    }
+
+    /// 範囲を追加する
+    pub fn push(&mut self, lo: char, hi: char) {
+        self.ranges.push((lo, hi))
+    }
+
+    /// `[a-z&&[^aeiou]]`のように、両方に含まれる文字だけのクラスを返す
+    pub fn intersect(&self, other: &CharClass) -> CharClass {
+        let a = self.positive_ranges();
+        let b = other.positive_ranges();
+
+        let mut ranges = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        while let (Some(&(lo1, hi1)), Some(&(lo2, hi2))) = (a.get(i), b.get(j)) {
+            let lo = lo1.max(lo2);
+            let hi = hi1.min(hi2);
+            if lo <= hi {
+                ranges.push((lo, hi));
+            }
+            // 先に終わる方を進める
+            if hi1 < hi2 {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+
+        CharClass {
+            ranges,
+            negated: false,
+        }
+    }
+
+    /// 否定を解消し、整列して重なりをまとめた範囲を返す
+    fn positive_ranges(&self) -> Vec<(char, char)> {
+        let mut sorted = self.ranges.clone();
+        sorted.sort();
+
+        let mut merged: Vec<(char, char)> = Vec::new();
+        for (lo, hi) in sorted {
+            match merged.last_mut() {
+                Some((_, last)) if next_char(*last).is_none_or(|next| lo <= next) => {
+                    *last = (*last).max(hi);
+                }
+                _ => merged.push((lo, hi)),
+            }
+        }
+
+        if !self.negated {
+            return merged;
+        }
+
+        // 範囲の隙間を集める
+        let mut complement = Vec::new();
+        let mut start = Some('\0');
+        for (lo, hi) in merged {
+            if let Some(s) = start {
+                if s < lo {
+                    if let Some(end) = prev_char(lo) {
+                        complement.push((s, end));
+                    }
+                }
+            }
+            start = next_char(hi);
+        }
+        if let Some(s) = start {
+            complement.push((s, char::MAX));
+        }
+        complement
+    }
+}
+
+/// `c`の次の文字。サロゲートの範囲は飛ばす
+fn next_char(c: char) -> Option<char> {
+    match c {
+        '\u{D7FF}' => Some('\u{E000}'),
+        _ => char::from_u32(c as u32 + 1),
+    }
+}
+
+/// `c`の前の文字。サロゲートの範囲は飛ばす
+fn prev_char(c: char) -> Option<char> {
+    match c {
+        '\u{E000}' => Some('\u{D7FF}'),
+        _ => char::from_u32((c as u32).checked_sub(1)?),
+    }
+}
+
+/// `[:word:]`の範囲。`\b`の単語境界もこの範囲で判定する
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+
+/// 単語を構成する文字か
+pub fn is_word_char(c: &char) -> bool {
+    WORD.iter().any(|(lo, hi)| lo <= c && c <= hi)
+}
+
+/// `[:alpha:]`のようなPOSIX文字クラスの範囲を返す
+///
+/// 存在しない名前の場合は`None`
+pub fn posix_class(name: &str) -> Option<&'static [(char, char)]> {
+    let ranges: &[(char, char)] = match name {
+        "alpha" => &[('A', 'Z'), ('a', 'z')],
+        "digit" => &[('0', '9')],
+        "alnum" => &[('0', '9'), ('A', 'Z'), ('a', 'z')],
+        "upper" => &[('A', 'Z')],
+        "lower" => &[('a', 'z')],
+        "space" => &[('\t', '\r'), (' ', ' ')],
+        "blank" => &[('\t', '\t'), (' ', ' ')],
+        "punct" => &[('!', '/'), (':', '@'), ('[', '`'), ('{', '~')],
+        "xdigit" => &[('0', '9'), ('A', 'F'), ('a', 'f')],
+        "word" => WORD,
+        "cntrl" => &[('\0', '\x1f'), ('\x7f', '\x7f')],
+        "print" => &[(' ', '~')],
+        "graph" => &[('!', '~')],
+        _ => return None,
+    };
+    Some(ranges)
+}
+
+/// `\p{L}`で指定するUnicodeの一般カテゴリ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Category {
+    /// `L`: 文字
+    Letter,
+    /// `Lu`: 大文字
+    UppercaseLetter,
+    /// `Ll`: 小文字
+    LowercaseLetter,
+    /// `N`: 数字
+    Number,
+}
+
+impl Category {
+    /// `L`や`Letter`のような名前からカテゴリを返す
+    ///
+    /// 存在しない名前の場合は`None`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let category = match name {
+            "L" | "Letter" => Category::Letter,
+            "Lu" | "Uppercase_Letter" => Category::UppercaseLetter,
+            "Ll" | "Lowercase_Letter" => Category::LowercaseLetter,
+            "N" | "Number" => Category::Number,
+            _ => return None,
+        };
+        Some(category)
+    }
+
+    /// 表を持たずに済むよう、標準ライブラリの判定を使う
+    fn contains(&self, c: char) -> bool {
+        match self {
+            Category::Letter => c.is_alphabetic(),
+            Category::UppercaseLetter => c.is_uppercase(),
+            Category::LowercaseLetter => c.is_lowercase(),
+            Category::Number => c.is_numeric(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Category::Letter => "L",
+            Category::UppercaseLetter => "Lu",
+            Category::LowercaseLetter => "Ll",
+            Category::Number => "N",
+        }
+    }
+}
+
+/// `\p{L}`や`\P{L}`が表す文字の集合
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Property {
+    pub category: Category,
+    /// `\P{...}`のように否定されているか
+    pub negated: bool,
+}
+
+impl Property {
+    /// `c`がこのカテゴリにマッチするか
+    pub fn contains(&self, c: char) -> bool {
+        self.category.contains(c) != self.negated
+    }
+}
+
+impl Display for Property {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let p = if self.negated { 'P' } else { 'p' };
+        write!(f, "\\{p}{{{}}}", self.category.name())
+    }
+}
+
+impl Display for CharClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[")?;
+        if self.negated {
+            write!(f, "^")?;
+        }
+        for (lo, hi) in &self.ranges {
+            if lo == hi {
+                write!(f, "{}", lo.escape_debug())?;
+            } else {
+                write!(f, "{}-{}", lo.escape_debug(), hi.escape_debug())?;
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{string::ToString, vec};
+
+    #[test]
+    fn contains() {
+        let class = CharClass {
+            ranges: vec![('a', 'c'), ('x', 'x')],
+            negated: false,
+        };
+        assert!(class.contains('b'));
+        assert!(class.contains('x'));
+        assert!(!class.contains('d'));
+
+        let class = CharClass {
+            negated: true,
+            ..class
+        };
+        assert!(!class.contains('b'));
+        assert!(class.contains('d'));
+    }
+
+    #[test]
+    fn posix() {
+        let digit = CharClass {
+            ranges: posix_class("digit").unwrap().to_vec(),
+            negated: false,
+        };
+        assert!(digit.contains('5'));
+        assert!(!digit.contains('a'));
+
+        assert!(posix_class("foo").is_none());
+    }
+
+    #[test]
+    fn intersect() {
+        let lower = CharClass {
+            ranges: vec![('a', 'z')],
+            negated: false,
+        };
+        let not_vowel = CharClass {
+            ranges: vec![('a', 'a'), ('e', 'e'), ('i', 'i'), ('o', 'o'), ('u', 'u')],
+            negated: true,
+        };
+
+        let consonant = lower.intersect(&not_vowel);
+        assert_eq!(
+            consonant.ranges,
+            vec![('b', 'd'), ('f', 'h'), ('j', 'n'), ('p', 't'), ('v', 'z')]
+        );
+        assert!(!consonant.negated);
+
+        // 重なっている範囲はまとめる
+        let digit = CharClass {
+            ranges: vec![('5', '9'), ('0', '3'), ('4', '6')],
+            negated: false,
+        };
+        assert_eq!(digit.intersect(&digit).ranges, vec![('0', '9')]);
+
+        let none = lower.intersect(&digit);
+        assert!(none.ranges.is_empty());
+    }
+
+    #[test]
+    fn property() {
+        let letter = Property {
+            category: Category::from_name("L").unwrap(),
+            negated: false,
+        };
+        assert!(letter.contains('é'));
+        assert!(letter.contains('あ'));
+        assert!(!letter.contains('1'));
+
+        let not_upper = Property {
+            category: Category::from_name("Uppercase_Letter").unwrap(),
+            negated: true,
+        };
+        assert!(not_upper.contains('a'));
+        assert!(!not_upper.contains('É'));
+        assert_eq!(not_upper.to_string(), r"\P{Lu}");
+
+        assert!(Category::from_name("Foo").is_none());
+    }
+}
@@ -1,5 +1,9 @@
-use super::{parser::Ast, Instruction};
+use super::{
+    parser::{repeat_copies, Ast},
+    CharClass, Flags, Instruction, Property,
+};
 use crate::helper::safe_add;
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
 
 #[derive(Debug)]
 pub enum CodeGenError {
@@ -8,20 +12,60 @@ pub enum CodeGenError {
     FailStar,
     FailOr,
     FailQuestion,
+    FailAtomic,
+    FailLookAround,
+    /// 後読みの中身の長さが一定でない
+    VariableLookBehind,
+    /// `{n,m}`を展開すると大きくなりすぎる
+    RepeatTooLarge,
+    /// Astの入れ子が深すぎる
+    TooDeep,
 }
 
-impl std::fmt::Display for CodeGenError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+/// `{n,m}`1つあたりで展開するコピーの数の上限
+const MAX_UNROLL: usize = 100000;
+
+/// `get_code`で生成できるAstの入れ子の深さの上限
+const MAX_DEPTH: usize = 1000;
+
+impl core::fmt::Display for CodeGenError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "CodeGenError: {self:?}")
     }
 }
 
-impl std::error::Error for CodeGenError {}
+impl core::error::Error for CodeGenError {}
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct Generator {
     pc: usize,
     insts: Vec<Instruction>,
+    /// 今生成しているAstの深さ
+    depth: usize,
+    /// 深さの上限。これより深いAstは、スタックを溢れさせないようエラーにする
+    max_depth: usize,
+}
+
+/// 生成したプログラムの大きさ
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CodeStats {
+    /// 命令の数
+    pub insts: usize,
+    /// `Split`の数
+    pub splits: usize,
+    /// `Jump`の数
+    pub jumps: usize,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator {
+            pc: 0,
+            insts: Vec::new(),
+            depth: 0,
+            max_depth: MAX_DEPTH,
+        }
+    }
 }
 
 impl Generator {
@@ -31,16 +75,44 @@ impl Generator {
     }
 
     fn gen_expr(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
+        if self.depth >= self.max_depth {
+            return Err(CodeGenError::TooDeep);
+        }
+
+        self.depth += 1;
+        let result = self.gen_node(ast);
+        self.depth -= 1;
+        result
+    }
+
+    fn gen_node(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
         match ast {
             Ast::Char(c) => self.gen_char(c),
-            Ast::Plus(ast) => self.gen_plus(ast),
-            Ast::Star(ast) => self.gen_star(ast),
-            Ast::Question(ast) => self.gen_question(ast),
+            Ast::Plus(ast) => self.gen_plus(ast, false),
+            Ast::Star(ast) => self.gen_star(ast, false),
+            Ast::Question(ast) => self.gen_question(ast, false),
+            Ast::PlusLazy(ast) => self.gen_plus(ast, true),
+            Ast::StarLazy(ast) => self.gen_star(ast, true),
+            Ast::QuestionLazy(ast) => self.gen_question(ast, true),
+            Ast::Repeat(ast, min, max) => self.gen_repeat(ast, *min, *max, false),
+            Ast::RepeatLazy(ast, min, max) => self.gen_repeat(ast, *min, *max, true),
             Ast::Or(e1, e2) => self.gen_or(e1, e2),
             Ast::Seq(seq) => self.gen_seq(seq),
             Ast::Any => self.gen_any(),
             Ast::Start => self.gen_start(),
             Ast::End => self.gen_end(),
+            Ast::LineStart => self.gen_line_start(),
+            Ast::LineEnd => self.gen_line_end(),
+            Ast::WordBoundary => self.gen_word_boundary(),
+            Ast::Class(class) => self.gen_class(class),
+            Ast::Property(prop) => self.gen_property(prop),
+            Ast::Atomic(ast) => self.gen_atomic(ast),
+            Ast::LookAhead(ast) => self.gen_look_ahead(ast, false),
+            Ast::NegLookAhead(ast) => self.gen_look_ahead(ast, true),
+            Ast::LookBehind(ast) => self.gen_look_behind(ast, false),
+            Ast::NegLookBehind(ast) => self.gen_look_behind(ast, true),
+            Ast::Capture(n, ast) => self.gen_capture(*n, ast),
+            Ast::BackRef(n) => self.gen_back_ref(*n),
         }
     }
 
@@ -51,25 +123,54 @@ impl Generator {
         Ok(())
     }
 
+    /// 2文字以上続く文字は、1つの`StringMatch`にまとめる
     fn gen_seq(&mut self, exprs: &[Ast]) -> Result<(), CodeGenError> {
-        for e in exprs {
-            self.gen_expr(e)?
+        let mut rest = exprs;
+        while let Some(e) = rest.first() {
+            let run = rest
+                .iter()
+                .map_while(|e| match e {
+                    Ast::Char(c) => Some(*c),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+
+            if run.len() >= 2 {
+                self.gen_string(run.iter().collect())?;
+                rest = &rest[run.len()..];
+            } else {
+                self.gen_expr(e)?;
+                rest = &rest[1..];
+            }
         }
         Ok(())
     }
 
-    fn gen_plus(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
+    fn gen_string(&mut self, s: String) -> Result<(), CodeGenError> {
+        let inst = Instruction::StringMatch(s);
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// `is_lazy`が`true`のときは、繰り返しを抜ける方を優先する
+    fn gen_plus(&mut self, ast: &Ast, is_lazy: bool) -> Result<(), CodeGenError> {
         let start_addr = self.pc;
         self.gen_expr(ast)?;
 
         self.inc_pc()?;
-        let split = Instruction::Split(start_addr, self.pc);
+        let split = if is_lazy {
+            Instruction::Split(self.pc, start_addr)
+        } else {
+            Instruction::Split(start_addr, self.pc)
+        };
         self.insts.push(split);
 
         Ok(())
     }
 
-    fn gen_star(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
+    /// `is_lazy`が`true`のときは、繰り返しを抜ける方を優先する
+    fn gen_star(&mut self, ast: &Ast, is_lazy: bool) -> Result<(), CodeGenError> {
         let split_addr = self.pc;
         self.inc_pc()?;
 
@@ -83,8 +184,11 @@ impl Generator {
         self.insts.push(jump);
         self.inc_pc()?;
 
-        if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
+        if let Some(Instruction::Split(l1, l2)) = self.insts.get_mut(split_addr) {
             *l2 = self.pc;
+            if is_lazy {
+                core::mem::swap(l1, l2);
+            }
         } else {
             return Err(CodeGenError::FailStar);
         }
@@ -92,7 +196,8 @@ impl Generator {
         Ok(())
     }
 
-    fn gen_question(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
+    /// `is_lazy`が`true`のときは、`ast`を飛ばす方を優先する
+    fn gen_question(&mut self, ast: &Ast, is_lazy: bool) -> Result<(), CodeGenError> {
         let split_addr = self.pc;
         self.inc_pc()?;
         // 次の行に飛ぶか、その終わりに飛ぶか。`ast`の次の行は`ast`を生成しないと値が分からないので、仮に0を設定しておく
@@ -101,8 +206,11 @@ impl Generator {
 
         self.gen_expr(ast)?;
 
-        if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
+        if let Some(Instruction::Split(l1, l2)) = self.insts.get_mut(split_addr) {
             *l2 = self.pc;
+            if is_lazy {
+                core::mem::swap(l1, l2);
+            }
         } else {
             return Err(CodeGenError::FailQuestion);
         }
@@ -110,37 +218,152 @@ impl Generator {
         Ok(())
     }
 
-    fn gen_or(&mut self, e1: &Ast, e2: &Ast) -> Result<(), CodeGenError> {
-        // `split`がある行
-        let split_addr = self.pc;
-        self.inc_pc()?;
+    /// `{n,m}`を、`ast`を`n`個と`ast?`を`m-n`個並べたものとして生成する。`{n,}`の場合は最後を`ast*`にする
+    fn gen_repeat(
+        &mut self,
+        ast: &Ast,
+        min: usize,
+        max: Option<usize>,
+        is_lazy: bool,
+    ) -> Result<(), CodeGenError> {
+        if repeat_copies(min, max) > MAX_UNROLL {
+            return Err(CodeGenError::RepeatTooLarge);
+        }
 
-        // `e2`は`e1`を生成しないと値が分からないので、仮に0を設定しておく
-        let split = Instruction::Split(self.pc, 0);
+        for _ in 0..min {
+            self.gen_expr(ast)?;
+        }
+        match max {
+            Some(max) => {
+                for _ in min..max {
+                    self.gen_question(ast, is_lazy)?;
+                }
+            }
+            None => self.gen_star(ast, is_lazy)?,
+        }
+        Ok(())
+    }
 
-        self.insts.push(split);
-        self.gen_expr(e1)?;
+    /// `e1|e2`を生成する
+    ///
+    /// `a|b|c`は`a|(b|c)`のように右に入れ子になるが、入れ子の深さは増やさずに選択肢を順に生成する
+    fn gen_or<'a>(&mut self, mut e1: &'a Ast, mut e2: &'a Ast) -> Result<(), CodeGenError> {
+        // 各選択肢の後にある`jump`の行
+        let mut jmp_addrs = Vec::new();
+        loop {
+            // `split`がある行
+            let split_addr = self.pc;
+            self.inc_pc()?;
+
+            // `e2`は`e1`を生成しないと値が分からないので、仮に0を設定しておく
+            let split = Instruction::Split(self.pc, 0);
+
+            self.insts.push(split);
+            self.gen_expr(e1)?;
+
+            // 本当は最後の選択肢の次の値を入れたいが、生成しないとわからないので仮に0を設定しておく
+            jmp_addrs.push(self.pc);
+            self.insts.push(Instruction::Jump(0));
+
+            self.inc_pc()?;
+            // `e2`の始まる位置が確定したので、`split`を正しいものにする
+            if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
+                *l2 = self.pc;
+            } else {
+                return Err(CodeGenError::FailOr);
+            }
+
+            let Ast::Or(next1, next2) = e2 else {
+                break;
+            };
+            (e1, e2) = (next1, next2);
+        }
+
+        self.gen_expr(e2)?;
+
+        for jmp_addr in jmp_addrs {
+            if let Some(Instruction::Jump(l3)) = self.insts.get_mut(jmp_addr) {
+                *l3 = self.pc;
+            } else {
+                return Err(CodeGenError::FailOr);
+            }
+        }
+
+        Ok(())
+    }
 
-        let jmp_addr = self.pc;
-        // 本当は`e2`の次の値を入れたいが、生成しないとわからないので仮に0を設定しておく
-        self.insts.push(Instruction::Jump(0));
+    /// `ast`を`match`で終わるサブプログラムとして生成し、その前に`inst`で作った命令を置く
+    ///
+    /// `inst`にはサブプログラムの次のアドレスが渡される
+    fn gen_sub_program(
+        &mut self,
+        ast: &Ast,
+        inst: impl Fn(usize) -> Instruction,
+        err: CodeGenError,
+    ) -> Result<(), CodeGenError> {
+        let head_addr = self.pc;
+        self.inc_pc()?;
+        // 中身の次の行は`ast`を生成しないと値が分からないので、仮に0を設定しておく
+        self.insts.push(inst(0));
 
+        // 中身は`match`で終わらせて、そこで評価を打ち切る
+        self.gen_expr(ast)?;
         self.inc_pc()?;
-        // `e2`の始まる位置が確定したので、`split`を正しいものにする
-        if let Some(Instruction::Split(_, l2)) = self.insts.get_mut(split_addr) {
-            *l2 = self.pc;
+        self.insts.push(Instruction::Match);
+
+        if let Some(head) = self.insts.get_mut(head_addr) {
+            *head = inst(self.pc);
         } else {
-            return Err(CodeGenError::FailOr);
+            return Err(err);
         }
 
-        self.gen_expr(e2)?;
+        Ok(())
+    }
+
+    fn gen_atomic(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
+        self.gen_sub_program(ast, Instruction::Atomic, CodeGenError::FailAtomic)
+    }
 
-        if let Some(Instruction::Jump(l3)) = self.insts.get_mut(jmp_addr) {
-            *l3 = self.pc;
+    fn gen_look_ahead(&mut self, ast: &Ast, is_negative: bool) -> Result<(), CodeGenError> {
+        let inst = if is_negative {
+            Instruction::NegLookAhead
         } else {
-            return Err(CodeGenError::FailOr);
-        }
+            Instruction::LookAhead
+        };
+        self.gen_sub_program(ast, inst, CodeGenError::FailLookAround)
+    }
+
+    fn gen_look_behind(&mut self, ast: &Ast, is_negative: bool) -> Result<(), CodeGenError> {
+        // 何文字前から中身を評価すればよいかを、ここで決めておく
+        let Some(width) = fixed_width(ast) else {
+            return Err(CodeGenError::VariableLookBehind);
+        };
+        let inst = |addr| {
+            if is_negative {
+                Instruction::NegLookBehind(width, addr)
+            } else {
+                Instruction::LookBehind(width, addr)
+            }
+        };
+        self.gen_sub_program(ast, inst, CodeGenError::FailLookAround)
+    }
 
+    /// `n`番目のグループの開始位置を`2n`、終了位置を`2n+1`に記録する
+    fn gen_capture(&mut self, n: usize, ast: &Ast) -> Result<(), CodeGenError> {
+        self.insts.push(Instruction::Save(n * 2));
+        self.inc_pc()?;
+
+        self.gen_expr(ast)?;
+
+        self.insts.push(Instruction::Save(n * 2 + 1));
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_back_ref(&mut self, n: usize) -> Result<(), CodeGenError> {
+        let inst = Instruction::BackRef(n);
+        self.insts.push(inst);
+        self.inc_pc()?;
         Ok(())
     }
 
@@ -165,23 +388,273 @@ impl Generator {
         Ok(())
     }
 
+    fn gen_line_start(&mut self) -> Result<(), CodeGenError> {
+        let inst = Instruction::LineStart;
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_line_end(&mut self) -> Result<(), CodeGenError> {
+        let inst = Instruction::LineEnd;
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_word_boundary(&mut self) -> Result<(), CodeGenError> {
+        let inst = Instruction::WordBoundary;
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// `[0-9]`のように範囲が1つだけの場合は`RangeChar`にする
+    fn gen_class(&mut self, class: &CharClass) -> Result<(), CodeGenError> {
+        let inst = match class.ranges.as_slice() {
+            [(lo, hi)] if !class.negated => Instruction::RangeChar(*lo, *hi),
+            _ => Instruction::CharClass(class.clone()),
+        };
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    fn gen_property(&mut self, prop: &Property) -> Result<(), CodeGenError> {
+        let inst = Instruction::Property(*prop);
+        self.insts.push(inst);
+        self.inc_pc()?;
+        Ok(())
+    }
+
+    /// `addr`から`Jump`をたどった先のアドレス。`Jump`が輪になっている場合は`addr`のまま
+    fn jump_target(&self, addr: usize) -> usize {
+        let mut target = addr;
+        // 命令の数より多くたどった場合は輪になっている
+        for _ in 0..self.insts.len() {
+            match self.insts.get(target) {
+                Some(Instruction::Jump(next)) => target = *next,
+                _ => return target,
+            }
+        }
+        addr
+    }
+
+    /// `Jump`や`Split`の飛び先が`Jump`の場合に、その先へ直接飛ぶよう書き換える
+    fn collapse_jumps(&mut self) {
+        for pc in 0..self.insts.len() {
+            match self.insts[pc] {
+                Instruction::Jump(addr) => {
+                    self.insts[pc] = Instruction::Jump(self.jump_target(addr));
+                }
+                Instruction::Split(addr1, addr2) => {
+                    self.insts[pc] =
+                        Instruction::Split(self.jump_target(addr1), self.jump_target(addr2));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// `pc`の命令を実行した後に進む可能性のあるアドレス
+    fn successors(&self, pc: usize) -> Vec<usize> {
+        self.insts
+            .get(pc)
+            .map(|inst| successors(inst, pc))
+            .unwrap_or_default()
+    }
+
+    /// 0番地から到達できない命令を取り除き、飛び先のアドレスを詰め直す
+    fn remove_dead(&mut self) {
+        let mut reachable = vec![false; self.insts.len()];
+        let mut stack = vec![0];
+        while let Some(pc) = stack.pop() {
+            match reachable.get_mut(pc) {
+                Some(visited) if !*visited => *visited = true,
+                _ => continue,
+            }
+            stack.extend(self.successors(pc));
+        }
+
+        // 古いアドレスから新しいアドレスへの対応
+        let mut new_addr = Vec::with_capacity(self.insts.len());
+        let mut count = 0;
+        for is_reachable in &reachable {
+            new_addr.push(count);
+            if *is_reachable {
+                count += 1;
+            }
+        }
+        // 到達できる命令の飛び先は、必ず到達できる
+        let map = |addr: &mut usize| *addr = new_addr.get(*addr).copied().unwrap_or(count);
+
+        let insts = core::mem::take(&mut self.insts);
+        for (mut inst, is_reachable) in insts.into_iter().zip(reachable) {
+            if !is_reachable {
+                continue;
+            }
+            match &mut inst {
+                Instruction::Jump(addr)
+                | Instruction::Atomic(addr)
+                | Instruction::LookAhead(addr)
+                | Instruction::NegLookAhead(addr)
+                | Instruction::LookBehind(_, addr)
+                | Instruction::NegLookBehind(_, addr) => map(addr),
+                Instruction::Split(addr1, addr2) => {
+                    map(addr1);
+                    map(addr2);
+                }
+                _ => {}
+            }
+            self.insts.push(inst);
+        }
+        self.pc = self.insts.len();
+    }
+
     fn gen_code(&mut self, ast: &Ast) -> Result<(), CodeGenError> {
         self.gen_expr(ast)?;
         self.inc_pc()?;
         self.insts.push(Instruction::Match);
         Ok(())
     }
+
+    /// 生成した命令の数を、種類ごとに数える
+    fn stats(&self) -> CodeStats {
+        let count = |f: fn(&Instruction) -> bool| self.insts.iter().filter(|inst| f(inst)).count();
+        CodeStats {
+            insts: self.insts.len(),
+            splits: count(|inst| matches!(inst, Instruction::Split(..))),
+            jumps: count(|inst| matches!(inst, Instruction::Jump(_))),
+        }
+    }
+}
+
+/// `ast`がマッチする文字数。文字数が一定でない場合は`None`
+fn fixed_width(ast: &Ast) -> Option<usize> {
+    match ast {
+        Ast::Char(_) | Ast::Any | Ast::Class(_) | Ast::Property(_) => Some(1),
+        Ast::Start
+        | Ast::End
+        | Ast::LineStart
+        | Ast::LineEnd
+        | Ast::WordBoundary
+        | Ast::LookAhead(_)
+        | Ast::NegLookAhead(_)
+        | Ast::LookBehind(_)
+        | Ast::NegLookBehind(_) => Some(0),
+        Ast::Seq(seq) => seq
+            .iter()
+            .try_fold(0_usize, |sum, e| sum.checked_add(fixed_width(e)?)),
+        Ast::Or(e1, e2) => {
+            let w1 = fixed_width(e1)?;
+            (w1 == fixed_width(e2)?).then_some(w1)
+        }
+        Ast::Atomic(ast) | Ast::Capture(_, ast) => fixed_width(ast),
+        Ast::Repeat(ast, min, Some(max)) | Ast::RepeatLazy(ast, min, Some(max)) if min == max => {
+            fixed_width(ast)?.checked_mul(*min)
+        }
+        Ast::Repeat(..) | Ast::RepeatLazy(..) => None,
+        Ast::Plus(_)
+        | Ast::Star(_)
+        | Ast::Question(_)
+        | Ast::PlusLazy(_)
+        | Ast::StarLazy(_)
+        | Ast::QuestionLazy(_)
+        | Ast::BackRef(_) => None,
+    }
+}
+
+/// `pc`にある`inst`を実行した後に進む可能性のあるアドレス
+pub fn successors(inst: &Instruction, pc: usize) -> Vec<usize> {
+    match inst {
+        Instruction::Jump(addr) => vec![*addr],
+        Instruction::Split(addr1, addr2) => vec![*addr1, *addr2],
+        Instruction::Match => vec![],
+        // 中身は次の行から始まり、評価し終わったら`addr`へ進む
+        Instruction::Atomic(addr)
+        | Instruction::LookAhead(addr)
+        | Instruction::NegLookAhead(addr)
+        | Instruction::LookBehind(_, addr)
+        | Instruction::NegLookBehind(_, addr) => vec![pc + 1, *addr],
+        _ => vec![pc + 1],
+    }
+}
+
+/// マッチの1文字目になりうる文字の集合
+///
+/// どんな文字でもよい場合や、空文字列にマッチしうる場合など、絞り込めないときは`None`
+pub fn first_chars(insts: &[Instruction], flags: &Flags) -> Option<CharClass> {
+    // 大文字と小文字を区別しない場合は、範囲を広げる必要があるので諦める
+    if flags.case_insensitive {
+        return None;
+    }
+
+    let mut first = CharClass::default();
+    let mut stack = vec![0];
+    let mut visited = vec![false; insts.len()];
+    while let Some(pc) = stack.pop() {
+        if core::mem::replace(visited.get_mut(pc)?, true) {
+            continue;
+        }
+        match insts.get(pc)? {
+            Instruction::Char(c) => first.push(*c, *c),
+            Instruction::StringMatch(s) => {
+                let c = s.chars().next()?;
+                first.push(c, c);
+            }
+            Instruction::RangeChar(lo, hi) => first.push(*lo, *hi),
+            // 否定されたクラスはほとんどの文字を含むので、絞り込む意味がない
+            Instruction::CharClass(class) if !class.negated => first.ranges.extend(&class.ranges),
+            Instruction::Jump(addr) => stack.push(*addr),
+            Instruction::Split(addr1, addr2) => {
+                stack.push(*addr2);
+                stack.push(*addr1);
+            }
+            Instruction::Save(_) => stack.push(pc + 1),
+            _ => return None,
+        }
+    }
+
+    Some(first)
 }
 
 pub fn get_code(ast: &Ast) -> Result<Vec<Instruction>, CodeGenError> {
-    let mut generator = Generator::default();
+    get_code_with_max_depth(ast, MAX_DEPTH)
+}
+
+/// Astの入れ子の深さの上限を`max_depth`にして、コードを生成する
+pub fn get_code_with_max_depth(
+    ast: &Ast,
+    max_depth: usize,
+) -> Result<Vec<Instruction>, CodeGenError> {
+    Ok(generate(ast, max_depth)?.insts)
+}
+
+/// `get_code`で生成されるプログラムの大きさを返す
+pub fn get_code_stats(ast: &Ast) -> Result<CodeStats, CodeGenError> {
+    Ok(generate(ast, MAX_DEPTH)?.stats())
+}
+
+fn generate(ast: &Ast, max_depth: usize) -> Result<Generator, CodeGenError> {
+    let mut generator = Generator {
+        max_depth,
+        ..Default::default()
+    };
     generator.gen_code(ast)?;
-    Ok(generator.insts)
+    generator.collapse_jumps();
+    generator.remove_dead();
+    Ok(generator)
+}
+
+/// `get_code`と同じだが、以後変更しないプログラムとして返す
+pub fn get_program(ast: &Ast) -> Result<Box<[Instruction]>, CodeGenError> {
+    Ok(get_code(ast)?.into_boxed_slice())
 }
 
 #[cfg(test)]
 mod tests {
     use crate::engine::parser;
+    use alloc::{format, string::ToString};
 
     use super::*;
 
@@ -208,18 +681,33 @@ mod tests {
 
         generator.gen_expr(&ast).unwrap();
 
-        let expected = vec![
-            Instruction::Char('f'),
-            Instruction::Char('o'),
-            Instruction::Char('o'),
-            Instruction::Char('b'),
-            Instruction::Char('a'),
-            Instruction::Char('r'),
-        ];
+        let expected = vec![Instruction::StringMatch("foobar".to_string())];
 
         assert_eq!(generator.insts, expected)
     }
 
+    #[test]
+    fn string_regex() {
+        let ast = parser::parse("abcdef").unwrap();
+
+        // 1文字ずつなら6命令になるところが、1命令にまとまる
+        let code = get_code(&ast).unwrap();
+        assert_eq!(
+            code,
+            vec![
+                Instruction::StringMatch("abcdef".to_string()),
+                Instruction::Match
+            ]
+        );
+
+        // 文字以外で区切られた場合は、それぞれをまとめる。1文字だけなら`Char`のまま
+        let ast = parser::parse("ab.c(de)+").unwrap();
+        let code = get_code(&ast).unwrap();
+        assert_eq!(code[0], Instruction::StringMatch("ab".to_string()));
+        assert_eq!(code[2], Instruction::Char('c'));
+        assert_eq!(code[4], Instruction::StringMatch("de".to_string()));
+    }
+
     #[test]
     fn plus_regex() {
         let regex_str = "a+";
@@ -276,22 +764,130 @@ mod tests {
         generator.gen_expr(&ast).unwrap();
 
         let expected = vec![
-            Instruction::Split(1, 5),
+            Instruction::Split(1, 3),
+            Instruction::StringMatch("abc".to_string()),
+            Instruction::Jump(4),
+            Instruction::StringMatch("123".to_string()),
+        ];
+
+        assert_eq!(generator.insts, expected)
+    }
+
+    #[test]
+    fn lazy_regex() {
+        let regex_str = "a+?b*?";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        let expected = vec![
             Instruction::Char('a'),
+            Instruction::Split(2, 0),
+            Instruction::Split(5, 3),
             Instruction::Char('b'),
-            Instruction::Char('c'),
-            Instruction::Jump(8),
-            Instruction::Char('1'),
-            Instruction::Char('2'),
-            Instruction::Char('3'),
+            Instruction::Jump(2),
         ];
 
         assert_eq!(generator.insts, expected)
     }
 
     #[test]
-    fn any_regex() {
-        let regex_str = "ab.";
+    fn lazy_question_regex() {
+        let greedy = get_code(&parser::parse("a?").unwrap()).unwrap();
+        let lazy = get_code(&parser::parse("a??").unwrap()).unwrap();
+
+        // 分岐先が入れ替わり、`a`を飛ばす方が優先される
+        assert_eq!(greedy[0], Instruction::Split(1, 2));
+        assert_eq!(lazy[0], Instruction::Split(2, 1));
+        assert_eq!(greedy[1..], lazy[1..]);
+    }
+
+    #[test]
+    fn possessive_regex() {
+        let regex_str = "a++b";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        let expected = vec![
+            Instruction::Atomic(4),
+            Instruction::Char('a'),
+            Instruction::Split(1, 3),
+            Instruction::Match,
+            Instruction::Char('b'),
+        ];
+
+        assert_eq!(generator.insts, expected)
+    }
+
+    #[test]
+    fn look_ahead_regex() {
+        let regex_str = "a(?!b)";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        let expected = vec![
+            Instruction::Char('a'),
+            Instruction::NegLookAhead(4),
+            Instruction::Char('b'),
+            Instruction::Match,
+        ];
+
+        assert_eq!(generator.insts, expected)
+    }
+
+    #[test]
+    fn look_behind_regex() {
+        let regex_str = "(?<=ab|cd)e";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        let expected = vec![
+            Instruction::LookBehind(2, 6),
+            Instruction::Split(2, 4),
+            Instruction::StringMatch("ab".to_string()),
+            Instruction::Jump(5),
+            Instruction::StringMatch("cd".to_string()),
+            Instruction::Match,
+            Instruction::Char('e'),
+        ];
+
+        assert_eq!(generator.insts, expected)
+    }
+
+    #[test]
+    fn capture_regex() {
+        let save_indices = |regex_str| {
+            let ast = parser::parse(regex_str).unwrap();
+            let insts = get_code(&ast).unwrap();
+            insts
+                .iter()
+                .filter_map(|inst| match inst {
+                    Instruction::Save(n) => Some(*n),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(save_indices("(ab)(cd)"), vec![2, 3, 4, 5]);
+        assert_eq!(save_indices("(a(b))"), vec![2, 4, 5, 3]);
+        // `(?:...)`は番号を使わない
+        assert_eq!(save_indices("(?:ab)(cd)"), vec![2, 3]);
+    }
+
+    #[test]
+    fn back_ref_regex() {
+        let regex_str = r"(a|b)\1";
         let ast = parser::parse(regex_str).unwrap();
 
         let mut generator = Generator::default();
@@ -299,14 +895,261 @@ mod tests {
         generator.gen_expr(&ast).unwrap();
 
         let expected = vec![
+            Instruction::Save(2),
+            Instruction::Split(2, 4),
             Instruction::Char('a'),
+            Instruction::Jump(5),
             Instruction::Char('b'),
-            Instruction::Any,
+            Instruction::Save(3),
+            Instruction::BackRef(1),
         ];
 
         assert_eq!(generator.insts, expected)
     }
 
+    #[test]
+    fn variable_look_behind() {
+        for regex_str in ["(?<=a+)b", "(?<=a|bc)d", "(?<!a?)b"] {
+            let ast = parser::parse(regex_str).unwrap();
+            let mut generator = Generator::default();
+
+            let err = generator.gen_expr(&ast).err().unwrap();
+            assert!(matches!(err, CodeGenError::VariableLookBehind));
+        }
+    }
+
+    #[test]
+    fn repeat_regex() {
+        let regex_str = "a{2,3}";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        // `a`が2つと、`a?`が1つ
+        let expected = vec![
+            Instruction::Char('a'),
+            Instruction::Char('a'),
+            Instruction::Split(3, 4),
+            Instruction::Char('a'),
+        ];
+
+        assert_eq!(generator.insts.len(), 4);
+        assert_eq!(generator.insts, expected);
+
+        let ast = parser::Ast::Repeat(Box::new(parser::Ast::Char('a')), 0, Some(MAX_UNROLL + 1));
+        let err = get_code(&ast).err().unwrap();
+        assert!(matches!(err, CodeGenError::RepeatTooLarge));
+    }
+
+    #[test]
+    fn collapse_jumps() {
+        // `(ab|cd)|ef`
+        let ast = parser::Ast::Or(
+            Box::new(parser::Ast::Or(
+                Box::new(parser::Ast::Seq(vec![
+                    parser::Ast::Char('a'),
+                    parser::Ast::Char('b'),
+                ])),
+                Box::new(parser::Ast::Seq(vec![
+                    parser::Ast::Char('c'),
+                    parser::Ast::Char('d'),
+                ])),
+            )),
+            Box::new(parser::Ast::Seq(vec![
+                parser::Ast::Char('e'),
+                parser::Ast::Char('f'),
+            ])),
+        );
+
+        let mut generator = Generator::default();
+        generator.gen_code(&ast).unwrap();
+        // 内側の`Or`の終わりは、外側の`Or`の`Jump`になっている
+        assert_eq!(generator.insts[3], Instruction::Jump(5));
+        assert_eq!(generator.insts[5], Instruction::Jump(7));
+
+        generator.collapse_jumps();
+        let insts = generator.insts;
+        let is_jump = |addr: &usize| matches!(insts[*addr], Instruction::Jump(_));
+        for inst in &insts {
+            match inst {
+                Instruction::Jump(addr) => assert!(!is_jump(addr)),
+                Instruction::Split(addr1, addr2) => assert!(!is_jump(addr1) && !is_jump(addr2)),
+                _ => {}
+            }
+        }
+        assert_eq!(insts[3], Instruction::Jump(7));
+
+        // 輪になっている`Jump`はそのまま
+        let mut generator = Generator {
+            pc: 2,
+            insts: vec![Instruction::Jump(1), Instruction::Jump(0)],
+            ..Default::default()
+        };
+        generator.collapse_jumps();
+        assert_eq!(
+            generator.insts,
+            vec![Instruction::Jump(1), Instruction::Jump(0)]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let ast = parser::parse("a|b|c").unwrap();
+
+        // split, a, jmp, split, b, jmp, c, match
+        let stats = get_code_stats(&ast).unwrap();
+        assert_eq!(
+            stats,
+            CodeStats {
+                insts: 8,
+                splits: 2,
+                jumps: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn remove_dead() {
+        let mut generator = Generator {
+            pc: 6,
+            insts: vec![
+                Instruction::Char('a'),
+                Instruction::Jump(3),
+                Instruction::Char('b'),
+                Instruction::Split(4, 5),
+                Instruction::Char('c'),
+                Instruction::Match,
+                Instruction::Char('d'),
+            ],
+            ..Default::default()
+        };
+
+        generator.remove_dead();
+
+        let expected = vec![
+            Instruction::Char('a'),
+            Instruction::Jump(2),
+            Instruction::Split(3, 4),
+            Instruction::Char('c'),
+            Instruction::Match,
+        ];
+        assert_eq!(generator.insts, expected);
+        assert_eq!(generator.pc, 5);
+
+        // 先読みの中身と、その後ろはどちらも残る
+        let ast = parser::parse("(?=a)b").unwrap();
+        let mut generator = Generator::default();
+        generator.gen_code(&ast).unwrap();
+        let before = generator.insts.len();
+        generator.remove_dead();
+        assert_eq!(generator.insts.len(), before);
+    }
+
+    #[test]
+    fn first_chars() {
+        let first = |regex: &str| {
+            let mut flags = Flags::default();
+            let ast = parser::parse_with_flags(regex, &mut flags).unwrap();
+            super::first_chars(&get_code(&ast).unwrap(), &flags).map(|class| class.ranges)
+        };
+
+        assert_eq!(first("abc"), Some(vec![('a', 'a')]));
+        assert_eq!(
+            first("(x|[0-9]+)y|zz"),
+            Some(vec![('x', 'x'), ('0', '9'), ('z', 'z')])
+        );
+        assert_eq!(first("a?b"), Some(vec![('a', 'a'), ('b', 'b')]));
+
+        // 空文字列にマッチしうる場合や、どんな文字でもよい場合は絞り込めない
+        assert_eq!(first("a*"), None);
+        assert_eq!(first(".b"), None);
+        assert_eq!(first("[^a]"), None);
+        assert_eq!(first("^a"), None);
+        assert_eq!(first("(?i)a"), None);
+    }
+
+    #[test]
+    fn program() {
+        let ast = parser::parse("a|bc").unwrap();
+
+        let program = get_program(&ast).unwrap();
+
+        assert_eq!(program.as_ref(), get_code(&ast).unwrap().as_slice());
+        assert_eq!(program.len(), 5);
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut ast = parser::Ast::Char('a');
+        for _ in 0..5000 {
+            ast = parser::Ast::Seq(vec![ast]);
+        }
+
+        let err = get_code(&ast).err().unwrap();
+        assert!(matches!(err, CodeGenError::TooDeep));
+
+        // 上限を変えられる
+        let ast = parser::parse("((a))").unwrap();
+        assert!(get_code_with_max_depth(&ast, 6).is_ok());
+        let err = get_code_with_max_depth(&ast, 5).err().unwrap();
+        assert!(matches!(err, CodeGenError::TooDeep));
+
+        // 入れ子になった`(`は1段ずつ深くなる
+        let expr = format!("{}a{}", "(".repeat(2000), ")".repeat(2000));
+        let config = parser::ParseConfig::default().max_nesting(5000);
+        let ast = parser::parse_with_config(&expr, &mut crate::engine::Flags::default(), &config)
+            .unwrap();
+        let err = get_code(&ast).err().unwrap();
+        assert!(matches!(err, CodeGenError::TooDeep));
+    }
+
+    #[test]
+    fn long_or() {
+        // 入れ子になっていない選択肢は、いくつあっても深くならない
+        let expr = vec!["ab"; 5000].join("|");
+        let ast = parser::parse(&expr).unwrap();
+        let code = get_code_with_max_depth(&ast, 5).unwrap();
+        // `split`と`jump`が選択肢の間に1つずつ入る
+        let branch = get_code(&parser::parse("ab").unwrap()).unwrap().len() - 1;
+        assert_eq!(code.len(), 5000 * branch + 4999 * 2 + 1);
+    }
+
+    #[test]
+    fn unbounded_repeat_regex() {
+        // `a{0,}`は`a*`と同じコードになる
+        let expected = get_code(&parser::parse("a*").unwrap()).unwrap();
+        let actual = get_code(&parser::parse("a{0,}").unwrap()).unwrap();
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn any_regex() {
+        let regex_str = "ab.";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        let expected = vec![Instruction::StringMatch("ab".to_string()), Instruction::Any];
+
+        assert_eq!(generator.insts, expected)
+    }
+
+    #[test]
+    fn get_code_any() {
+        let ast = parser::Ast::Seq(vec![parser::Ast::Char('a'), parser::Ast::Any]);
+
+        let actual = get_code(&ast).unwrap();
+
+        let expected = vec![Instruction::Char('a'), Instruction::Any, Instruction::Match];
+
+        assert_eq!(actual, expected)
+    }
+
     #[test]
     fn start_regex() {
         let regex_str = "^ab";
@@ -318,8 +1161,7 @@ mod tests {
 
         let expected = vec![
             Instruction::Start,
-            Instruction::Char('a'),
-            Instruction::Char('b'),
+            Instruction::StringMatch("ab".to_string()),
         ];
 
         assert_eq!(generator.insts, expected)
@@ -334,10 +1176,72 @@ mod tests {
 
         generator.gen_expr(&ast).unwrap();
 
+        let expected = vec![Instruction::StringMatch("ab".to_string()), Instruction::End];
+
+        assert_eq!(generator.insts, expected)
+    }
+
+    #[test]
+    fn anchor_regex() {
+        let ast = parser::parse("^abc$").unwrap();
+
+        let actual = get_code(&ast).unwrap();
+
         let expected = vec![
-            Instruction::Char('a'),
-            Instruction::Char('b'),
+            Instruction::Start,
+            Instruction::StringMatch("abc".to_string()),
             Instruction::End,
+            Instruction::Match,
+        ];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn class_regex() {
+        let regex_str = "[a-c]+";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        // 文字ごとに分岐せず、1命令で判定する
+        let expected = vec![Instruction::RangeChar('a', 'c'), Instruction::Split(0, 2)];
+
+        assert_eq!(generator.insts, expected);
+
+        // 範囲が複数あるときや、否定されているときは`CharClass`
+        let ast = parser::parse("[a-cx][^0-9]").unwrap();
+        let actual = get_code(&ast).unwrap();
+        let expected = vec![
+            Instruction::CharClass(CharClass {
+                ranges: vec![('a', 'c'), ('x', 'x')],
+                negated: false,
+            }),
+            Instruction::CharClass(CharClass {
+                ranges: vec![('0', '9')],
+                negated: true,
+            }),
+            Instruction::Match,
+        ];
+
+        assert_eq!(actual, expected)
+    }
+
+    #[test]
+    fn word_boundary_regex() {
+        let regex_str = r"\ba\b";
+        let ast = parser::parse(regex_str).unwrap();
+
+        let mut generator = Generator::default();
+
+        generator.gen_expr(&ast).unwrap();
+
+        let expected = vec![
+            Instruction::WordBoundary,
+            Instruction::Char('a'),
+            Instruction::WordBoundary,
         ];
 
         assert_eq!(generator.insts, expected)
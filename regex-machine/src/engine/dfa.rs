@@ -0,0 +1,537 @@
+use crate::helper::HashMap;
+use alloc::{vec, vec::Vec};
+
+use super::{
+    evaluator::{eq_char, eval_pike, in_class, in_property, in_range, is_any, EvalError},
+    Flags, Instruction,
+};
+
+/// 状態数の上限のデフォルト値
+const MAX_STATES: usize = 1000;
+
+/// NFAのスレッド。`StringMatch`の途中にいる場合は、何文字目まで進んだかを持つ
+pub(super) type Thread = (usize, usize);
+
+/// DFAの状態。NFAのスレッドの集合に対応する
+#[derive(Debug)]
+struct State {
+    threads: Vec<Thread>,
+    /// `match`に到達したスレッドがあるか
+    is_match: bool,
+    /// 最後に使ったときの時刻。上限に達したときは、最も古いものから捨てる
+    last_used: u64,
+}
+
+/// `eval_dfa`で作ったDFAの状態と遷移を覚えておくキャッシュ
+///
+/// 同じプログラムで何度も評価する場合に、前回までに作った状態を使い回せる。
+/// 違うプログラムやフラグで評価した場合は、作り直す
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_cache, DfaCache};
+/// let mut cache = DfaCache::default();
+/// assert!(do_matching_with_cache("(ab|cd)+", "abcdab", &mut cache).unwrap());
+/// assert!(!do_matching_with_cache("(ab|cd)+", "ba", &mut cache).unwrap());
+/// ```
+#[derive(Debug)]
+pub struct DfaCache {
+    /// キャッシュを作った`Regex`の番号。`Regex`を通さずに評価した場合は`None`
+    owner: Option<usize>,
+    /// キャッシュを作ったプログラムとフラグ。`owner`がある場合はプログラムを比べないので持たない
+    program: Vec<Instruction>,
+    flags: Flags,
+    /// `program`の各行の`StringMatch`の文字列を、文字の列にしたもの。それ以外の行は空
+    literals: Vec<Vec<char>>,
+    max_states: usize,
+    /// `false`のときは、上限に達しても古い状態を捨てずにエラーにする
+    evict: bool,
+    states: HashMap<usize, State>,
+    ids: HashMap<(Vec<Thread>, bool), usize>,
+    transitions: HashMap<(usize, char), usize>,
+    next_id: usize,
+    clock: u64,
+}
+
+impl Default for DfaCache {
+    fn default() -> Self {
+        DfaCache::new(MAX_STATES)
+    }
+}
+
+impl DfaCache {
+    /// 状態を`max_states`個まで覚えておくキャッシュを作る
+    ///
+    /// 評価中の状態を捨てないよう、2個より少なくはしない
+    pub fn new(max_states: usize) -> Self {
+        DfaCache {
+            owner: None,
+            program: Vec::new(),
+            flags: Flags::default(),
+            literals: Vec::new(),
+            max_states: max_states.max(2),
+            evict: true,
+            states: HashMap::new(),
+            ids: HashMap::new(),
+            transitions: HashMap::new(),
+            next_id: 0,
+            clock: 0,
+        }
+    }
+
+    /// `false`のとき、状態が上限に達したら古い状態を捨てずに`EvalError::DfaCacheFull`を返す
+    ///
+    /// デフォルトでは`true`で、最も長く使われていない状態から捨てる。
+    /// エラーになった場合は、Pike VMなどの別の方式で評価し直せる
+    ///
+    /// ```
+    /// use regex_machine::engine::{do_matching_with_cache, DfaCache};
+    /// let mut cache = DfaCache::new(2).evict(false);
+    /// assert!(do_matching_with_cache("(a|b)*abb", "ababb", &mut cache).is_err());
+    /// ```
+    pub fn evict(mut self, evict: bool) -> Self {
+        self.evict = evict;
+        self
+    }
+
+    /// 評価するプログラムが前回と違う場合は、キャッシュを空にする
+    ///
+    /// `owner`がある場合は、命令列を比べずに`Regex`の番号だけで同じプログラムか判断する
+    fn reset_for(&mut self, owner: Option<usize>, insts: &[Instruction], flags: &Flags) {
+        let is_same = match owner {
+            Some(_) => self.owner == owner,
+            None => self.owner.is_none() && self.program == insts && self.flags == *flags,
+        };
+        if is_same {
+            return;
+        }
+        self.owner = owner;
+        self.program = match owner {
+            Some(_) => Vec::new(),
+            None => insts.to_vec(),
+        };
+        self.flags = *flags;
+        // 状態を作るたびに文字列を数え直さないよう、先に文字の列にしておく
+        self.literals = insts
+            .iter()
+            .map(|inst| match inst {
+                Instruction::StringMatch(s) => s.chars().collect(),
+                _ => Vec::new(),
+            })
+            .collect();
+        self.states.clear();
+        self.ids.clear();
+        self.transitions.clear();
+    }
+
+    /// `threads`に対応する状態を返す。まだない場合は作る
+    fn state(&mut self, threads: Vec<Thread>, is_match: bool) -> Result<usize, EvalError> {
+        let key = (threads, is_match);
+        if let Some(id) = self.ids.get(&key).copied() {
+            self.touch(id);
+            return Ok(id);
+        }
+
+        if self.states.len() >= self.max_states {
+            if !self.evict {
+                return Err(EvalError::DfaCacheFull);
+            }
+            self.evict_oldest();
+        }
+        self.clock += 1;
+        let id = self.next_id;
+        self.next_id += 1;
+        let (threads, is_match) = key;
+        self.ids.insert((threads.clone(), is_match), id);
+        self.states.insert(
+            id,
+            State {
+                threads,
+                is_match,
+                last_used: self.clock,
+            },
+        );
+        Ok(id)
+    }
+
+    /// `id`の状態を使ったことを記録する
+    fn touch(&mut self, id: usize) {
+        self.clock += 1;
+        if let Some(state) = self.states.get_mut(&id) {
+            state.last_used = self.clock;
+        }
+    }
+
+    /// 最も長く使われていない状態と、それに関わる遷移を捨てる
+    fn evict_oldest(&mut self) {
+        let Some(oldest) = self
+            .states
+            .iter()
+            .min_by_key(|(_, state)| state.last_used)
+            .map(|(id, _)| *id)
+        else {
+            return;
+        };
+        if let Some(state) = self.states.remove(&oldest) {
+            self.ids.remove(&(state.threads, state.is_match));
+        }
+        self.transitions
+            .retain(|(from, _), to| *from != oldest && *to != oldest);
+    }
+}
+
+/// DFAで扱えない命令を含むか
+///
+/// 位置によって結果が変わる命令や、後方参照を含む場合はDFAにできない
+fn is_supported(insts: &[Instruction]) -> bool {
+    insts.iter().all(|inst| {
+        matches!(
+            inst,
+            Instruction::Char(_)
+                | Instruction::StringMatch(_)
+                | Instruction::CharClass(_)
+                | Instruction::RangeChar(..)
+                | Instruction::Property(_)
+                | Instruction::Any
+                | Instruction::Match
+                | Instruction::Jump(_)
+                | Instruction::Split(..)
+                | Instruction::Save(_)
+        )
+    })
+}
+
+/// `pc`から入力を消費せずに到達できるスレッドを`threads`に集める。`match`に到達した場合は`true`を返す
+///
+/// `^`や`\b`などの位置の条件は`holds`で判定する。`visited`にはたどった行を記録するので、
+/// 同じ位置で何度も呼ぶ場合は使い回すと、既に集めたスレッドをたどり直さずに済む
+pub(super) fn closure(
+    insts: &[Instruction],
+    pc: usize,
+    visited: &mut [bool],
+    threads: &mut Vec<Thread>,
+    holds: impl Fn(&Instruction) -> bool,
+) -> Result<bool, EvalError> {
+    let mut stack = vec![pc];
+    let mut is_match = false;
+
+    while let Some(pc) = stack.pop() {
+        let Some(inst) = insts.get(pc) else {
+            return Err(EvalError::InvalidPC);
+        };
+        if core::mem::replace(&mut visited[pc], true) {
+            continue;
+        }
+        match inst {
+            Instruction::Match => is_match = true,
+            Instruction::Jump(addr) => stack.push(*addr),
+            Instruction::Split(addr1, addr2) => {
+                stack.push(*addr2);
+                stack.push(*addr1);
+            }
+            Instruction::Save(_) => stack.push(pc + 1),
+            Instruction::Start
+            | Instruction::End
+            | Instruction::LineStart
+            | Instruction::LineEnd
+            | Instruction::WordBoundary => {
+                if holds(inst) {
+                    stack.push(pc + 1);
+                }
+            }
+            _ => threads.push((pc, 0)),
+        }
+    }
+
+    Ok(is_match)
+}
+
+/// DFAにできるプログラムは位置の条件を含まないので、`closure`に渡す判定は使われない
+fn no_condition(_: &Instruction) -> bool {
+    false
+}
+
+/// `threads`が入力の`c`を1文字消費した後のスレッドを返す
+///
+/// `literals`は`DfaCache::literals`で、`StringMatch`の文字列を文字の列にしたもの
+fn step(
+    insts: &[Instruction],
+    literals: &[Vec<char>],
+    threads: &[Thread],
+    c: &char,
+    flags: &Flags,
+) -> Result<(Vec<Thread>, bool), EvalError> {
+    let mut next = Vec::new();
+    let mut is_match = false;
+    let mut visited = vec![false; insts.len()];
+
+    for &(pc, offset) in threads {
+        let is_next = match &insts[pc] {
+            Instruction::Char(expected) => eq_char(expected, c, flags),
+            Instruction::StringMatch(_) => {
+                let s = literals.get(pc).map(Vec::as_slice).unwrap_or_default();
+                let Some(expected) = s.get(offset) else {
+                    return Err(EvalError::InvalidContext);
+                };
+                if !eq_char(expected, c, flags) {
+                    continue;
+                }
+                // 文字列の途中なら、次の文字へ進むだけ
+                if offset + 1 < s.len() {
+                    next.push((pc, offset + 1));
+                    continue;
+                }
+                true
+            }
+            Instruction::CharClass(class) => in_class(class, c, flags),
+            Instruction::RangeChar(lo, hi) => in_range(lo, hi, c, flags),
+            Instruction::Property(prop) => in_property(prop, c, flags),
+            Instruction::Any => is_any(c, flags),
+            _ => return Err(EvalError::InvalidContext),
+        };
+        if is_next {
+            is_match |= closure(insts, pc + 1, &mut visited, &mut next, no_condition)?;
+        }
+    }
+
+    next.sort_unstable();
+    next.dedup();
+    Ok((next, is_match))
+}
+
+/// NFAのスレッドの集合を状態とするDFAを、入力を読みながら作って評価する
+///
+/// 作った状態と遷移は`cache`に残るので、同じプログラムで何度も評価する場合は速くなる。
+/// DFAにできない命令を含む場合は、Pike VMで評価する
+pub fn eval_dfa(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    cache: &mut DfaCache,
+) -> Result<bool, EvalError> {
+    if !is_supported(insts) {
+        return eval_pike(insts, line, flags);
+    }
+    cache.reset_for(None, insts, flags);
+    run(insts, line, flags, cache, false)
+}
+
+/// `Regex`のプログラムで、入力のどこかにマッチする部分があるかをDFAで検証する
+///
+/// `owner`は`Regex`の番号で、キャッシュが同じプログラムのものかは命令列を比べずにこれで判断する。
+/// DFAにできない命令を含む場合は`None`を返す
+pub(crate) fn search_dfa(
+    owner: usize,
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    cache: &mut DfaCache,
+) -> Option<Result<bool, EvalError>> {
+    if !is_supported(insts) {
+        return None;
+    }
+    cache.reset_for(Some(owner), insts, flags);
+    Some(run(insts, line, flags, cache, true))
+}
+
+/// `cache`の状態を使いながら入力を読む
+///
+/// `search`が`true`のときは、各位置から始まるスレッドも状態に加えて、入力の途中から始まるマッチも探す
+fn run(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    cache: &mut DfaCache,
+    search: bool,
+) -> Result<bool, EvalError> {
+    let mut threads = Vec::new();
+    let is_match = closure(
+        insts,
+        0,
+        &mut vec![false; insts.len()],
+        &mut threads,
+        no_condition,
+    )?;
+    threads.sort_unstable();
+    threads.dedup();
+    let mut current = cache.state(threads, is_match)?;
+
+    for c in line {
+        let state = &cache.states[&current];
+        if state.is_match {
+            return Ok(true);
+        }
+        if state.threads.is_empty() {
+            return Ok(false);
+        }
+
+        current = match cache.transitions.get(&(current, *c)) {
+            Some(next) if cache.states.contains_key(next) => {
+                let next = *next;
+                cache.touch(next);
+                next
+            }
+            _ => {
+                let (mut threads, mut is_match) = step(
+                    insts,
+                    &cache.literals,
+                    &cache.states[&current].threads,
+                    c,
+                    flags,
+                )?;
+                if search {
+                    let mut visited = vec![false; insts.len()];
+                    is_match |= closure(insts, 0, &mut visited, &mut threads, no_condition)?;
+                    threads.sort_unstable();
+                    threads.dedup();
+                }
+                let next = cache.state(threads, is_match)?;
+                cache.transitions.insert((current, *c), next);
+                next
+            }
+        };
+    }
+
+    Ok(cache.states[&current].is_match)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::testing::{assert_same_as_pike, to_insts, PATTERNS};
+    use alloc::format;
+
+    use super::*;
+
+    fn to_chars(s: &str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn same_as_nfa() {
+        let mut cache = DfaCache::default();
+        assert_same_as_pike(PATTERNS, |insts, line, flags| {
+            let line = to_chars(line);
+            let expected = eval_dfa(insts, &line, flags, &mut cache);
+            // 2回目はキャッシュした遷移を使う
+            assert_eq!(eval_dfa(insts, &line, flags, &mut cache), expected);
+            expected
+        });
+    }
+
+    #[test]
+    fn large_input() {
+        let insts = to_insts("(ab|cd)*e");
+        let mut cache = DfaCache::default();
+
+        let mut line = "abcd".repeat(50000);
+        line.push('e');
+        let line = to_chars(&line);
+        assert!(eval_dfa(&insts, &line, &Flags::default(), &mut cache).unwrap());
+        // 状態は入力の長さによらず、少ないまま
+        assert!(cache.states.len() < 10);
+
+        let mut line = "abcd".repeat(50000);
+        line.push('x');
+        let line = to_chars(&line);
+        let expected = eval_pike(&insts, &line, &Flags::default()).unwrap();
+        assert!(!expected);
+        assert_eq!(
+            eval_dfa(&insts, &line, &Flags::default(), &mut cache).unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn max_states() {
+        // 状態の上限に達しても、古い状態を捨てながら同じ結果を返す
+        let insts = to_insts("(a|b)*abb");
+        let mut cache = DfaCache::new(2);
+        for line in ["ababb", "abab", "bbbabb", "aaaaabb"] {
+            let line = to_chars(line);
+            let expected = eval_pike(&insts, &line, &Flags::default()).unwrap();
+            let res = eval_dfa(&insts, &line, &Flags::default(), &mut cache).unwrap();
+            assert_eq!(res, expected);
+            assert!(cache.states.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn evict_while_scanning() {
+        // 1回の評価の途中で状態を捨てても、Pike VMと同じ結果になる
+        let insts = to_insts("(a|b)*a(a|b)(a|b)abba");
+        let mut cache = DfaCache::new(2);
+        let mut seed = 1u32;
+        for _ in 0..20 {
+            let line = (0..300)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                    if seed >> 16 & 1 == 0 {
+                        'a'
+                    } else {
+                        'b'
+                    }
+                })
+                .collect::<Vec<_>>();
+            let created = cache.next_id;
+            let expected = eval_pike(&insts, &line, &Flags::default()).unwrap();
+            let res = eval_dfa(&insts, &line, &Flags::default(), &mut cache).unwrap();
+            assert_eq!(res, expected);
+            assert!(cache.next_id - created > 2);
+            assert!(cache.states.len() <= 2);
+        }
+    }
+
+    #[test]
+    fn long_literal() {
+        let literal = "abc".repeat(200);
+        let insts = to_insts(&format!("x*{literal}y"));
+        assert!(insts
+            .iter()
+            .any(|inst| matches!(inst, Instruction::StringMatch(s) if s.len() >= 600)));
+        let mut cache = DfaCache::default();
+        for line in [
+            format!("xx{literal}y"),
+            format!("x{literal}"),
+            literal.clone(),
+        ] {
+            let line = to_chars(&line);
+            let expected = eval_pike(&insts, &line, &Flags::default()).unwrap();
+            assert_eq!(
+                eval_dfa(&insts, &line, &Flags::default(), &mut cache).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn cache_full() {
+        let insts = to_insts("(a|b)*abb");
+        let mut cache = DfaCache::new(2).evict(false);
+        assert_eq!(
+            eval_dfa(&insts, &to_chars("ababb"), &Flags::default(), &mut cache),
+            Err(EvalError::DfaCacheFull)
+        );
+
+        // 上限に達しなければ、普通に評価できる
+        let mut cache = DfaCache::new(100).evict(false);
+        assert_eq!(
+            eval_dfa(&insts, &to_chars("ababb"), &Flags::default(), &mut cache),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn reset_and_fallback() {
+        let mut cache = DfaCache::default();
+        let line = to_chars("ab");
+        assert!(eval_dfa(&to_insts("ab"), &line, &Flags::default(), &mut cache).unwrap());
+        // 違うプログラムではキャッシュを作り直す
+        assert!(!eval_dfa(&to_insts("b"), &line, &Flags::default(), &mut cache).unwrap());
+        assert_eq!(cache.program, to_insts("b"));
+
+        // 位置によって結果が変わる命令を含む場合は、Pike VMで評価する
+        let insts = to_insts(r"a\b");
+        assert!(!is_supported(&insts));
+        assert!(!eval_dfa(&insts, &line, &Flags::default(), &mut cache).unwrap());
+        assert!(eval_dfa(&insts, &to_chars("a b"), &Flags::default(), &mut cache).unwrap());
+    }
+}
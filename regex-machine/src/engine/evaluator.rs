@@ -1,8 +1,23 @@
-use std::collections::VecDeque;
+use crate::helper::HashSet;
+use alloc::collections::VecDeque;
+use alloc::{vec, vec::Vec};
+#[cfg(feature = "std")]
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+    time::Instant,
+};
+
+/// 深さ優先探索で、期限を過ぎていないか、打ち切られていないかを確認する間隔の命令数
+#[cfg(feature = "std")]
+const DEADLINE_INTERVAL: usize = 1024;
 
 use crate::helper::safe_add;
 
-use super::Instruction;
+use super::{class::is_word_char, CharClass, Flags, Instruction, Property};
 
 /// 評価時のエラー型
 #[derive(Debug, PartialEq)]
@@ -15,153 +30,843 @@ pub enum EvalError {
     InvalidPC,
     /// 不正なコンテキスト
     InvalidContext,
+    /// 後方参照は深さ優先探索でしか評価できない
+    BackRefNotSupported,
+    /// 深さ優先探索で実行した命令の数が上限を超えた
+    StepLimitExceeded,
+    /// 深さ優先探索の評価が期限までに終わらなかった
+    Timeout,
+    /// 並列に評価している他の分岐がマッチしたので、評価を打ち切った
+    Cancelled,
+    /// 深さ優先探索で覚えておく分岐の数が上限を超えた
+    StackLimitExceeded,
+    /// DFAの状態が上限に達し、古い状態を捨てられなかった
+    DfaCacheFull,
+    /// バイト列に対しては、ASCII以外の文字を含むパターンや先読みなどを評価できない
+    BytesNotSupported,
+    /// イテレータからの入力に対しては、先読みなどの入力を戻って読む命令を評価できない
+    StreamNotSupported,
 }
 
-impl std::fmt::Display for EvalError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "EvaluationError: {self:?}")
+impl core::fmt::Display for EvalError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            EvalError::PCOverFlow => {
+                write!(f, "EvaluationError: program counter overflow")
+            }
+            EvalError::SPOverFlow => {
+                write!(f, "EvaluationError: string pointer overflow")
+            }
+            EvalError::InvalidPC => {
+                write!(f, "EvaluationError: invalid program counter")
+            }
+            EvalError::InvalidContext => {
+                write!(f, "EvaluationError: invalid context")
+            }
+            EvalError::BackRefNotSupported => {
+                write!(
+                    f,
+                    "EvaluationError: back references require depth-first evaluation"
+                )
+            }
+            EvalError::StepLimitExceeded => {
+                write!(f, "EvaluationError: step limit exceeded")
+            }
+            EvalError::Timeout => {
+                write!(f, "EvaluationError: deadline exceeded")
+            }
+            EvalError::Cancelled => {
+                write!(f, "EvaluationError: evaluation cancelled")
+            }
+            EvalError::StackLimitExceeded => {
+                write!(f, "EvaluationError: stack limit exceeded")
+            }
+            EvalError::DfaCacheFull => {
+                write!(f, "EvaluationError: DFA cache is full")
+            }
+            EvalError::BytesNotSupported => {
+                write!(
+                    f,
+                    "EvaluationError: pattern cannot be evaluated against bytes"
+                )
+            }
+            EvalError::StreamNotSupported => {
+                write!(
+                    f,
+                    "EvaluationError: pattern cannot be evaluated against a stream"
+                )
+            }
+        }
     }
 }
 
-impl std::error::Error for EvalError {}
+impl core::error::Error for EvalError {}
 
-pub fn eval_depth(
-    insts: &[Instruction],
-    line: &[char],
-    mut pc: usize,
-    mut sp: usize,
-) -> Result<bool, EvalError> {
-    loop {
-        let Some(next) = insts.get(pc) else {
-            return Err(EvalError::InvalidPC);
+/// 評価の際の上限などの設定
+///
+/// ```
+/// use regex_machine::engine::EvalConfig;
+/// let config = EvalConfig::default().step_limit(100000);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EvalConfig {
+    step_limit: Option<usize>,
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    stack_limit: Option<usize>,
+    max_threads: Option<usize>,
+    anchored: bool,
+    longest: bool,
+    case_insensitive: bool,
+    dot_all: bool,
+}
+
+impl EvalConfig {
+    /// 深さ優先探索で実行する命令の数の上限。悪意のある入力で、評価が終わらなくなるのを防ぐ
+    ///
+    /// デフォルトでは上限はない
+    pub fn step_limit(mut self, step_limit: usize) -> Self {
+        self.step_limit = Some(step_limit);
+        self
+    }
+
+    /// 深さ優先探索の評価を打ち切る時刻。命令によって掛かる時間が違う場合でも、評価に掛かる時間を制限できる
+    ///
+    /// 時刻は一定の命令数ごとに確認するので、少し過ぎてから打ち切ることがある。デフォルトでは期限はない
+    ///
+    /// ```
+    /// use std::time::{Duration, Instant};
+    /// use regex_machine::engine::EvalConfig;
+    /// let config = EvalConfig::default().deadline(Instant::now() + Duration::from_millis(100));
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// 深さ優先探索で、戻って試すために覚えておく分岐の数の上限。長い入力で繰り返すパターンで、使うメモリを制限できる
+    ///
+    /// 分岐はヒープに積むので、上限がなくてもスタックオーバーフローにはならない。`Split`を通るたびに1つ増える。
+    /// デフォルトでは上限はない
+    pub fn stack_limit(mut self, stack_limit: usize) -> Self {
+        self.stack_limit = Some(stack_limit);
+        self
+    }
+
+    /// `eval_parallel`で使うスレッドの数の上限。これより多い分岐は、空いたスレッドで順に評価する
+    ///
+    /// デフォルトでは、このマシンで並列に実行できるスレッドの数
+    pub fn max_threads(mut self, max_threads: usize) -> Self {
+        self.max_threads = Some(max_threads);
+        self
+    }
+
+    /// `true`のとき、入力の中を探さずに先頭からのマッチだけを試す
+    ///
+    /// パターンの先頭に`^`を付けたのと同じ結果になるが、他の位置を試さない分速い。
+    /// デフォルトでは`false`で、`match_span`などは入力の各位置から順に試す
+    pub fn anchored(mut self, anchored: bool) -> Self {
+        self.anchored = anchored;
+        self
+    }
+
+    /// `true`のとき、同じ位置から始まるマッチのうち最も長いものを返す(POSIXの最左最長一致)
+    ///
+    /// デフォルトでは`false`で、優先される分岐のマッチを返す。`a|ab`は`ab`に対して`a`にマッチする
+    pub fn longest(mut self, longest: bool) -> Self {
+        self.longest = longest;
+        self
+    }
+
+    /// `true`のとき、パターンに`(?i)`がなくても大文字と小文字を区別しない
+    ///
+    /// デフォルトでは`false`で、パターンのフラグに従う
+    pub fn case_insensitive(mut self, case_insensitive: bool) -> Self {
+        self.case_insensitive = case_insensitive;
+        self
+    }
+
+    /// `true`のとき、パターンに`(?s)`がなくても`.`が改行にマッチする
+    ///
+    /// デフォルトでは`false`で、パターンのフラグに従う
+    pub fn dot_all(mut self, dot_all: bool) -> Self {
+        self.dot_all = dot_all;
+        self
+    }
+
+    /// 先頭からのマッチだけを試すか
+    pub fn is_anchored(&self) -> bool {
+        self.anchored
+    }
+
+    /// 同じ位置から始まるマッチのうち、最も長いものを返すか
+    pub fn is_longest(&self) -> bool {
+        self.longest
+    }
+
+    /// パターンのフラグ`flags`に、この設定で指定したものを加えたフラグを返す
+    pub fn apply(&self, flags: &Flags) -> Flags {
+        Flags {
+            case_insensitive: flags.case_insensitive || self.case_insensitive,
+            dot_all: flags.dot_all || self.dot_all,
+            ..*flags
+        }
+    }
+}
+
+/// パターン中の文字`c`と入力中の文字`sp_c`が等しいか
+///
+/// `(?i)`のときは、ASCIIの文字同士はそのまま比べ、それ以外は小文字にして比べる
+pub fn eq_char(c: &char, sp_c: &char, flags: &Flags) -> bool {
+    if flags.case_insensitive {
+        if c.is_ascii() && sp_c.is_ascii() {
+            c.eq_ignore_ascii_case(sp_c)
+        } else {
+            c.to_lowercase().eq(sp_c.to_lowercase())
+        }
+    } else {
+        c == sp_c
+    }
+}
+
+/// 入力の`sp`から`s`が続いていれば、その次の位置を返す
+fn match_str(s: &str, line: &[char], sp: usize, flags: &Flags) -> Option<usize> {
+    let mut next = sp;
+    for c in s.chars() {
+        if !eq_char(&c, line.get(next)?, flags) {
+            return None;
+        }
+        next += 1;
+    }
+    Some(next)
+}
+
+/// 入力中の文字`sp_c`が`contains`を満たすか。`(?i)`のときは大文字と小文字も試す
+fn matches_folded(sp_c: &char, flags: &Flags, contains: impl Fn(char) -> bool) -> bool {
+    if flags.case_insensitive {
+        let mut lower = sp_c.to_lowercase();
+        let mut upper = sp_c.to_uppercase();
+        contains(*sp_c) || lower.any(&contains) || upper.any(&contains)
+    } else {
+        contains(*sp_c)
+    }
+}
+
+/// 入力中の文字`sp_c`が文字クラスに含まれるか
+pub fn in_class(class: &CharClass, sp_c: &char, flags: &Flags) -> bool {
+    matches_folded(sp_c, flags, |c| class.contains(c))
+}
+
+/// 入力中の文字`sp_c`がUnicodeの性質`prop`を持つか
+pub fn in_property(prop: &Property, sp_c: &char, flags: &Flags) -> bool {
+    matches_folded(sp_c, flags, |c| prop.contains(c))
+}
+
+/// 入力中の文字`sp_c`が`lo`から`hi`の範囲に含まれるか
+pub fn in_range(lo: &char, hi: &char, sp_c: &char, flags: &Flags) -> bool {
+    matches_folded(sp_c, flags, |c| *lo <= c && c <= *hi)
+}
+
+/// 入力中の文字`sp_c`が`.`にマッチするか。`(?s)`でない場合は改行にマッチしない
+pub fn is_any(sp_c: &char, flags: &Flags) -> bool {
+    flags.dot_all || *sp_c != '\n'
+}
+
+/// `sp`が行頭か。入力の先頭か、改行の直後の場合に行頭とみなす
+fn is_line_start(line: &[char], sp: usize) -> bool {
+    sp == 0 || line.get(sp - 1) == Some(&'\n')
+}
+
+/// `sp`が行末か。入力の末尾か、改行の直前の場合に行末とみなす
+fn is_line_end(line: &[char], sp: usize) -> bool {
+    sp == line.len() || line.get(sp) == Some(&'\n')
+}
+
+/// `sp`が単語境界上にあるか。`sp`の前後のうち、片方だけが単語を構成する文字の場合に境界とみなす
+fn is_word_boundary(line: &[char], sp: usize) -> bool {
+    let before = sp
+        .checked_sub(1)
+        .and_then(|i| line.get(i))
+        .is_some_and(is_word_char);
+    let after = line.get(sp).is_some_and(is_word_char);
+    before != after
+}
+
+/// 深さ優先探索で、失敗したときに戻ってくる`Split`
+struct Branch {
+    /// `Split`の`(pc, sp)`
+    split: (usize, usize),
+    /// まだ試していない2つ目の分岐の行き先。2つ目の分岐を評価している間は`None`
+    next: Option<usize>,
+    /// `Split`に来たときの記録した位置。2つ目の分岐を試す前に元に戻す
+    captures: Vec<Option<usize>>,
+    /// `Split`に来たときの`cuts`
+    cuts: usize,
+}
+
+/// 深さ優先探索の評価器
+struct DepthEvaluator<'a> {
+    insts: &'a [Instruction],
+    line: &'a [char],
+    flags: &'a Flags,
+    /// `Save`で記録した位置。`n`番目のグループは`2n`と`2n+1`に入る
+    captures: Vec<Option<usize>>,
+    /// 評価中の`Split`の`(pc, sp)`。空文字列にマッチする繰り返しで、同じ位置に戻ってきたことを検出する
+    splits: HashSet<(usize, usize)>,
+    /// 実行した命令の数
+    steps: usize,
+    /// `steps`の上限
+    step_limit: Option<usize>,
+    /// 評価を打ち切る時刻
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+    /// `true`になったら評価を打ち切る。`eval_parallel`で、他の分岐がマッチしたときに残りの分岐を止める
+    #[cfg(feature = "std")]
+    cancel: Option<&'a AtomicBool>,
+    /// 評価中の`Split`の数の上限
+    stack_limit: Option<usize>,
+    /// マッチしないことが分かった`Split`の`(pc, sp)`。同じ状態をもう一度評価しないようにする
+    dead: HashSet<(usize, usize)>,
+    /// 後方参照がある場合は、キャプチャした位置によって結果が変わるので`dead`を使わない
+    memoize: bool,
+    /// 空文字列にマッチする繰り返しを打ち切った回数
+    cuts: usize,
+    /// `true`のときは最初のマッチで止めずに、すべての分岐を評価する
+    longest: bool,
+    /// `longest`のときに見つかった、最も長いマッチの終わり
+    best: Option<usize>,
+    /// 評価中の先読み・後読みやアトミックグループの深さ。中身の`match`ではマッチを止める
+    sub_depth: usize,
+}
+
+impl<'a> DepthEvaluator<'a> {
+    fn new(insts: &'a [Instruction], line: &'a [char], flags: &'a Flags) -> Self {
+        DepthEvaluator {
+            insts,
+            line,
+            flags,
+            captures: Vec::new(),
+            splits: HashSet::new(),
+            steps: 0,
+            step_limit: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+            #[cfg(feature = "std")]
+            cancel: None,
+            stack_limit: None,
+            dead: HashSet::new(),
+            memoize: !insts
+                .iter()
+                .any(|inst| matches!(inst, Instruction::BackRef(_))),
+            cuts: 0,
+            longest: false,
+            best: None,
+            sub_depth: 0,
+        }
+    }
+
+    /// `config`の上限を設定した評価器を作る
+    fn with_config(
+        insts: &'a [Instruction],
+        line: &'a [char],
+        flags: &'a Flags,
+        config: &EvalConfig,
+    ) -> Self {
+        let mut evaluator = DepthEvaluator::new(insts, line, flags);
+        evaluator.step_limit = config.step_limit;
+        #[cfg(feature = "std")]
+        {
+            evaluator.deadline = config.deadline;
+        }
+        evaluator.stack_limit = config.stack_limit;
+        evaluator
+    }
+
+    /// `pc`にある`Atomic`や`LookAhead`の中身を`sp`から評価し、マッチした場合はマッチし終わった位置を返す
+    fn eval_sub(&mut self, mut pc: usize, sp: usize) -> Result<Option<usize>, EvalError> {
+        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+        self.sub_depth += 1;
+        let end = self.eval(pc, sp);
+        self.sub_depth -= 1;
+        end
+    }
+
+    /// `pc`にある`LookBehind`の中身が、`sp`の`width`文字前からマッチするか
+    fn eval_look_behind(&mut self, pc: usize, sp: usize, width: usize) -> Result<bool, EvalError> {
+        // 入力の先頭より前から始めることはできない
+        let Some(start) = sp.checked_sub(width) else {
+            return Ok(false);
         };
-        match next {
-            Instruction::Char(c) => {
-                let Some(sp_c) = line.get(sp) else {
-                    return Ok(false);
-                };
+        Ok(self.eval_sub(pc, start)? == Some(sp))
+    }
 
-                if c == sp_c {
-                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                    safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-                } else {
-                    return Ok(false);
+    /// 否定の先読み・後読みを評価する。中身で記録された位置は残さない
+    fn eval_negative(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<bool, EvalError>,
+    ) -> Result<bool, EvalError> {
+        let captures = self.captures.clone();
+        let is_match = f(self)?;
+        self.captures = captures;
+        Ok(!is_match)
+    }
+
+    /// `n`番目のグループがキャプチャした範囲
+    fn capture(&self, n: usize) -> Option<(usize, usize)> {
+        let start = self.captures.get(n * 2).copied().flatten()?;
+        let end = self.captures.get(n * 2 + 1).copied().flatten()?;
+        Some((start, end))
+    }
+
+    /// `n`番目のグループがキャプチャした文字列が`sp`から続いていれば、その次の位置を返す
+    fn back_ref(&self, n: usize, sp: usize) -> Option<usize> {
+        let (start, end) = self.capture(n)?;
+        let captured = self.line.get(start..end)?;
+        let next = sp.checked_add(captured.len())?;
+        let target = self.line.get(sp..next)?;
+
+        let is_match = captured
+            .iter()
+            .zip(target)
+            .all(|(c, sp_c)| eq_char(c, sp_c, self.flags));
+        is_match.then_some(next)
+    }
+
+    /// 深さ優先探索で評価し、マッチした場合はマッチし終わった位置を返す
+    ///
+    /// 分岐は`branches`に積んで、失敗したら最後に積んだ分岐に戻る。入力が長くても再帰はしない
+    fn eval(&mut self, mut pc: usize, mut sp: usize) -> Result<Option<usize>, EvalError> {
+        let mut branches = Vec::new();
+        loop {
+            if let Some(end) = self.eval_thread(pc, sp, &mut branches)? {
+                for branch in branches {
+                    self.splits.remove(&branch.split);
                 }
+                return Ok(Some(end));
             }
-            Instruction::Any => {
-                if line.get(sp).is_none() {
-                    return Ok(false);
-                };
+            let Some(next) = self.backtrack(&mut branches) else {
+                return Ok(None);
+            };
+            (pc, sp) = next;
+        }
+    }
 
-                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+    /// 最後に積んだ分岐のうち、まだ試していないものの`(pc, sp)`を返す。すべて試した分岐は取り除く
+    fn backtrack(&mut self, branches: &mut Vec<Branch>) -> Option<(usize, usize)> {
+        while let Some(branch) = branches.last_mut() {
+            // 1つ目の分岐が失敗したときは、その分岐で記録した位置を元に戻す
+            if let Some(addr2) = branch.next.take() {
+                self.captures = core::mem::take(&mut branch.captures);
+                return Some((addr2, branch.split.1));
             }
-            Instruction::Start => {
-                if sp != 0 {
-                    return Ok(false);
-                }
-                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+            let Branch { split, cuts, .. } = branches.pop()?;
+            self.splits.remove(&split);
+
+            // 失敗だけを記録する。打ち切った分岐がある場合は、どこから来たかで結果が変わりうるので記録しない
+            if self.memoize && cuts == self.cuts {
+                self.dead.insert(split);
             }
-            Instruction::End => {
-                if sp != line.len() {
-                    return Ok(false);
-                }
-                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+        }
+        None
+    }
+
+    /// `Split`に来るたびに`branches`に積みながら1本の道筋を評価する。失敗した場合は`None`を返す
+    fn eval_thread(
+        &mut self,
+        mut pc: usize,
+        mut sp: usize,
+        branches: &mut Vec<Branch>,
+    ) -> Result<Option<usize>, EvalError> {
+        let line = self.line;
+        let flags = self.flags;
+        // 最後の`Split`から後に、戻る向きにジャンプした`(pc, sp)`。`Split`を挟まないので、同じ状態に戻ってきたら抜けられない
+        let mut loops = Vec::new();
+        loop {
+            let Some(next) = self.insts.get(pc) else {
+                return Err(EvalError::InvalidPC);
+            };
+            self.steps += 1;
+            if self.step_limit.is_some_and(|limit| self.steps > limit) {
+                return Err(EvalError::StepLimitExceeded);
             }
-            Instruction::Match => {
-                return Ok(true);
+            #[cfg(feature = "std")]
+            if self.steps.is_multiple_of(DEADLINE_INTERVAL)
+                && self
+                    .deadline
+                    .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                return Err(EvalError::Timeout);
             }
-            Instruction::Jump(addr) => {
-                pc = *addr;
+            #[cfg(feature = "std")]
+            if self.steps.is_multiple_of(DEADLINE_INTERVAL)
+                && self
+                    .cancel
+                    .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+            {
+                return Err(EvalError::Cancelled);
             }
-            Instruction::Split(addr1, addr2) => {
-                if eval_depth(insts, line, *addr1, sp)? || eval_depth(insts, line, *addr2, sp)? {
-                    return Ok(true);
-                } else {
-                    return Ok(false);
+            match next {
+                Instruction::Char(c) => {
+                    let Some(sp_c) = line.get(sp) else {
+                        return Ok(None);
+                    };
+
+                    if eq_char(c, sp_c, flags) {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                    } else {
+                        return Ok(None);
+                    }
                 }
-            }
-        }
-    }
-}
+                Instruction::StringMatch(s) => {
+                    let Some(next) = match_str(s, line, sp, flags) else {
+                        return Ok(None);
+                    };
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    sp = next;
+                }
+                Instruction::CharClass(class) => {
+                    let Some(sp_c) = line.get(sp) else {
+                        return Ok(None);
+                    };
 
-fn eval_width(insts: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
-    let mut queue = VecDeque::<(usize, usize)>::new();
-    let mut pc = 0;
-    let mut sp = 0;
-    loop {
-        let Some(next) = insts.get(pc) else {
-            return Err(EvalError::InvalidPC);
-        };
-        dbg!(next, pc, sp);
-        match next {
-            Instruction::Char(c) => {
-                if let Some(sp_c) = line.get(sp) {
-                    if sp_c == c {
+                    if in_class(class, sp_c, flags) {
                         safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
                         safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
                     } else {
-                        // 分岐がもうないとき
-                        if queue.is_empty() {
-                            return Ok(false);
-                        } else {
-                            let Some(branch) = queue.pop_front() else {
-                                return Err(EvalError::InvalidContext);
-                            };
-                            pc = branch.0;
-                            sp = branch.1;
-                        }
+                        return Ok(None);
                     }
-                } else if queue.is_empty() {
-                    return Ok(false);
-                };
-            }
-            Instruction::Any => {
-                if line.get(sp).is_none() {
-                    return Ok(false);
                 }
-                safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
-            }
-            Instruction::Start => {
-                if sp == 0 {
+                Instruction::RangeChar(lo, hi) => {
+                    let Some(sp_c) = line.get(sp) else {
+                        return Ok(None);
+                    };
+
+                    if in_range(lo, hi, sp_c, flags) {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                Instruction::Property(prop) => {
+                    let Some(sp_c) = line.get(sp) else {
+                        return Ok(None);
+                    };
+
+                    if in_property(prop, sp_c, flags) {
+                        safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                        safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                Instruction::Any => {
+                    let Some(sp_c) = line.get(sp) else {
+                        return Ok(None);
+                    };
+
+                    if !is_any(sp_c, flags) {
+                        return Ok(None);
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    safe_add(&mut sp, &1, || EvalError::SPOverFlow)?;
+                }
+                Instruction::Start => {
+                    if sp != 0 {
+                        return Ok(None);
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::End => {
+                    if sp != line.len() {
+                        return Ok(None);
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::LineStart => {
+                    if !is_line_start(line, sp) {
+                        return Ok(None);
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::LineEnd => {
+                    if !is_line_end(line, sp) {
+                        return Ok(None);
+                    }
                     safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                } else if queue.is_empty() {
-                    return Ok(false);
-                } else {
-                    let Some(branch) = queue.pop_front() else {
-                        return Err(EvalError::InvalidContext);
+                }
+                Instruction::WordBoundary => {
+                    if !is_word_boundary(line, sp) {
+                        return Ok(None);
+                    }
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                }
+                Instruction::Match if self.longest && self.sub_depth == 0 => {
+                    // 失敗したことにして、残りの分岐からより長いマッチを探す
+                    self.best = self.best.max(Some(sp));
+                    return Ok(None);
+                }
+                Instruction::Match => {
+                    return Ok(Some(sp));
+                }
+                Instruction::Jump(addr) => {
+                    if *addr <= pc {
+                        if loops.contains(&(pc, sp)) {
+                            return Ok(None);
+                        }
+                        loops.push((pc, sp));
+                    }
+                    pc = *addr;
+                }
+                Instruction::Split(addr1, addr2) => {
+                    // 記録するのは`Split`だけなので、ここで確かめれば十分
+                    if self.dead.contains(&(pc, sp)) {
+                        return Ok(None);
+                    }
+                    // 戻ってくるために覚えておく分岐の数が、上限に達していないか
+                    if self
+                        .stack_limit
+                        .is_some_and(|limit| branches.len() >= limit)
+                    {
+                        return Err(EvalError::StackLimitExceeded);
+                    }
+                    // 入力を消費せずに同じ`Split`へ戻ってきた場合は、何度繰り返しても進まないので打ち切る
+                    if !self.splits.insert((pc, sp)) {
+                        self.cuts += 1;
+                        return Ok(None);
+                    }
+                    // 1つ目の分岐を続けて評価し、2つ目の分岐は失敗したときに`backtrack`で試す
+                    branches.push(Branch {
+                        split: (pc, sp),
+                        next: Some(*addr2),
+                        captures: self.captures.clone(),
+                        cuts: self.cuts,
+                    });
+                    loops.clear();
+                    pc = *addr1;
+                }
+                Instruction::Atomic(addr) => {
+                    // 中身を1度だけ評価して、見つかった位置から先に進む。中身へのバックトラックはしない
+                    let Some(end) = self.eval_sub(pc, sp)? else {
+                        return Ok(None);
                     };
-                    pc = branch.0;
-                    sp = branch.1;
+                    pc = *addr;
+                    sp = end;
                 }
-            }
-            Instruction::End => {
-                if sp == line.len() {
+                Instruction::LookAhead(addr) => {
+                    // 中身がマッチするか確かめるだけで、入力は消費しない
+                    if self.eval_sub(pc, sp)?.is_none() {
+                        return Ok(None);
+                    }
+                    pc = *addr;
+                }
+                Instruction::NegLookAhead(addr) => {
+                    if !self.eval_negative(|e| Ok(e.eval_sub(pc, sp)?.is_some()))? {
+                        return Ok(None);
+                    }
+                    pc = *addr;
+                }
+                Instruction::LookBehind(width, addr) => {
+                    if !self.eval_look_behind(pc, sp, *width)? {
+                        return Ok(None);
+                    }
+                    pc = *addr;
+                }
+                Instruction::NegLookBehind(width, addr) => {
+                    if !self.eval_negative(|e| e.eval_look_behind(pc, sp, *width))? {
+                        return Ok(None);
+                    }
+                    pc = *addr;
+                }
+                Instruction::Save(n) => {
+                    if self.captures.len() <= *n {
+                        self.captures.resize(n + 1, None);
+                    }
+                    self.captures[*n] = Some(sp);
                     safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
-                } else if queue.is_empty() {
-                    return Ok(false);
-                } else {
-                    let Some(branch) = queue.pop_front() else {
-                        return Err(EvalError::InvalidContext);
+                }
+                Instruction::BackRef(n) => {
+                    let Some(next) = self.back_ref(*n, sp) else {
+                        return Ok(None);
                     };
-                    pc = branch.0;
-                    sp = branch.1;
+                    safe_add(&mut pc, &1, || EvalError::PCOverFlow)?;
+                    sp = next;
                 }
             }
+        }
+    }
+}
+
+pub fn eval_depth(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    config: &EvalConfig,
+) -> Result<bool, EvalError> {
+    let mut evaluator = DepthEvaluator::with_config(insts, line, flags, config);
+    let end = evaluator.eval(0, 0)?;
+    Ok(end.is_some())
+}
+
+/// 幅優先探索で使うバッファ
+///
+/// 評価のたびに空にして使い回すので、多くの行を評価するときにメモリの確保を減らせる
+///
+/// ```
+/// use regex_machine::engine::{do_matching_with_context, EvalContext};
+/// let mut context = EvalContext::new();
+/// for line in ["abc", "abd", "xyz"] {
+///     do_matching_with_context("ab(c|d)", line, &mut context).unwrap();
+/// }
+/// ```
+#[derive(Debug, Default)]
+pub struct EvalContext {
+    /// まだ評価していない分岐の`(pc, sp)`
+    queue: VecDeque<(usize, usize)>,
+    /// 評価済みの`Split`の`(pc, sp)`。同じ状態から先は同じ結果になるので、2度目は評価しない
+    visited: HashSet<(usize, usize)>,
+}
+
+impl EvalContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 確保したメモリは残したまま、前回の評価の内容を消す
+    fn clear(&mut self) {
+        self.queue.clear();
+        self.visited.clear();
+    }
+}
+
+fn eval_width(insts: &[Instruction], line: &[char], flags: &Flags) -> Result<bool, EvalError> {
+    eval_width_with_context(insts, line, flags, &mut EvalContext::new())
+}
+
+/// `context`のバッファを使って、幅優先探索で評価する
+pub fn eval_width_with_context(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    context: &mut EvalContext,
+) -> Result<bool, EvalError> {
+    Ok(width_from(insts, line, flags, 0, false, &mut 0, context)?.is_some())
+}
+
+/// 幅優先探索で`start`から評価し、マッチした場合はマッチし終わった位置を返す
+///
+/// 実行した命令の数を`steps`に足す
+fn width_from(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    start: usize,
+    longest: bool,
+    steps: &mut usize,
+    context: &mut EvalContext,
+) -> Result<Option<usize>, EvalError> {
+    // `longest`のときに見つかった、最も長いマッチの終わり
+    let mut best = None;
+    context.clear();
+    let EvalContext { queue, visited } = context;
+    let mut pc = 0;
+    let mut sp = start;
+    loop {
+        let Some(inst) = insts.get(pc) else {
+            return Err(EvalError::InvalidPC);
+        };
+        *steps += 1;
+        let mut next = pc;
+        safe_add(&mut next, &1, || EvalError::PCOverFlow)?;
+
+        // 評価を続けられる場合は、次のプログラムカウンタと位置を返す
+        let advance = match inst {
+            // 入力の終わりに達した分岐も、再びキューに入れずに捨てる
+            Instruction::Char(c) => line
+                .get(sp)
+                .is_some_and(|sp_c| eq_char(c, sp_c, flags))
+                .then_some((next, sp + 1)),
+            Instruction::StringMatch(s) => match_str(s, line, sp, flags).map(|end| (next, end)),
+            Instruction::CharClass(class) => line
+                .get(sp)
+                .is_some_and(|sp_c| in_class(class, sp_c, flags))
+                .then_some((next, sp + 1)),
+            Instruction::RangeChar(lo, hi) => line
+                .get(sp)
+                .is_some_and(|sp_c| in_range(lo, hi, sp_c, flags))
+                .then_some((next, sp + 1)),
+            Instruction::Property(prop) => line
+                .get(sp)
+                .is_some_and(|sp_c| in_property(prop, sp_c, flags))
+                .then_some((next, sp + 1)),
+            Instruction::Any => line
+                .get(sp)
+                .is_some_and(|sp_c| is_any(sp_c, flags))
+                .then_some((next, sp + 1)),
+            Instruction::Start => (sp == 0).then_some((next, sp)),
+            Instruction::End => (sp == line.len()).then_some((next, sp)),
+            Instruction::LineStart => is_line_start(line, sp).then_some((next, sp)),
+            Instruction::LineEnd => is_line_end(line, sp).then_some((next, sp)),
+            Instruction::WordBoundary => is_word_boundary(line, sp).then_some((next, sp)),
             Instruction::Match => {
-                return Ok(true);
+                if !longest {
+                    return Ok(Some(sp));
+                }
+                // 残りの分岐も評価して、より長いマッチを探す
+                best = best.max(Some(sp));
+                None
             }
             Instruction::Jump(addr) => {
-                pc = *addr;
+                // 入力を消費せずに同じ状態へ戻ってきた場合は、その分岐を捨てる
+                let is_loop = *addr <= pc && !visited.insert((pc, sp));
+                (!is_loop).then_some((*addr, sp))
+            }
+            // 先読み・後読みとアトミックグループの中身は深さ優先で評価する
+            Instruction::LookAhead(addr) | Instruction::NegLookAhead(addr) => {
+                let is_match = DepthEvaluator::new(insts, line, flags)
+                    .eval_sub(pc, sp)?
+                    .is_some();
+                let is_negative = matches!(inst, Instruction::NegLookAhead(_));
+                (is_match != is_negative).then_some((*addr, sp))
+            }
+            Instruction::LookBehind(width, addr) | Instruction::NegLookBehind(width, addr) => {
+                let is_match =
+                    DepthEvaluator::new(insts, line, flags).eval_look_behind(pc, sp, *width)?;
+                let is_negative = matches!(inst, Instruction::NegLookBehind(..));
+                (is_match != is_negative).then_some((*addr, sp))
+            }
+            Instruction::Atomic(addr) => DepthEvaluator::new(insts, line, flags)
+                .eval_sub(pc, sp)?
+                .map(|end| (*addr, end)),
+            // 幅優先探索ではキャプチャした位置を使わない
+            Instruction::Save(_) => Some((next, sp)),
+            Instruction::BackRef(_) => {
+                return Err(EvalError::BackRefNotSupported);
             }
             Instruction::Split(addr1, addr2) => {
-                // プログラムカウンタをセットして、ブランチをプッシュ
-                pc = *addr1;
-                queue.push_back((*addr2, sp));
-                continue;
+                // 空文字列にマッチする繰り返しで戻ってきた場合も、ここで止まる
+                if visited.insert((pc, sp)) {
+                    // プログラムカウンタをセットして、ブランチをプッシュ
+                    pc = *addr1;
+                    queue.push_back((*addr2, sp));
+                    continue;
+                }
+                None
             }
-        }
+        };
+
+        // 失敗した分岐は捨てて、キューから次の分岐を取り出す。分岐がもうないときは終わり
+        let Some((next_pc, next_sp)) = advance else {
+            let Some(branch) = queue.pop_front() else {
+                return Ok(best);
+            };
+            (pc, sp) = branch;
+            continue;
+        };
+        pc = next_pc;
+        sp = next_sp;
 
         if !queue.is_empty() {
             queue.push_back((pc, sp));
@@ -174,17 +879,500 @@ fn eval_width(insts: &[Instruction], line: &[char]) -> Result<bool, EvalError> {
     }
 }
 
-pub fn eval(insts: &[Instruction], line: &[char], is_depth: bool) -> Result<bool, EvalError> {
-    if is_depth {
-        eval_depth(insts, line, 0, 0)
-    } else {
-        eval_width(insts, line)
+/// 命令の位置の集合。命令ごとに1ビットを使う
+#[derive(Debug, Clone)]
+struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    /// `len`個の命令を入れられる、空の集合を作る
+    fn new(len: usize) -> Self {
+        BitSet {
+            words: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    /// `pc`を追加する。既に含まれていた場合は`false`を返す
+    fn insert(&mut self, pc: usize) -> bool {
+        let bit = 1 << (pc % 64);
+        let word = &mut self.words[pc / 64];
+        let is_new = *word & bit == 0;
+        *word |= bit;
+        is_new
     }
+
+    fn clear(&mut self) {
+        self.words.fill(0);
+    }
+}
+
+/// すべてのスレッドを1文字ずつ同時に進める評価器(Pike VM)
+///
+/// 同じ位置で同じ命令に来たスレッドは1つにまとめるので、入力の長さと命令の数の積に比例する時間で終わる。
+/// ただし先読み・後読みとアトミックグループの中身は深さ優先で評価する
+pub fn eval_pike(insts: &[Instruction], line: &[char], flags: &Flags) -> Result<bool, EvalError> {
+    Ok(pike_from(insts, line, flags, 0, false, &mut 0)?.is_some())
+}
+
+/// Pike VMで`start`から評価し、マッチした場合はマッチし終わった位置を返す
+///
+/// 位置の小さい方から進めるので、最初に見つかる最も短いマッチの終わりを返す。実行した命令の数を`steps`に足す
+fn pike_from(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    start: usize,
+    longest: bool,
+    steps: &mut usize,
+) -> Result<Option<usize>, EvalError> {
+    let config = EvalConfig::default().anchored(true).longest(longest);
+    let span = pike_run(insts, line, flags, start, &config, steps, |_| true)?;
+    Ok(span.map(|(_, end)| end))
+}
+
+/// 入力のどこかにマッチする部分があるかを、Pike VMで1度だけ入力を読んで検証する
+///
+/// `first`を満たす文字の位置でだけ、新しいスレッドを始める
+pub fn pike_search(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    first: Option<&CharClass>,
+) -> Result<bool, EvalError> {
+    let is_start = |sp: usize| match first {
+        Some(class) => line.get(sp).is_some_and(|c| class.contains(*c)),
+        None => true,
+    };
+    let config = EvalConfig::default();
+    Ok(pike_run(insts, line, flags, 0, &config, &mut 0, is_start)?.is_some())
+}
+
+/// 入力の`from`以降で最も左にあるマッチを、Pike VMで1度だけ入力を読んで探し、`(始まり, 終わり)`を返す
+///
+/// `is_start`を満たす位置でだけ、新しいスレッドを始める。`EvalConfig::longest`のときはその位置から始まる最も長いマッチを、
+/// そうでないときは最初に`match`に到達した最も短いマッチを返す
+pub fn pike_find(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    from: usize,
+    config: &EvalConfig,
+    is_start: impl Fn(usize) -> bool,
+) -> Result<Option<(usize, usize)>, EvalError> {
+    pike_run(insts, line, flags, from, config, &mut 0, is_start)
+}
+
+/// Pike VMで`from`から評価し、最も左にあるマッチの`(始まり, 終わり)`を返す
+///
+/// `is_start`を満たす位置では、先頭の命令から新しいスレッドを始める。`config`が`anchored`のときは`from`でだけ始める。
+/// 各スレッドは始まった位置を持ち、同じ命令に来たスレッドは始まりが左のものを残す
+fn pike_run(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    from: usize,
+    config: &EvalConfig,
+    steps: &mut usize,
+    is_start: impl Fn(usize) -> bool,
+) -> Result<Option<(usize, usize)>, EvalError> {
+    if from > line.len() {
+        return Ok(None);
+    }
+    let EvalConfig {
+        anchored, longest, ..
+    } = *config;
+    // 見つかったマッチ。`longest`でないときは、始まりごとに最初に見つかったものが最も短い
+    let mut found: Option<(usize, usize)> = None;
+    // `pending[sp - from]`は、`sp`から評価を始めるスレッドの`(プログラムカウンタ, 始まり)`
+    let mut pending = Vec::<Vec<(usize, usize)>>::new();
+    // `pending`に入っているスレッドの数
+    let mut live = 0;
+    // 今の位置で評価済みの命令
+    let mut visited = BitSet::new(insts.len());
+    // 次の位置で評価する命令
+    let mut queued = BitSet::new(insts.len());
+
+    for sp in from..=line.len() {
+        let mut stack = pending
+            .get_mut(sp - from)
+            .map(core::mem::take)
+            .unwrap_or_default();
+        live -= stack.len();
+        // 見つかったマッチより右で始まったスレッドは、もう評価しなくてよい
+        if let Some((start, _)) = found {
+            stack.retain(|(_, s)| *s < start || longest && *s == start);
+        }
+        // 残っているスレッドがなく、新しいスレッドも始めない場合は終わり
+        if stack.is_empty() && live == 0 && (found.is_some() || anchored && sp > from) {
+            break;
+        }
+        let is_seed = found.is_none() && (!anchored || sp == from) && is_start(sp);
+        if stack.is_empty() && !is_seed {
+            continue;
+        }
+        visited.clear();
+        queued.clear();
+        // スタックの上から取り出すので、始まりが左のスレッドほど上に置いて先に評価する
+        stack.sort_by_key(|(_, s)| core::cmp::Reverse(*s));
+        if is_seed {
+            stack.insert(0, (0, sp));
+        }
+
+        while let Some((pc, start)) = stack.pop() {
+            let Some(inst) = insts.get(pc) else {
+                return Err(EvalError::InvalidPC);
+            };
+            if !visited.insert(pc) {
+                continue;
+            }
+            *steps += 1;
+            let mut next = pc;
+            safe_add(&mut next, &1, || EvalError::PCOverFlow)?;
+
+            // 入力を消費する命令は、消費し終わった位置とプログラムカウンタを返す
+            let advance = match inst {
+                Instruction::Char(c) => line
+                    .get(sp)
+                    .is_some_and(|sp_c| eq_char(c, sp_c, flags))
+                    .then_some((sp + 1, next)),
+                Instruction::StringMatch(s) => match_str(s, line, sp, flags).map(|end| (end, next)),
+                Instruction::CharClass(class) => line
+                    .get(sp)
+                    .is_some_and(|sp_c| in_class(class, sp_c, flags))
+                    .then_some((sp + 1, next)),
+                Instruction::RangeChar(lo, hi) => line
+                    .get(sp)
+                    .is_some_and(|sp_c| in_range(lo, hi, sp_c, flags))
+                    .then_some((sp + 1, next)),
+                Instruction::Property(prop) => line
+                    .get(sp)
+                    .is_some_and(|sp_c| in_property(prop, sp_c, flags))
+                    .then_some((sp + 1, next)),
+                Instruction::Any => line
+                    .get(sp)
+                    .is_some_and(|sp_c| is_any(sp_c, flags))
+                    .then_some((sp + 1, next)),
+                Instruction::Atomic(addr) => DepthEvaluator::new(insts, line, flags)
+                    .eval_sub(pc, sp)?
+                    .map(|end| (end, *addr)),
+                Instruction::Start => (sp == 0).then_some((sp, next)),
+                Instruction::End => (sp == line.len()).then_some((sp, next)),
+                Instruction::LineStart => is_line_start(line, sp).then_some((sp, next)),
+                Instruction::LineEnd => is_line_end(line, sp).then_some((sp, next)),
+                Instruction::WordBoundary => is_word_boundary(line, sp).then_some((sp, next)),
+                Instruction::LookAhead(addr) | Instruction::NegLookAhead(addr) => {
+                    let is_match = DepthEvaluator::new(insts, line, flags)
+                        .eval_sub(pc, sp)?
+                        .is_some();
+                    let is_negative = matches!(inst, Instruction::NegLookAhead(_));
+                    (is_match != is_negative).then_some((sp, *addr))
+                }
+                Instruction::LookBehind(width, addr) | Instruction::NegLookBehind(width, addr) => {
+                    let is_match =
+                        DepthEvaluator::new(insts, line, flags).eval_look_behind(pc, sp, *width)?;
+                    let is_negative = matches!(inst, Instruction::NegLookBehind(..));
+                    (is_match != is_negative).then_some((sp, *addr))
+                }
+                // 位置を記録しないので、何もしない
+                Instruction::Save(_) => Some((sp, next)),
+                Instruction::BackRef(_) => return Err(EvalError::BackRefNotSupported),
+                Instruction::Match => {
+                    // 始まりが左のものを優先し、同じ始まりなら位置の小さい方から進めるので後で見つかったものほど長い
+                    let is_better = match found {
+                        None => true,
+                        Some((s, _)) => start < s || longest && start == s,
+                    };
+                    if is_better {
+                        found = Some((start, sp));
+                    }
+                    // 残りのスレッドは始まりが同じか右なので、最も短いマッチを探す場合は評価しなくてよい
+                    if !longest {
+                        stack.clear();
+                    }
+                    None
+                }
+                Instruction::Jump(addr) => Some((sp, *addr)),
+                Instruction::Split(addr1, addr2) => {
+                    stack.push((*addr2, start));
+                    Some((sp, *addr1))
+                }
+            };
+
+            let Some((end, pc)) = advance else {
+                continue;
+            };
+            if end == sp {
+                stack.push((pc, start));
+            } else if end > sp + 1 || queued.insert(pc) {
+                // 同じ命令を次の位置へ何度も送らない
+                let index = end - from;
+                if pending.len() <= index {
+                    pending.resize_with(index + 1, Vec::new);
+                }
+                pending[index].push((pc, start));
+                live += 1;
+            }
+        }
+    }
+
+    Ok(found)
+}
+
+/// 評価に使う方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// 深さ優先探索。後方参照を使える
+    Depth,
+    /// 幅優先探索
+    Width,
+    /// すべてのスレッドを同時に進める。入力の長さに対して線形時間で終わる
+    Pike,
+}
+
+/// `strategy`の方式で評価する
+pub fn eval(
+    insts: &[Instruction],
+    line: &[char],
+    strategy: Strategy,
+    flags: &Flags,
+    config: &EvalConfig,
+) -> Result<bool, EvalError> {
+    match strategy {
+        Strategy::Depth => eval_depth(insts, line, flags, config),
+        Strategy::Width => eval_width(insts, line, flags),
+        Strategy::Pike => eval_pike(insts, line, flags),
+    }
+}
+
+/// 各グループがキャプチャした範囲。`n`番目のグループが`n`番目に入り、0番目はマッチ全体
+pub type Captures = Vec<Option<(usize, usize)>>;
+
+/// 深さ優先探索で入力の`start`から評価し、マッチした場合は各グループがキャプチャした範囲を返す
+///
+/// 0番目はマッチ全体の範囲。マッチに使われなかったグループは`None`
+pub fn eval_captures(
+    insts: &[Instruction],
+    line: &[char],
+    start: usize,
+    flags: &Flags,
+    config: &EvalConfig,
+) -> Result<Option<Captures>, EvalError> {
+    let mut evaluator = DepthEvaluator::with_config(insts, line, flags, config);
+    let Some(end) = evaluator.eval(0, start)? else {
+        return Ok(None);
+    };
+
+    let groups = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Instruction::Save(n) => Some(n / 2 + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(1);
+    let mut captures = vec![Some((start, end))];
+    captures.extend((1..groups).map(|n| evaluator.capture(n)));
+
+    Ok(Some(captures))
+}
+
+/// 入力の`pos`から深さ優先探索で評価し、マッチした場合はマッチし終わった位置を返す
+///
+/// `pos`より前の入力は、`^`や後読み、単語境界の判定に使う。マッチを順に探す処理や、字句解析器を作るのに使える
+///
+/// ```
+/// use regex_machine::engine::{match_at, Flags, Instruction};
+/// let insts = vec![Instruction::StringMatch("bc".to_string()), Instruction::Match];
+/// let line = "abc".chars().collect::<Vec<_>>();
+/// assert_eq!(match_at(&insts, &line, 1, &Flags::default()).unwrap(), Some(3));
+/// ```
+pub fn match_at(
+    insts: &[Instruction],
+    line: &[char],
+    pos: usize,
+    flags: &Flags,
+) -> Result<Option<usize>, EvalError> {
+    eval_from(
+        insts,
+        line,
+        pos,
+        Strategy::Depth,
+        flags,
+        &EvalConfig::default(),
+    )
+}
+
+/// 入力の`start`から`strategy`の方式で評価し、マッチした場合はマッチし終わった位置を返す
+///
+/// 幅優先探索とPike VMは最初に`match`に到達した分岐で止まるので、`longest`でない場合は
+/// マッチがあることだけをその方式で確かめ、優先される分岐の終わりは深さ優先探索で求める
+pub fn eval_from(
+    insts: &[Instruction],
+    line: &[char],
+    start: usize,
+    strategy: Strategy,
+    flags: &Flags,
+    config: &EvalConfig,
+) -> Result<Option<usize>, EvalError> {
+    match strategy {
+        Strategy::Depth => {
+            let mut evaluator = DepthEvaluator::with_config(insts, line, flags, config);
+            evaluator.longest = config.longest;
+            let end = evaluator.eval(0, start)?;
+            Ok(if config.longest { evaluator.best } else { end })
+        }
+        Strategy::Width | Strategy::Pike => {
+            let end = match strategy {
+                Strategy::Width => width_from(
+                    insts,
+                    line,
+                    flags,
+                    start,
+                    config.longest,
+                    &mut 0,
+                    &mut EvalContext::new(),
+                )?,
+                _ => pike_from(insts, line, flags, start, config.longest, &mut 0)?,
+            };
+            if config.longest || end.is_none() {
+                return Ok(end);
+            }
+            eval_from(insts, line, start, Strategy::Depth, flags, config)
+        }
+    }
+}
+
+/// 先頭から`Split`をたどって、分かれた先の命令の位置を返す
+#[cfg(feature = "std")]
+fn split_branches(insts: &[Instruction]) -> Result<Vec<usize>, EvalError> {
+    let mut branches = Vec::new();
+    let mut visited = HashSet::new();
+    let mut stack = vec![0];
+    while let Some(pc) = stack.pop() {
+        if !visited.insert(pc) {
+            continue;
+        }
+        match insts.get(pc) {
+            Some(Instruction::Split(addr1, addr2)) => {
+                stack.push(*addr2);
+                stack.push(*addr1);
+            }
+            Some(_) => branches.push(pc),
+            None => return Err(EvalError::InvalidPC),
+        }
+    }
+    Ok(branches)
+}
+
+/// 先頭の`Split`から分かれる分岐を、別々のスレッドで深さ優先探索する(実験的)
+///
+/// どれかの分岐がマッチした時点で、評価中の分岐を打ち切って返す。使うスレッドは`EvalConfig::max_threads`個までで、
+/// 分岐が1つしかない場合や上限が1以下の場合は、呼び出したスレッドで評価する
+#[cfg(feature = "std")]
+pub fn eval_parallel(
+    insts: &[Instruction],
+    line: &[char],
+    flags: &Flags,
+    config: &EvalConfig,
+) -> Result<bool, EvalError> {
+    let branches = split_branches(insts)?;
+    let max_threads = config
+        .max_threads
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, core::num::NonZeroUsize::get));
+    let threads = max_threads.min(branches.len());
+    if threads <= 1 {
+        return eval_depth(insts, line, flags, config);
+    }
+
+    // 次に評価する分岐の番号
+    let next = AtomicUsize::new(0);
+    // マッチが見つかったら`true`にして、評価中の分岐を打ち切る
+    let cancel = AtomicBool::new(false);
+    // 返る前にすべてのスレッドの終了を待つので、評価が裏で続くことはない
+    thread::scope(|scope| {
+        let (tx, rx) = mpsc::channel();
+        for _ in 0..threads {
+            let (branches, next, cancel, tx) = (&branches, &next, &cancel, tx.clone());
+            scope.spawn(move || {
+                while let Some(pc) = branches.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    if cancel.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let mut evaluator = DepthEvaluator::with_config(insts, line, flags, config);
+                    evaluator.cancel = Some(cancel);
+                    let result = evaluator.eval(*pc, 0).map(|end| end.is_some());
+                    // 受け取る側がもう返している場合は、残りの分岐を評価しない
+                    if tx.send(result).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(tx);
+
+        // エラーになった分岐があっても、他の分岐がマッチすればマッチとする
+        let mut error = None;
+        for result in rx {
+            match result {
+                Ok(true) => {
+                    cancel.store(true, Ordering::Relaxed);
+                    return Ok(true);
+                }
+                Ok(false) => {}
+                Err(e) => error = error.or(Some(e)),
+            }
+        }
+        error.map_or(Ok(false), Err)
+    })
+}
+
+/// 評価の結果と、評価中に実行した命令の数
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EvalStats {
+    /// マッチしたか
+    pub matched: bool,
+    /// 実行した命令の数。先読みなどの中身を別に評価した分は含まない
+    pub steps: usize,
+}
+
+/// `strategy`の方式で評価し、結果と実行した命令の数を返す
+pub fn eval_with_stats(
+    insts: &[Instruction],
+    line: &[char],
+    strategy: Strategy,
+    flags: &Flags,
+) -> Result<EvalStats, EvalError> {
+    let mut steps = 0;
+    let end = match strategy {
+        Strategy::Depth => {
+            let mut evaluator = DepthEvaluator::new(insts, line, flags);
+            let end = evaluator.eval(0, 0)?;
+            steps = evaluator.steps;
+            end
+        }
+        Strategy::Width => width_from(
+            insts,
+            line,
+            flags,
+            0,
+            false,
+            &mut steps,
+            &mut EvalContext::new(),
+        )?,
+        Strategy::Pike => pike_from(insts, line, flags, 0, false, &mut steps)?,
+    };
+
+    Ok(EvalStats {
+        matched: end.is_some(),
+        steps,
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use crate::engine::{codegen, parser};
+    use alloc::{format, string::ToString};
 
     use super::*;
 
@@ -204,23 +1392,47 @@ mod tests {
         let line = to_chars("abcde");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res)
     }
 
+    #[test]
+    fn test_string() {
+        let insts = to_insts("abcdef");
+        assert_eq!(insts.len(), 2);
+
+        for (line, expected) in [("abcdefg", true), ("abcdxf", false), ("abc", false)] {
+            let line = to_chars(line);
+
+            let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+            assert_eq!(res, expected);
+
+            let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+            assert_eq!(res, expected);
+        }
+
+        let flags = Flags {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let line = to_chars("AbCdEf");
+        assert!(eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap());
+        assert!(eval_width(&insts, &line, &flags).unwrap());
+    }
+
     #[test]
     fn test_question() {
         let regex = "a?";
         let line = to_chars("ab");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res)
     }
 
@@ -230,19 +1442,69 @@ mod tests {
         let line = to_chars("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res);
 
         let line = to_chars("b");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res)
+    }
+
+    #[test]
+    fn test_range_char() {
+        let insts = vec![Instruction::RangeChar('0', '9'), Instruction::Match];
+
+        let line = to_chars("5");
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res);
+
+        let line = to_chars("a");
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res);
+
+        // `(?i)`のときは大文字も範囲に含まれる
+        let insts = vec![Instruction::RangeChar('a', 'f'), Instruction::Match];
+        let line = to_chars("C");
+        let flags = Flags {
+            case_insensitive: true,
+            ..Default::default()
+        };
+        let res = eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap();
+        assert!(res);
+    }
+
+    #[test]
+    fn test_class_plus() {
+        let regex = "[a-c]+";
+        let line = to_chars("abcabc");
+        let insts = to_insts(regex);
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res);
+
+        let line = to_chars("d");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(!res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(!res)
     }
 
@@ -252,34 +1514,566 @@ mod tests {
         let line = to_chars("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res);
 
         // `*`は0文字でもマッチするためこっちはなし
     }
 
+    #[test]
+    fn test_pike() {
+        // 深さ優先では分岐の数が2のn乗になるが、同じ命令に来たスレッドはまとめるので、すぐに終わる
+        let insts = to_insts("(a|a)*b");
+        let line = to_chars(&"a".repeat(5000));
+        assert!(!eval_pike(&insts, &line, &Flags::default()).unwrap());
+
+        let line = to_chars(&format!("{}b", "a".repeat(5000)));
+        assert!(eval_pike(&insts, &line, &Flags::default()).unwrap());
+
+        // 深さ優先と同じ結果になる
+        let cases = [
+            ("abc|(de|cd)+", "decddede"),
+            ("a+?b*?c", "aabbc"),
+            ("(?=ab)a.", "ab"),
+            ("(?<!x)ab$", "ab"),
+            ("(?>a+)a", "aaa"),
+            (r"foo", "foo bar"),
+            ("[a-c]{2,3}d", "abd"),
+            ("(a*)*b", "aa"),
+            ("a.|a", "a"),
+        ];
+        for (regex, line) in cases {
+            let insts = to_insts(regex);
+            let line = to_chars(line);
+
+            let depth =
+                eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+            let pike = eval_pike(&insts, &line, &Flags::default()).unwrap();
+            assert_eq!(depth, pike, "{regex}");
+        }
+
+        let insts = to_insts(r"(a)\1");
+        let line = to_chars("aa");
+        assert_eq!(
+            eval(
+                &insts,
+                &line,
+                Strategy::Pike,
+                &Flags::default(),
+                &EvalConfig::default()
+            ),
+            Err(EvalError::BackRefNotSupported)
+        );
+    }
+
+    #[test]
+    fn test_step_limit() {
+        let insts = to_insts("(a+)+$");
+        let line = to_chars(&format!("{}b", "a".repeat(100)));
+        let config = EvalConfig::default().step_limit(300);
+
+        let err = eval(&insts, &line, Strategy::Depth, &Flags::default(), &config);
+        assert_eq!(err, Err(EvalError::StepLimitExceeded));
+
+        let line = to_chars("aaa");
+        assert!(eval(&insts, &line, Strategy::Depth, &Flags::default(), &config).unwrap());
+    }
+
+    #[test]
+    fn test_stats() {
+        let insts = to_insts("(a|b)*c");
+        let line = to_chars("ababc");
+        let depth = eval_with_stats(&insts, &line, Strategy::Depth, &Flags::default()).unwrap();
+        let width = eval_with_stats(&insts, &line, Strategy::Width, &Flags::default()).unwrap();
+        let pike = eval_with_stats(&insts, &line, Strategy::Pike, &Flags::default()).unwrap();
+        assert!(depth.matched && width.matched && pike.matched);
+        assert!(depth.steps > 0 && width.steps > 0 && pike.steps > 0);
+        assert_ne!(depth.steps, width.steps);
+        assert_ne!(depth.steps, pike.steps);
+
+        // 同じ入力なら同じ数になる
+        let again = eval_with_stats(&insts, &line, Strategy::Depth, &Flags::default()).unwrap();
+        assert_eq!(depth, again);
+
+        let stats = eval_with_stats(&insts, &to_chars("abab"), Strategy::Pike, &Flags::default());
+        assert!(!stats.unwrap().matched);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_parallel() {
+        let patterns = [
+            "abc|(de|cd)+",
+            "a|b|c|d",
+            "x*y|(a|aa)+$",
+            r"(a)\1|b",
+            "(?<=a)b|^b",
+            "abc",
+        ];
+        let lines = ["abc", "decd", "d", "xxy", "aaaa", "aaab", "aa", "ab", "b"];
+        for pattern in patterns {
+            let insts = to_insts(pattern);
+            for line in lines {
+                let line = to_chars(line);
+                let expected = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default());
+                for max_threads in [0, 1, 2, 4] {
+                    let config = EvalConfig::default().max_threads(max_threads);
+                    assert_eq!(
+                        eval_parallel(&insts, &line, &Flags::default(), &config),
+                        expected,
+                        "{pattern} {line:?} {max_threads}"
+                    );
+                }
+            }
+        }
+        assert_eq!(split_branches(&to_insts("a|b|c")).unwrap().len(), 3);
+
+        // 分岐が多くても、上限より多くのスレッドは使わない
+        let pattern = (0..200)
+            .map(|n| format!("x{n}y"))
+            .collect::<Vec<_>>()
+            .join("|");
+        let insts = to_insts(&pattern);
+        assert_eq!(split_branches(&insts).unwrap().len(), 200);
+        for max_threads in [1, 3] {
+            let config = EvalConfig::default().max_threads(max_threads);
+            for (line, expected) in [("x150y", true), ("x1y", true), ("x200y", false)] {
+                let line = to_chars(line);
+                assert_eq!(
+                    eval_parallel(&insts, &line, &Flags::default(), &config),
+                    Ok(expected)
+                );
+            }
+        }
+        let line = to_chars("x199y");
+        assert_eq!(
+            eval_parallel(&insts, &line, &Flags::default(), &EvalConfig::default()),
+            Ok(true)
+        );
+
+        // 他の分岐がマッチしたら、終わらない分岐は打ち切られる
+        let insts = to_insts(r"(x+x+)+y\1|x");
+        let line = to_chars(&"x".repeat(40));
+        let config = EvalConfig::default().max_threads(2);
+        assert_eq!(
+            eval_parallel(&insts, &line, &Flags::default(), &config),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_context() {
+        let insts = to_insts("(a|b)*c");
+        let mut context = EvalContext::new();
+        assert_eq!(
+            eval_width_with_context(&insts, &to_chars("ababc"), &Flags::default(), &mut context),
+            Ok(true)
+        );
+        let capacity = context.queue.capacity();
+        assert!(capacity > 0);
+
+        // 短い行をいくつ評価しても、同じバッファを使う
+        for line in ["abc", "c", "bbd", "ab", "bac"] {
+            let line = to_chars(line);
+            assert_eq!(
+                eval_width_with_context(&insts, &line, &Flags::default(), &mut context),
+                eval_width(&insts, &line, &Flags::default())
+            );
+            assert_eq!(context.queue.capacity(), capacity);
+        }
+    }
+
+    #[test]
+    fn test_bitset() {
+        let mut set = BitSet::new(130);
+        assert_eq!(set.words.len(), 3);
+        assert!(set.insert(0));
+        assert!(set.insert(129));
+        assert!(!set.insert(129));
+        assert!(set.insert(64));
+        set.clear();
+        assert!(set.insert(129));
+    }
+
+    #[test]
+    fn test_pike_no_duplicate() {
+        // 同じ位置に同じ命令のスレッドがいくつもできるパターン
+        let insts = to_insts("(a|a|a|a)*(a|a)*b");
+        for n in [1, 10, 50] {
+            let line = to_chars(&"a".repeat(n));
+            let stats = eval_with_stats(&insts, &line, Strategy::Pike, &Flags::default()).unwrap();
+            assert!(!stats.matched);
+            // 各位置で、それぞれの命令を高々1回しか評価しない
+            assert!(stats.steps <= (n + 1) * insts.len(), "{n} {}", stats.steps);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_deadline() {
+        // 後方参照があるとメモ化しないので、失敗するまでに多くの分岐を試す
+        let insts = to_insts(r"(x?)(a|aa)+\1$");
+        let line = to_chars(&format!("{}b", "a".repeat(100)));
+        let config = EvalConfig::default().deadline(Instant::now());
+        assert_eq!(
+            eval_depth(&insts, &line, &Flags::default(), &config),
+            Err(EvalError::Timeout)
+        );
+
+        // 期限までに終われば、普通に評価できる
+        let config =
+            EvalConfig::default().deadline(Instant::now() + std::time::Duration::from_secs(60));
+        let line = to_chars("aaaa");
+        assert_eq!(
+            eval_depth(&insts, &line, &Flags::default(), &config),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_error_message() {
+        assert_eq!(
+            EvalError::StepLimitExceeded.to_string(),
+            "EvaluationError: step limit exceeded"
+        );
+        assert_eq!(
+            EvalError::Timeout.to_string(),
+            "EvaluationError: deadline exceeded"
+        );
+        assert_eq!(
+            EvalError::DfaCacheFull.to_string(),
+            "EvaluationError: DFA cache is full"
+        );
+        assert_eq!(
+            EvalError::BackRefNotSupported.to_string(),
+            "EvaluationError: back references require depth-first evaluation"
+        );
+    }
+
+    #[test]
+    fn test_eq_char() {
+        let flags = Flags::default();
+        let folded = EvalConfig::default().case_insensitive(true).apply(&flags);
+        assert!(eq_char(&'a', &'a', &flags));
+        assert!(!eq_char(&'a', &'A', &flags));
+        assert!(eq_char(&'a', &'A', &folded));
+        assert!(eq_char(&'Ä', &'ä', &folded));
+        // ケルビン記号は小文字にすると`k`になる
+        assert!(eq_char(&'k', &'\u{212A}', &folded));
+        assert!(!eq_char(&'a', &'b', &folded));
+
+        let insts = to_insts("abc");
+        let line = to_chars("ABC");
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            assert_eq!(
+                eval(&insts, &line, strategy, &flags, &EvalConfig::default()),
+                Ok(false)
+            );
+            assert_eq!(
+                eval(&insts, &line, strategy, &folded, &EvalConfig::default()),
+                Ok(true)
+            );
+        }
+    }
+
+    #[test]
+    fn test_stack_limit() {
+        // 繰り返すたびに分岐を積むので、長い入力では多くなる
+        let insts = to_insts("(a|b)*c");
+        let line = to_chars(&"ab".repeat(1000));
+        let config = EvalConfig::default().stack_limit(1000);
+        assert_eq!(
+            eval_depth(&insts, &line, &Flags::default(), &config),
+            Err(EvalError::StackLimitExceeded)
+        );
+        // デフォルトでは上限はない
+        assert_eq!(
+            eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()),
+            Ok(false)
+        );
+        assert_eq!(
+            EvalError::StackLimitExceeded.to_string(),
+            "EvaluationError: stack limit exceeded"
+        );
+
+        let line = to_chars("ababc");
+        let config = EvalConfig::default().stack_limit(5);
+        assert_eq!(
+            eval_depth(&insts, &line, &Flags::default(), &config),
+            Err(EvalError::StackLimitExceeded)
+        );
+        let config = EvalConfig::default().stack_limit(100);
+        assert_eq!(
+            eval_depth(&insts, &line, &Flags::default(), &config),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_long_line() {
+        // 分岐はヒープに積むので、長い入力でもスタックオーバーフローにならない
+        let line = to_chars(&format!("{}b", "a".repeat(20000)));
+        for regex in [".*", "a*", "(a|c)*b", "(a*)*b"] {
+            let insts = to_insts(regex);
+            assert_eq!(
+                eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()),
+                Ok(true),
+                "{regex}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_pike_search() {
+        let insts = to_insts("bc|(de|cd)+");
+        let first = CharClass {
+            ranges: vec![('b', 'd')],
+            negated: false,
+        };
+        for (line, expected) in [("abc", true), ("xxcdcd", true), ("xxd", false), ("", false)] {
+            let line = to_chars(line);
+            assert_eq!(
+                pike_search(&insts, &line, &Flags::default(), None),
+                Ok(expected)
+            );
+            assert_eq!(
+                pike_search(&insts, &line, &Flags::default(), Some(&first)),
+                Ok(expected)
+            );
+        }
+
+        // 空文字列にマッチするパターンは、末尾でもマッチする
+        let insts = to_insts("x*$");
+        assert_eq!(
+            pike_search(&insts, &to_chars("ab"), &Flags::default(), None),
+            Ok(true)
+        );
+    }
+
+    #[test]
+    fn test_pike_find() {
+        let config = EvalConfig::default();
+        let find = |regex: &str, line: &str, config: &EvalConfig| {
+            pike_find(
+                &to_insts(regex),
+                &to_chars(line),
+                &Flags::default(),
+                0,
+                config,
+                |_| true,
+            )
+        };
+        // 始まりが最も左のマッチを、その位置から最も短いもので返す
+        // `b`の方が先に`match`に到達するが、`abc`の方が左で始まる
+        assert_eq!(find("b|abc", "xabc", &config), Ok(Some((1, 4))));
+        assert_eq!(find("a+", "xaaa", &config), Ok(Some((1, 2))));
+        assert_eq!(
+            find("a+", "xaaa", &config.clone().longest(true)),
+            Ok(Some((1, 4)))
+        );
+        assert_eq!(find("bcd|c", "abcd", &config), Ok(Some((1, 4))));
+        assert_eq!(find("x", "abc", &config), Ok(None));
+        assert_eq!(find("bc", "abc", &config.clone().anchored(true)), Ok(None));
+
+        // 各位置から始め直さないので、マッチしない長い入力でも命令の数は入力の長さに比例する
+        let insts = to_insts("a*c");
+        let line = to_chars(&"a".repeat(2000));
+        let mut steps = 0;
+        let span = pike_run(
+            &insts,
+            &line,
+            &Flags::default(),
+            0,
+            &config,
+            &mut steps,
+            |_| true,
+        );
+        assert_eq!(span, Ok(None));
+        assert!(steps < 10 * line.len(), "{steps}");
+
+        // 先頭からだけ試す場合は、スレッドがなくなった時点で止める
+        let mut steps = 0;
+        let span = pike_from(
+            &to_insts("ab"),
+            &line,
+            &Flags::default(),
+            0,
+            false,
+            &mut steps,
+        );
+        assert_eq!(span, Ok(None));
+        assert!(steps < 10, "{steps}");
+    }
+
+    #[test]
+    fn test_memoize() {
+        // 記録しない場合は選択肢の組み合わせが指数的に増えて、上限を超える
+        let insts = to_insts("(a|aa)*c");
+        let line = to_chars(&"a".repeat(60));
+        let config = EvalConfig::default().step_limit(100000);
+        assert!(!eval_depth(&insts, &line, &Flags::default(), &config).unwrap());
+
+        let line = to_chars(&format!("{}c", "a".repeat(60)));
+        assert!(eval_depth(&insts, &line, &Flags::default(), &config).unwrap());
+
+        // 後方参照がある場合は記録しない
+        let insts = to_insts(r"(a|ab)(c|bcd)\2");
+        let line = to_chars("abcdbcd");
+        let flags = Flags::default();
+        let mut evaluator = DepthEvaluator::new(&insts, &line, &flags);
+        assert!(evaluator.eval(0, 0).unwrap().is_some());
+        assert!(!evaluator.memoize);
+        assert!(evaluator.dead.is_empty());
+    }
+
+    #[test]
+    fn test_match_at() {
+        let insts = to_insts("bc");
+        let line = to_chars("abc");
+        assert_eq!(match_at(&insts, &line, 1, &Flags::default()), Ok(Some(3)));
+        assert_eq!(match_at(&insts, &line, 0, &Flags::default()), Ok(None));
+        assert_eq!(match_at(&insts, &line, 4, &Flags::default()), Ok(None));
+
+        // 前の入力も見る
+        let insts = to_insts(r"\bc|(?<=a)b");
+        assert_eq!(match_at(&insts, &line, 1, &Flags::default()), Ok(Some(2)));
+        assert_eq!(match_at(&insts, &line, 2, &Flags::default()), Ok(None));
+        let insts = to_insts("^b");
+        assert_eq!(match_at(&insts, &line, 1, &Flags::default()), Ok(None));
+    }
+
+    #[test]
+    fn test_empty_loop() {
+        // 繰り返しの中身が空文字列にマッチしても、止まる
+        let insts = to_insts("(a*)*b");
+        for (line, expected) in [("b", true), ("aab", true), ("aa", false), ("", false)] {
+            let line = to_chars(line);
+
+            let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+            assert_eq!(res, expected);
+
+            let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+            assert_eq!(res, expected);
+        }
+
+        let insts = to_insts("(a?b?)*c");
+        let line = to_chars("abbac");
+        assert!(eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap());
+        assert!(eval_width(&insts, &line, &Flags::default()).unwrap());
+
+        // `Split`を挟まずに、入力を消費しないまま戻るジャンプ
+        let insts = [
+            Instruction::Char('a'),
+            Instruction::Save(0),
+            Instruction::Jump(1),
+            Instruction::Match,
+        ];
+        let line = to_chars("a");
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            let res = eval(
+                &insts,
+                &line,
+                strategy,
+                &Flags::default(),
+                &EvalConfig::default(),
+            );
+            assert_eq!(res, Ok(false), "{strategy:?}");
+        }
+
+        // 戻るジャンプでも、入力を消費していれば続ける
+        let insts = [
+            Instruction::Split(1, 3),
+            Instruction::Char('a'),
+            Instruction::Jump(0),
+            Instruction::Char('b'),
+            Instruction::Match,
+        ];
+        let line = to_chars("aab");
+        for strategy in [Strategy::Depth, Strategy::Width, Strategy::Pike] {
+            let res = eval(
+                &insts,
+                &line,
+                strategy,
+                &Flags::default(),
+                &EvalConfig::default(),
+            );
+            assert_eq!(res, Ok(true), "{strategy:?}");
+        }
+    }
+
+    #[test]
+    fn test_lazy() {
+        let regex = "a+?b*?c??d";
+        let line = to_chars("aaabbd");
+        let insts = to_insts(regex);
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res);
+
+        let line = to_chars("bd");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res)
+    }
+
+    #[test]
+    fn test_possessive() {
+        let line = to_chars("aaa");
+
+        let insts = to_insts("a++a");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res);
+
+        let insts = to_insts("a+a");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res);
+
+        let insts = to_insts("a*+b");
+        let line = to_chars("aab");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res)
+    }
+
     #[test]
     fn test_or() {
         let regex = "abc|123|def";
         let line = to_chars("def");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res);
 
         let line = to_chars("ab3");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(!res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(!res)
     }
 
@@ -289,40 +2083,243 @@ mod tests {
         let line = to_chars("ab");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res);
 
         let line = to_chars("a");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(!res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(!res)
     }
 
+    #[test]
+    fn test_any_backtrack() {
+        // `.`が入力の終わりで失敗しても、残りの分岐を試す
+        for (regex, line) in [
+            ("a.|xy", "xy"),
+            ("a.|a", "a"),
+            ("(?:a.)?a", "a"),
+            ("a.|b", "b"),
+        ] {
+            let insts = to_insts(regex);
+            let line = to_chars(line);
+
+            let depth =
+                eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+            let width = eval_width(&insts, &line, &Flags::default()).unwrap();
+            assert!(depth, "{regex}");
+            assert_eq!(depth, width, "{regex}");
+        }
+    }
+
+    #[test]
+    fn test_dot_all() {
+        let regex = "a.b";
+        let line = to_chars("a\nb");
+        let insts = to_insts(regex);
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res);
+
+        let flags = Flags {
+            dot_all: true,
+            ..Default::default()
+        };
+
+        let res = eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &flags).unwrap();
+        assert!(res)
+    }
+
+    #[test]
+    fn test_save() {
+        let insts = vec![
+            Instruction::Save(0),
+            Instruction::Char('a'),
+            Instruction::Save(1),
+            Instruction::Match,
+        ];
+        let line = to_chars("ab");
+
+        // `Save`はマッチするかどうかには影響しない
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res);
+
+        // 深さ優先探索では記録した位置が残る
+        let flags = Flags::default();
+        let mut evaluator = DepthEvaluator::new(&insts, &line, &flags);
+        assert_eq!(evaluator.eval(0, 0).unwrap(), Some(1));
+        assert_eq!(evaluator.captures, vec![Some(0), Some(1)]);
+        assert_eq!(evaluator.capture(0), Some((0, 1)));
+    }
+
+    #[test]
+    fn test_captures() {
+        let insts = to_insts(r"([0-9]+)-([0-9]+)");
+        let line = to_chars("12-34");
+        let captures = eval_captures(&insts, &line, 0, &Flags::default(), &EvalConfig::default());
+        assert_eq!(
+            captures.unwrap(),
+            Some(vec![Some((0, 5)), Some((0, 2)), Some((3, 5))])
+        );
+
+        // 使われなかったグループは`None`。失敗した分岐で記録した位置は残らない
+        let insts = to_insts("(a)x|(b)");
+        let line = to_chars("ab");
+        let captures = eval_captures(&insts, &line, 1, &Flags::default(), &EvalConfig::default());
+        assert_eq!(
+            captures.unwrap(),
+            Some(vec![Some((1, 2)), None, Some((1, 2))])
+        );
+
+        let line = to_chars("c");
+        let captures = eval_captures(&insts, &line, 0, &Flags::default(), &EvalConfig::default());
+        assert_eq!(captures.unwrap(), None);
+    }
+
+    #[test]
+    fn test_back_ref() {
+        let regex = r"(ab)\1";
+        let insts = to_insts(regex);
+
+        let line = to_chars("abab");
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let line = to_chars("abcd");
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        // 後方参照は幅優先探索では使えない
+        let err = eval_width(&insts, &line, &Flags::default()).unwrap_err();
+        assert_eq!(err, EvalError::BackRefNotSupported);
+
+        // 失敗した分岐でキャプチャした位置は残らない
+        let regex = r"((a)b|ac)\2";
+        let insts = to_insts(regex);
+
+        let line = to_chars("aca");
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+    }
+
     #[test]
     fn test_start() {
         let regex = "^abc(^def|123)";
         let line = to_chars("abc123");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res);
 
         let line = to_chars("abcdef");
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res)
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let regex = "ab";
+        let line = to_chars("Ab");
+        let insts = to_insts(regex);
+        let flags = Flags {
+            case_insensitive: true,
+            ..Default::default()
+        };
+
+        let res = eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &flags).unwrap();
+        assert!(res);
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(!res)
+    }
+
+    #[test]
+    fn test_class() {
+        let regex = "[a-c]+d";
+        let line = to_chars("cabd");
+        let insts = to_insts(regex);
+        let flags = Flags::default();
+
+        let res = eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &flags).unwrap();
+        assert!(res);
+
+        let line = to_chars("Cabd");
+
+        let res = eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap();
+        assert!(!res);
+
+        let res = eval_width(&insts, &line, &flags).unwrap();
+        assert!(!res);
+
+        let flags = Flags {
+            case_insensitive: true,
+            ..Default::default()
+        };
+
+        let res = eval_depth(&insts, &line, &flags, &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &flags).unwrap();
+        assert!(res)
+    }
+
+    #[test]
+    fn test_word_boundary() {
+        assert!(is_word_boundary(&to_chars("ab"), 0));
+        assert!(!is_word_boundary(&to_chars("ab"), 1));
+        assert!(is_word_boundary(&to_chars("ab"), 2));
+        assert!(!is_word_boundary(&to_chars(" "), 0));
+        assert!(!is_word_boundary(&[], 0));
+
+        let regex = r"ab\b";
+        let insts = to_insts(regex);
+
+        let line = to_chars("ab c");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
+        assert!(res);
+
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
+        assert!(res);
+
+        let line = to_chars("abc");
+
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(!res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(!res)
     }
 
@@ -332,18 +2329,18 @@ mod tests {
         let line = to_chars("abc123");
         let insts = to_insts(regex);
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(res);
 
         let line = to_chars("abc123def");
 
-        let res = eval_depth(&insts, &line, 0, 0).unwrap();
+        let res = eval_depth(&insts, &line, &Flags::default(), &EvalConfig::default()).unwrap();
         assert!(!res);
 
-        let res = eval_width(&insts, &line).unwrap();
+        let res = eval_width(&insts, &line, &Flags::default()).unwrap();
         assert!(!res)
     }
 }
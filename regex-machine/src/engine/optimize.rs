@@ -0,0 +1,284 @@
+use super::{parser::Ast, CharClass};
+use alloc::{boxed::Box, vec, vec::Vec};
+
+/// `+`,`*`,`?`のうち、最長一致のもの
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Quantifier {
+    Plus,
+    Star,
+    Question,
+}
+
+impl Quantifier {
+    fn of(ast: &Ast) -> Option<Self> {
+        match ast {
+            Ast::Plus(_) => Some(Quantifier::Plus),
+            Ast::Star(_) => Some(Quantifier::Star),
+            Ast::Question(_) => Some(Quantifier::Question),
+            _ => None,
+        }
+    }
+
+    fn wrap(self, ast: Ast) -> Ast {
+        let ast = Box::new(ast);
+        match self {
+            Quantifier::Plus => Ast::Plus(ast),
+            Quantifier::Star => Ast::Star(ast),
+            Quantifier::Question => Ast::Question(ast),
+        }
+    }
+}
+
+/// `outer`で`inner`を繰り返す。`inner`も繰り返しの場合は1つにまとめる
+///
+/// `(?:a+)+`は`a+`、`(?:a?)?`は`a?`と同じで、種類が違う場合は`(?:a+)?`のようにどれも`a*`と同じになる。
+/// キャプチャグループで囲まれている場合は、まとめるとキャプチャする範囲が変わるのでそのままにする
+fn merge(outer: Quantifier, inner: Ast) -> Ast {
+    let Some(q) = Quantifier::of(&inner) else {
+        return outer.wrap(inner);
+    };
+
+    let merged = if q == outer { q } else { Quantifier::Star };
+    match inner {
+        Ast::Plus(e) | Ast::Star(e) | Ast::Question(e) => merged.wrap(*e),
+        inner => outer.wrap(inner),
+    }
+}
+
+/// `e1|e2`の`Or`の連なりを、選択肢の列にする
+///
+/// `Or`は右に入れ子になるので、選択肢が多くても再帰しないようにループでたどる
+fn or_branches(e1: Ast, e2: Ast) -> Vec<Ast> {
+    let mut branches = vec![e1];
+    let mut rest = e2;
+    while let Ast::Or(e1, e2) = rest {
+        branches.push(*e1);
+        rest = *e2;
+    }
+    branches.push(rest);
+    branches
+}
+
+/// 選択肢の列を、右に入れ子になった`Or`の連なりに戻す
+fn join_or(branches: Vec<Ast>) -> Ast {
+    branches
+        .into_iter()
+        .rev()
+        .reduce(|ast, branch| Ast::Or(Box::new(branch), Box::new(ast)))
+        .unwrap_or(Ast::Seq(Vec::new()))
+}
+
+/// 意味を変えずにAstを簡単にする
+///
+/// - `(?:a*)*`や`(?:a+)+`のような繰り返しの繰り返しを1つにまとめる
+/// - 入れ子になった`Seq`を平らにし、要素が1つだけの`Seq`は中身に置き換える
+pub fn simplify(ast: Ast) -> Ast {
+    match ast {
+        Ast::Plus(e) => merge(Quantifier::Plus, simplify(*e)),
+        Ast::Star(e) => merge(Quantifier::Star, simplify(*e)),
+        Ast::Question(e) => merge(Quantifier::Question, simplify(*e)),
+        Ast::PlusLazy(e) => Ast::PlusLazy(Box::new(simplify(*e))),
+        Ast::StarLazy(e) => Ast::StarLazy(Box::new(simplify(*e))),
+        Ast::QuestionLazy(e) => Ast::QuestionLazy(Box::new(simplify(*e))),
+        Ast::Repeat(e, min, max) => Ast::Repeat(Box::new(simplify(*e)), min, max),
+        Ast::RepeatLazy(e, min, max) => Ast::RepeatLazy(Box::new(simplify(*e)), min, max),
+        Ast::Or(e1, e2) => join_or(or_branches(*e1, *e2).into_iter().map(simplify).collect()),
+        Ast::Seq(seq) => {
+            let mut flat = Vec::new();
+            for e in seq {
+                match simplify(e) {
+                    Ast::Seq(inner) => flat.extend(inner),
+                    e => flat.push(e),
+                }
+            }
+            if flat.len() == 1 {
+                flat.swap_remove(0)
+            } else {
+                Ast::Seq(flat)
+            }
+        }
+        Ast::Atomic(e) => Ast::Atomic(Box::new(simplify(*e))),
+        Ast::LookAhead(e) => Ast::LookAhead(Box::new(simplify(*e))),
+        Ast::NegLookAhead(e) => Ast::NegLookAhead(Box::new(simplify(*e))),
+        Ast::LookBehind(e) => Ast::LookBehind(Box::new(simplify(*e))),
+        Ast::NegLookBehind(e) => Ast::NegLookBehind(Box::new(simplify(*e))),
+        Ast::Capture(n, e) => Ast::Capture(n, Box::new(simplify(*e))),
+        ast => ast,
+    }
+}
+
+/// 1文字にだけマッチする選択肢を、文字クラスとして返す
+fn as_class(ast: &Ast) -> Option<CharClass> {
+    match ast {
+        Ast::Char(c) => Some(CharClass {
+            ranges: vec![(*c, *c)],
+            negated: false,
+        }),
+        Ast::Class(class) if !class.negated => Some(class.clone()),
+        _ => None,
+    }
+}
+
+/// `a|b|c`のように1文字だけの選択肢が並んでいるとき、1つの文字クラスにまとめる
+///
+/// 選択肢の優先順位を変えないよう、隣り合っているものだけをまとめる
+pub fn fold_char_or(ast: Ast) -> Ast {
+    match ast {
+        Ast::Or(e1, e2) => {
+            let mut folded = Vec::new();
+            for branch in or_branches(*e1, *e2).into_iter().map(fold_char_or) {
+                let merged = folded.last().and_then(as_class).zip(as_class(&branch));
+                if let Some((mut class, other)) = merged {
+                    class.ranges.extend(other.ranges);
+                    folded.pop();
+                    folded.push(Ast::Class(class));
+                } else {
+                    folded.push(branch);
+                }
+            }
+
+            join_or(folded)
+        }
+        Ast::Plus(e) => Ast::Plus(Box::new(fold_char_or(*e))),
+        Ast::Star(e) => Ast::Star(Box::new(fold_char_or(*e))),
+        Ast::Question(e) => Ast::Question(Box::new(fold_char_or(*e))),
+        Ast::PlusLazy(e) => Ast::PlusLazy(Box::new(fold_char_or(*e))),
+        Ast::StarLazy(e) => Ast::StarLazy(Box::new(fold_char_or(*e))),
+        Ast::QuestionLazy(e) => Ast::QuestionLazy(Box::new(fold_char_or(*e))),
+        Ast::Repeat(e, min, max) => Ast::Repeat(Box::new(fold_char_or(*e)), min, max),
+        Ast::RepeatLazy(e, min, max) => Ast::RepeatLazy(Box::new(fold_char_or(*e)), min, max),
+        Ast::Seq(seq) => Ast::Seq(seq.into_iter().map(fold_char_or).collect()),
+        Ast::Atomic(e) => Ast::Atomic(Box::new(fold_char_or(*e))),
+        Ast::LookAhead(e) => Ast::LookAhead(Box::new(fold_char_or(*e))),
+        Ast::NegLookAhead(e) => Ast::NegLookAhead(Box::new(fold_char_or(*e))),
+        Ast::LookBehind(e) => Ast::LookBehind(Box::new(fold_char_or(*e))),
+        Ast::NegLookBehind(e) => Ast::NegLookBehind(Box::new(fold_char_or(*e))),
+        Ast::Capture(n, e) => Ast::Capture(n, Box::new(fold_char_or(*e))),
+        ast => ast,
+    }
+}
+
+/// パースしたAstに、すべての最適化をかける
+pub fn optimize(ast: Ast) -> Ast {
+    fold_char_or(simplify(ast))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{codegen::get_code, parser::parse};
+
+    /// `before`を簡単にしたコードが、`after`のコードと同じか
+    fn assert_same_code(before: &str, after: &str) {
+        let simplified = simplify(parse(before).unwrap());
+        let expected = parse(after).unwrap();
+        assert_eq!(
+            get_code(&simplified).unwrap(),
+            get_code(&expected).unwrap(),
+            "{before} -> {after}"
+        );
+    }
+
+    #[test]
+    fn nested_quantifier() {
+        assert_same_code(r"(?:a*)*", r"a*");
+        assert_same_code(r"(?:a+)+", r"a+");
+        assert_same_code(r"(?:a?)?", r"a?");
+        assert_same_code(r"(?:a+)?", r"a*");
+        assert_same_code(r"(?:a?)+b", r"a*b");
+        assert_same_code(r"(?:(?:a+)*)+", r"a*");
+    }
+
+    #[test]
+    fn keep_quantifier() {
+        // 最短一致や、繰り返しではない中身はそのまま
+        assert_same_code(r"(a*?)*", r"(a*?)*");
+        assert_same_code(r"(ab*)*", r"(ab*)*");
+        // キャプチャグループの中の繰り返しはまとめない
+        assert_same_code(r"(a+)?", r"(a+)?");
+        assert_same_code(r"((a+)*)+", r"((a+)*)+");
+        assert_same_code(r"a??", r"a??");
+    }
+
+    #[test]
+    fn fold_or() {
+        let ast = optimize(parse(r"a|b|c|d").unwrap());
+
+        assert_eq!(
+            ast,
+            Ast::Class(CharClass {
+                ranges: vec![('a', 'a'), ('b', 'b'), ('c', 'c'), ('d', 'd')],
+                negated: false
+            })
+        );
+        let code = get_code(&ast).unwrap();
+        let line = "c".chars().collect::<Vec<_>>();
+        assert!(crate::engine::evaluator::eval_depth(
+            &code,
+            &line,
+            &Default::default(),
+            &Default::default()
+        )
+        .unwrap());
+
+        // 隣り合っている1文字の選択肢だけをまとめる
+        let ast = optimize(parse(r"a|[x-z]|bc|d|e").unwrap());
+        assert_eq!(
+            ast,
+            Ast::Or(
+                Box::new(Ast::Class(CharClass {
+                    ranges: vec![('a', 'a'), ('x', 'z')],
+                    negated: false
+                })),
+                Box::new(Ast::Or(
+                    Box::new(Ast::Seq(vec![Ast::Char('b'), Ast::Char('c')])),
+                    Box::new(Ast::Class(CharClass {
+                        ranges: vec![('d', 'd'), ('e', 'e')],
+                        negated: false
+                    }))
+                ))
+            )
+        );
+
+        // 否定された文字クラスはまとめない
+        let ast = optimize(parse(r"[^a]|b").unwrap());
+        assert!(matches!(ast, Ast::Or(..)));
+    }
+
+    #[test]
+    fn flatten_seq() {
+        let ast = simplify(parse(r"(?:a*)*").unwrap());
+
+        assert_eq!(ast, Ast::Star(Box::new(Ast::Char('a'))));
+
+        // キャプチャグループで囲まれている繰り返しはまとめない
+        let ast = simplify(parse(r"(a+)?").unwrap());
+        assert_eq!(
+            ast,
+            Ast::Question(Box::new(Ast::Capture(
+                1,
+                Box::new(Ast::Plus(Box::new(Ast::Char('a'))))
+            )))
+        );
+
+        let ast = simplify(Ast::Seq(vec![
+            Ast::Char('a'),
+            Ast::Seq(vec![Ast::Char('b'), Ast::Char('c')]),
+        ]));
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Char('a'), Ast::Char('b'), Ast::Char('c')])
+        );
+    }
+
+    #[test]
+    fn long_or() {
+        // 選択肢が多くても、スタックオーバーフローにならない
+        let expr = vec!["ab"; 10000].join("|");
+        let ast = optimize(parse(&expr).unwrap());
+        let Ast::Or(e1, _) = &ast else {
+            panic!("{ast:?}");
+        };
+        assert_eq!(**e1, Ast::Seq(vec![Ast::Char('a'), Ast::Char('b')]));
+    }
+}
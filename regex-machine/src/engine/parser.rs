@@ -1,11 +1,23 @@
-use std::{
+use super::{class, Category, CharClass, Flags, Property};
+use crate::helper::HashMap;
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use core::{
     error::Error,
     fmt::{self, Display},
+    iter::{Enumerate, Peekable},
     mem::take,
+    str::Chars,
 };
 
 /// 正規表現のAst
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Ast {
     /// 1文字
     Char(char),
@@ -15,6 +27,16 @@ pub enum Ast {
     Star(Box<Ast>),
     /// 高々1回の繰り返し
     Question(Box<Ast>),
+    /// 1回以上の繰り返し(最短一致)
+    PlusLazy(Box<Ast>),
+    /// 0回以上の繰り返し(最短一致)
+    StarLazy(Box<Ast>),
+    /// 高々1回の繰り返し(最短一致)
+    QuestionLazy(Box<Ast>),
+    /// `{n,m}`の繰り返し。上限がない場合は`None`
+    Repeat(Box<Ast>, usize, Option<usize>),
+    /// `{n,m}?`の繰り返し(最短一致)
+    RepeatLazy(Box<Ast>, usize, Option<usize>),
     /// どっちか
     Or(Box<Ast>, Box<Ast>),
     /// 複数の正規表現をまとめたもの
@@ -25,10 +47,165 @@ pub enum Ast {
     Start,
     /// 行末
     End,
+    /// `(?m)`のときの`^`
+    LineStart,
+    /// `(?m)`のときの`$`
+    LineEnd,
+    /// 単語境界
+    WordBoundary,
+    /// `[...]`のような文字クラス
+    Class(CharClass),
+    /// `\p{L}`のようなUnicodeの一般カテゴリ
+    Property(Property),
+    /// 一度マッチしたら、中身へバックトラックしない
+    Atomic(Box<Ast>),
+    /// 肯定先読み。入力は消費しない
+    LookAhead(Box<Ast>),
+    /// 否定先読み。入力は消費しない
+    NegLookAhead(Box<Ast>),
+    /// 肯定後読み。入力は消費しない
+    LookBehind(Box<Ast>),
+    /// 否定後読み。入力は消費しない
+    NegLookBehind(Box<Ast>),
+    /// `usize`番目のキャプチャグループ。1から数える
+    Capture(usize, Box<Ast>),
+    /// `\1`のような後方参照
+    BackRef(usize),
+}
+
+/// Astを正規表現の文字列に戻す
+///
+/// 括弧やエスケープは必要なところにだけ付ける。`(?i)`のようなフラグはAstに含まれないので出力しない
+impl Display for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Ast::Seq(seq) => {
+                let mut prev: Option<&Ast> = None;
+                for ast in seq {
+                    match (prev, ast) {
+                        // `\1`の直後の数字は`\10`のように番号の続きとして読まれてしまう
+                        (Some(Ast::BackRef(_)), Ast::Char(c)) if c.is_ascii_digit() => {
+                            write!(f, "\\x{:02x}", *c as u32)?
+                        }
+                        _ => fmt_item(ast, f)?,
+                    }
+                    prev = Some(ast);
+                }
+                Ok(())
+            }
+            // `fold_or`は右に入れ子にするので、左にある`|`だけ括弧が要る
+            Ast::Or(left, right) => {
+                if let Ast::Or(..) = **left {
+                    write!(f, "(?:{left})|{right}")
+                } else {
+                    write!(f, "{left}|{right}")
+                }
+            }
+            _ => fmt_item(self, f),
+        }
+    }
+}
+
+/// `Seq`の要素として書き出す。`|`や`Seq`は`(?:...)`で囲む
+fn fmt_item(ast: &Ast, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match ast {
+        Ast::Char(c) if is_special(c) => write!(f, "\\{c}"),
+        Ast::Char(c) => write!(f, "{c}"),
+        Ast::Plus(ast) => fmt_repeated(ast, "+", f),
+        Ast::Star(ast) => fmt_repeated(ast, "*", f),
+        Ast::Question(ast) => fmt_repeated(ast, "?", f),
+        Ast::PlusLazy(ast) => fmt_repeated(ast, "+?", f),
+        Ast::StarLazy(ast) => fmt_repeated(ast, "*?", f),
+        Ast::QuestionLazy(ast) => fmt_repeated(ast, "??", f),
+        Ast::Repeat(ast, min, max) => fmt_repeated(ast, &fmt_range(*min, *max), f),
+        Ast::RepeatLazy(ast, min, max) => {
+            fmt_repeated(ast, &format!("{}?", fmt_range(*min, *max)), f)
+        }
+        Ast::Or(..) | Ast::Seq(_) => write!(f, "(?:{ast})"),
+        Ast::Any => write!(f, "."),
+        Ast::Start | Ast::LineStart => write!(f, "^"),
+        Ast::End | Ast::LineEnd => write!(f, "$"),
+        Ast::WordBoundary => write!(f, "\\b"),
+        Ast::Class(class) => fmt_class(class, f),
+        Ast::Property(prop) => write!(f, "{prop}"),
+        // `a*+`のような繰り返しは、`(?>a*)`とはAstが異なる
+        Ast::Atomic(ast) if is_greedy(ast) => {
+            fmt_item(ast, f)?;
+            write!(f, "+")
+        }
+        Ast::Atomic(ast) => write!(f, "(?>{ast})"),
+        Ast::LookAhead(ast) => write!(f, "(?={ast})"),
+        Ast::NegLookAhead(ast) => write!(f, "(?!{ast})"),
+        Ast::LookBehind(ast) => write!(f, "(?<={ast})"),
+        Ast::NegLookBehind(ast) => write!(f, "(?<!{ast})"),
+        Ast::Capture(_, ast) => write!(f, "({ast})"),
+        Ast::BackRef(n) => write!(f, "\\{n}"),
+    }
+}
+
+/// `ast`を繰り返す`op`を付けて書き出す
+fn fmt_repeated(ast: &Ast, op: &str, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    // `a+`の後に`?`や`+`を続けると、最短一致やバックトラックしない繰り返しになってしまう
+    if is_greedy(ast) && (op.starts_with('+') || op.starts_with('?')) {
+        write!(f, "(?:")?;
+        fmt_item(ast, f)?;
+        write!(f, "){op}")
+    } else {
+        fmt_item(ast, f)?;
+        write!(f, "{op}")
+    }
+}
+
+/// `+`,`*`,`?`,`{n,m}`による最長一致の繰り返しか
+fn is_greedy(ast: &Ast) -> bool {
+    matches!(
+        ast,
+        Ast::Plus(_) | Ast::Star(_) | Ast::Question(_) | Ast::Repeat(..)
+    )
+}
+
+/// `{n,m}`の繰り返し指定を文字列にする
+fn fmt_range(min: usize, max: Option<usize>) -> String {
+    match max {
+        Some(max) if max == min => format!("{{{min}}}"),
+        Some(max) => format!("{{{min},{max}}}"),
+        None => format!("{{{min},}}"),
+    }
+}
+
+/// 文字クラスの外でエスケープが必要な文字か
+fn is_special(c: &char) -> bool {
+    matches!(
+        c,
+        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' | '[' | ']' | '{' | '}' | '^' | '$'
+    )
+}
+
+/// 文字クラスを`[...]`の形で書き出す。クラスの中で意味を持つ記号はエスケープする
+fn fmt_class(class: &CharClass, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let escape = |c: &char| {
+        if matches!(c, '\\' | ']' | '[' | '^' | '-' | '&') {
+            format!("\\{c}")
+        } else {
+            c.to_string()
+        }
+    };
+    write!(f, "[")?;
+    if class.negated {
+        write!(f, "^")?;
+    }
+    for (lo, hi) in &class.ranges {
+        if lo == hi {
+            write!(f, "{}", escape(lo))?;
+        } else {
+            write!(f, "{}-{}", escape(lo), escape(hi))?;
+        }
+    }
+    write!(f, "]")
 }
 
 /// 正規表現をパースする際のエラー
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ParseError {
     /// 誤ったエスケープシーケンス
     InvalidEscape(usize, char),
@@ -38,8 +215,26 @@ pub enum ParseError {
     NoPrev(usize),
     /// 閉じ括弧`)`がない
     NoRightParen,
-    /// 空っぽ
-    Empty,
+    /// `{n}`のような繰り返し指定が不正
+    InvalidRepeat(usize),
+    /// `(?i)`のようなフラグ指定が不正
+    InvalidFlag(usize),
+    /// `(?<`の後が不正なグループ
+    InvalidGroup(usize),
+    /// `[z-a]`のような不正な文字クラス
+    InvalidClass(usize),
+    /// `[[:foo:]]`のような存在しないPOSIX文字クラス
+    UnknownClass(usize, String),
+    /// 文字クラスの`]`がない
+    UnclosedClass(usize),
+    /// まだ開かれていないグループへの後方参照
+    InvalidBackRef(usize),
+    /// `\k<foo>`のような存在しない名前のグループへの後方参照
+    UnknownGroupName(usize, String),
+    /// `{n}`で展開される量が上限を超えた
+    RepeatTooLarge(usize),
+    /// `(`の入れ子が深すぎる
+    NestingTooDeep(usize),
 }
 
 impl Display for ParseError {
@@ -54,12 +249,85 @@ impl Display for ParseError {
             ParseError::NoPrev(pos) => {
                 write!(f, "ParseError: no previous expression: pos = {}", pos)
             }
+            ParseError::InvalidRepeat(pos) => {
+                write!(f, "ParseError: invalid repetition: pos = {}", pos)
+            }
+            ParseError::RepeatTooLarge(pos) => {
+                write!(f, "ParseError: repetition too large: pos = {}", pos)
+            }
+            ParseError::NestingTooDeep(pos) => {
+                write!(f, "ParseError: nesting too deep: pos = {}", pos)
+            }
+            ParseError::InvalidFlag(pos) => {
+                write!(f, "ParseError: invalid flag: pos = {}", pos)
+            }
+            ParseError::InvalidGroup(pos) => {
+                write!(f, "ParseError: invalid group: pos = {}", pos)
+            }
+            ParseError::InvalidClass(pos) => {
+                write!(f, "ParseError: invalid character class: pos = {}", pos)
+            }
+            ParseError::UnknownClass(pos, name) => {
+                write!(
+                    f,
+                    "ParseError: unknown character class: pos = {}, name = {}",
+                    pos, name
+                )
+            }
+            ParseError::UnclosedClass(pos) => {
+                write!(f, "ParseError: unclosed character class: pos = {}", pos)
+            }
+            ParseError::InvalidBackRef(pos) => {
+                write!(f, "ParseError: invalid back reference: pos = {}", pos)
+            }
+            ParseError::UnknownGroupName(pos, name) => {
+                write!(
+                    f,
+                    "ParseError: unknown group name: pos = {}, name = {}",
+                    pos, name
+                )
+            }
             ParseError::NoRightParen => {
                 write!(f, "ParseError: no right parenthesis")
             }
-            ParseError::Empty => {
-                write!(f, "ParseError: empty expression")
-            }
+        }
+    }
+}
+
+impl ParseError {
+    /// エラーが起きた位置
+    ///
+    /// `)`が足りないときはパターンの末尾を指す。位置を持たないエラーの場合は`None`
+    pub fn pos(&self, expr: &str) -> Option<usize> {
+        match self {
+            ParseError::InvalidEscape(pos, _)
+            | ParseError::InvalidRightParen(pos)
+            | ParseError::NoPrev(pos)
+            | ParseError::InvalidRepeat(pos)
+            | ParseError::RepeatTooLarge(pos)
+            | ParseError::NestingTooDeep(pos)
+            | ParseError::InvalidFlag(pos)
+            | ParseError::InvalidGroup(pos)
+            | ParseError::InvalidClass(pos)
+            | ParseError::UnknownClass(pos, _)
+            | ParseError::UnclosedClass(pos)
+            | ParseError::InvalidBackRef(pos)
+            | ParseError::UnknownGroupName(pos, _) => Some(*pos),
+            ParseError::NoRightParen => Some(expr.chars().count()),
+        }
+    }
+
+    /// エラーメッセージに加えて、パターンとエラー位置を指す`^`を並べた文字列を返す
+    ///
+    /// ```text
+    /// ParseError: invalid right parenthesis: pos = 3
+    /// abc)
+    ///    ^
+    /// ```
+    pub fn render(&self, expr: &str) -> String {
+        match self.pos(expr) {
+            Some(pos) => format!("{self}\n{expr}\n{}^", " ".repeat(pos)),
+            None => format!("{self}\n{expr}"),
         }
     }
 }
@@ -67,10 +335,55 @@ impl Display for ParseError {
 // ParseErrorが`Debug`と`Display`を実装しているため自動で実装される
 impl Error for ParseError {}
 
+/// 元のパターンを添えた`ParseError`
+///
+/// `RegexError`として返した後でも、エラー位置をパターンと並べて表示できる
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    /// パースしようとしたパターン
+    pub pattern: String,
+    pub error: ParseError,
+}
+
+impl ParseError {
+    /// パターンを添えた`PatternError`に変換する
+    pub fn with_pattern(self, expr: &str) -> PatternError {
+        PatternError {
+            pattern: expr.to_string(),
+            error: self,
+        }
+    }
+}
+
+impl Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.error.render(&self.pattern))
+    }
+}
+
+impl Error for PatternError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
 /// 特殊文字のエスケープ
 fn parse_escape(pos: usize, c: char) -> Result<Ast, ParseError> {
     match c {
-        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' => Ok(Ast::Char(c)),
+        '\\' | '(' | ')' | '|' | '+' | '*' | '?' | '.' | '[' | ']' | '{' | '}' | '^' | '$' => {
+            Ok(Ast::Char(c))
+        }
+        'b' => Ok(Ast::WordBoundary),
+        // 入力全体の先頭と末尾。`^`や`$`と違い、フラグの影響を受けない
+        'A' => Ok(Ast::Start),
+        'z' => Ok(Ast::End),
+        // 末尾か、末尾の改行の直前
+        'Z' => {
+            let end = Ast::Seq(vec![Ast::Question(Box::new(Ast::Char('\n'))), Ast::End]);
+            Ok(Ast::LookAhead(Box::new(end)))
+        }
+        // `(?x)`のときに空白を書くためのエスケープ
+        _ if c.is_whitespace() => Ok(Ast::Char(c)),
         _ => {
             let err = ParseError::InvalidEscape(pos, c);
             Err(err)
@@ -78,10 +391,249 @@ fn parse_escape(pos: usize, c: char) -> Result<Ast, ParseError> {
     }
 }
 
+/// `\xHH`の`HH`を読み取り、対応する文字に変換する
+///
+/// `pos`は`x`の位置で、`\x`は読み込み済みとする
+fn parse_hex_escape(chars: &mut CharIter, pos: usize) -> Result<char, ParseError> {
+    let mut code = 0;
+    for _ in 0..2 {
+        let Some(d) = chars.next().and_then(|(_, c)| c.to_digit(16)) else {
+            return Err(ParseError::InvalidEscape(pos, 'x'));
+        };
+        code = code * 16 + d;
+    }
+    // 2桁の16進数は必ず有効な文字になる
+    char::from_u32(code).ok_or(ParseError::InvalidEscape(pos, 'x'))
+}
+
+/// `\p{L}`の`{L}`を読み取る
+///
+/// `pos`は`p`の位置で、`\p`は読み込み済みとする。`c`が`P`の場合は否定する
+fn parse_property(chars: &mut CharIter, pos: usize, c: char) -> Result<Property, ParseError> {
+    let Some((_, '{')) = chars.next() else {
+        return Err(ParseError::InvalidEscape(pos, c));
+    };
+
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some((_, '}')) => break,
+            Some((_, c)) if c.is_ascii_alphabetic() || c == '_' => name.push(c),
+            _ => return Err(ParseError::InvalidEscape(pos, c)),
+        }
+    }
+
+    let Some(category) = Category::from_name(&name) else {
+        return Err(ParseError::UnknownClass(pos, name));
+    };
+    Ok(Property {
+        category,
+        negated: c == 'P',
+    })
+}
+
+/// `(?<name>...)`や`\k<name>`の`name`を読み取る
+///
+/// `>`は読み込まない。英数字と`_`からなり、数字で始まらない名前でなければ`None`
+fn parse_group_name(chars: &mut CharIter) -> Option<String> {
+    let mut name = String::new();
+    while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_alphanumeric() || *c == '_') {
+        name.push(c);
+    }
+    let is_valid = name.chars().next().is_some_and(|c| !c.is_ascii_digit());
+    is_valid.then_some(name)
+}
+
+/// `\u{...}`の`{...}`を読み取り、対応する文字に変換する
+///
+/// `pos`は`u`の位置で、`\u`は読み込み済みとする
+fn parse_unicode_escape(chars: &mut CharIter, pos: usize) -> Result<char, ParseError> {
+    let err = ParseError::InvalidEscape(pos, 'u');
+
+    let Some((_, '{')) = chars.next() else {
+        return Err(err);
+    };
+
+    let mut code: Option<u32> = None;
+    loop {
+        match chars.next() {
+            Some((_, '}')) => break,
+            Some((_, c)) => {
+                let d = c.to_digit(16).ok_or(err.clone())?;
+                let n = code.unwrap_or(0).checked_mul(16);
+                code = Some(n.and_then(|n| n.checked_add(d)).ok_or(err.clone())?);
+            }
+            // `}`で閉じられていない
+            None => return Err(err),
+        }
+    }
+
+    // サロゲートや範囲外のコードポイントは文字にできない
+    code.and_then(char::from_u32).ok_or(err)
+}
+
+/// 文字クラス中のエスケープを文字に変換する
+///
+/// `pos`は`\\`の次の文字の位置で、その文字は読み込み済みとする
+fn parse_class_escape(chars: &mut CharIter, pos: usize, c: char) -> Result<char, ParseError> {
+    match c {
+        'x' => parse_hex_escape(chars, pos),
+        'u' => parse_unicode_escape(chars, pos),
+        // 記号はそのままの文字として扱う
+        _ if !c.is_alphanumeric() => Ok(c),
+        _ => Err(ParseError::InvalidEscape(pos, c)),
+    }
+}
+
+/// 文字クラス中の1文字を読み取る。エスケープされている場合は変換する
+///
+/// `pos`は文字クラスの`[`の位置
+fn parse_class_char(chars: &mut CharIter, pos: usize) -> Result<(usize, char), ParseError> {
+    match chars.next() {
+        Some((_, '\\')) => {
+            let Some((idx, c)) = chars.next() else {
+                return Err(ParseError::UnclosedClass(pos));
+            };
+            Ok((idx, parse_class_escape(chars, idx, c)?))
+        }
+        Some((idx, c)) => Ok((idx, c)),
+        None => Err(ParseError::UnclosedClass(pos)),
+    }
+}
+
+/// `[:alpha:]`のようなPOSIX文字クラスを読み取り、`class`に追加する
+///
+/// `pos`は`[:`の`[`の位置で、`[:`は読み込み済みとする。`class_pos`は外側の文字クラスの`[`の位置
+fn parse_posix_class(
+    class: &mut CharClass,
+    chars: &mut CharIter,
+    pos: usize,
+    class_pos: usize,
+) -> Result<(), ParseError> {
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some((_, ':')) => break,
+            Some((_, c)) if c.is_ascii_alphabetic() => name.push(c),
+            Some(_) => return Err(ParseError::InvalidClass(pos)),
+            None => return Err(ParseError::UnclosedClass(class_pos)),
+        }
+    }
+    match chars.next() {
+        Some((_, ']')) => {}
+        Some(_) => return Err(ParseError::InvalidClass(pos)),
+        None => return Err(ParseError::UnclosedClass(class_pos)),
+    };
+
+    let Some(ranges) = class::posix_class(&name) else {
+        return Err(ParseError::UnknownClass(pos, name));
+    };
+    class.ranges.extend_from_slice(ranges);
+    Ok(())
+}
+
+/// `[...]`をAstに変換する
+///
+/// `pos`は`[`の位置で、`[`は読み込み済みとする
+fn parse_class(chars: &mut CharIter, pos: usize) -> Result<Ast, ParseError> {
+    Ok(Ast::Class(parse_class_set(chars, pos)?))
+}
+
+/// `[`の後から対応する`]`までを読み取る
+///
+/// `pos`は`[`の位置で、`[`は読み込み済みとする
+fn parse_class_set(chars: &mut CharIter, pos: usize) -> Result<CharClass, ParseError> {
+    let negated = chars.next_if(|(_, c)| *c == '^').is_some();
+    let class = parse_class_items(chars, pos)?;
+    Ok(CharClass { negated, ..class })
+}
+
+/// 文字クラスの中身を`]`まで読み取る。`^`による否定は読み込み済みとする
+///
+/// `pos`は文字クラスの`[`の位置
+fn parse_class_items(chars: &mut CharIter, pos: usize) -> Result<CharClass, ParseError> {
+    let mut class = CharClass::default();
+
+    loop {
+        match chars.peek() {
+            Some((_, ']')) => {
+                chars.next();
+                break;
+            }
+            Some((_, '&')) => {
+                let mut ahead = chars.clone();
+                ahead.next();
+                if let Some((_, '&')) = ahead.next() {
+                    *chars = ahead;
+                    // `&&[...]`のときは、入れ子の文字クラスとの共通部分を取る
+                    let mut nested = chars.clone();
+                    if let (Some((idx, '[')), Some((_, c))) = (nested.next(), nested.peek()) {
+                        if *c != ':' {
+                            *chars = nested;
+                            class = class.intersect(&parse_class_set(chars, idx)?);
+                            continue;
+                        }
+                    }
+                    // `[a-z&&def]`のように`[`がないときは、残り全部との共通部分を取る
+                    return Ok(class.intersect(&parse_class_items(chars, pos)?));
+                }
+            }
+            Some((idx, '[')) => {
+                let idx = *idx;
+                // `[:`のときはPOSIX文字クラス、それ以外は`[`という文字
+                let mut ahead = chars.clone();
+                ahead.next();
+                if let Some((_, ':')) = ahead.next() {
+                    *chars = ahead;
+                    parse_posix_class(&mut class, chars, idx, pos)?;
+                    continue;
+                }
+            }
+            // `]`で閉じられていない
+            None => return Err(ParseError::UnclosedClass(pos)),
+            _ => {}
+        }
+
+        let (idx, lo) = parse_class_char(chars, pos)?;
+
+        // `a-z`のような範囲。`[a-]`のように`-`の後に`]`が続く場合は`-`という文字
+        let mut ahead = chars.clone();
+        let is_range = matches!(ahead.next(), Some((_, '-')))
+            && !matches!(ahead.peek(), Some((_, ']')) | None);
+        if is_range {
+            chars.next();
+            let (_, hi) = parse_class_char(chars, pos)?;
+            if hi < lo {
+                return Err(ParseError::InvalidClass(idx));
+            }
+            class.push(lo, hi);
+        } else {
+            class.push(lo, lo);
+        }
+    }
+
+    Ok(class)
+}
+
 enum Psq {
     Plus,
     Star,
     Question,
+    PlusLazy,
+    StarLazy,
+    QuestionLazy,
+}
+
+impl Psq {
+    /// 後ろに`?`が続いたときの最短一致版
+    fn lazy(self) -> Self {
+        match self {
+            Psq::Plus => Psq::PlusLazy,
+            Psq::Star => Psq::StarLazy,
+            Psq::Question => Psq::QuestionLazy,
+            lazy => lazy,
+        }
+    }
 }
 
 /// `+`.`*`,`?`をAstに変換する
@@ -99,6 +651,9 @@ fn parse_plus_star_question(
             Psq::Plus => Ast::Plus(prev_box),
             Psq::Star => Ast::Star(prev_box),
             Psq::Question => Ast::Question(prev_box),
+            Psq::PlusLazy => Ast::PlusLazy(prev_box),
+            Psq::StarLazy => Ast::StarLazy(prev_box),
+            Psq::QuestionLazy => Ast::QuestionLazy(prev_box),
         };
 
         seq.push(ast);
@@ -108,6 +663,128 @@ fn parse_plus_star_question(
     }
 }
 
+/// `parse`で用いる、位置付きの文字イテレータ
+type CharIter<'a> = Peekable<Enumerate<Chars<'a>>>;
+
+/// 10進数の整数を読み取る。数字が1つもない場合は`None`を返す
+///
+/// `usize`に収まらない場合は`usize::MAX`にして、繰り返しが大きすぎるエラーにする
+fn parse_number(chars: &mut CharIter) -> Option<usize> {
+    let mut n: Option<usize> = None;
+    while let Some(d) = chars.peek().and_then(|(_, c)| c.to_digit(10)) {
+        chars.next();
+        n = Some(n.unwrap_or(0).saturating_mul(10).saturating_add(d as usize));
+    }
+    n
+}
+
+/// `{n}`,`{n,}`,`{n,m}`の中身を読み取り、下限と上限を返す。上限がない場合は`None`
+///
+/// `{`は読み込み済みとする。繰り返し指定として読めない場合は`None`を返す
+fn parse_repeat_range(chars: &mut CharIter) -> Option<(usize, Option<usize>)> {
+    let min = parse_number(chars)?;
+    match chars.next()? {
+        (_, '}') => Some((min, Some(min))),
+        (_, ',') => {
+            let max = parse_number(chars);
+            let (_, '}') = chars.next()? else {
+                return None;
+            };
+            Some((min, max))
+        }
+        _ => None,
+    }
+}
+
+/// `{n,m}`を展開したときに並ぶコピーの数
+///
+/// `{n,m}`は必須の`n`個と省略できる`m-n`個、`{n,}`は`n`個と`*`1つになる
+pub fn repeat_copies(min: usize, max: Option<usize>) -> usize {
+    max.unwrap_or(min.saturating_add(1))
+}
+
+/// `{n,m}`を展開したときのAstのノード数
+fn ast_size(ast: &Ast) -> usize {
+    match ast {
+        Ast::Repeat(e, min, max) | Ast::RepeatLazy(e, min, max) => {
+            1_usize.saturating_add(repeat_copies(*min, *max).saturating_mul(ast_size(e)))
+        }
+        Ast::Plus(e)
+        | Ast::Star(e)
+        | Ast::Question(e)
+        | Ast::PlusLazy(e)
+        | Ast::StarLazy(e)
+        | Ast::QuestionLazy(e)
+        | Ast::Atomic(e)
+        | Ast::LookAhead(e)
+        | Ast::NegLookAhead(e)
+        | Ast::LookBehind(e)
+        | Ast::NegLookBehind(e)
+        | Ast::Capture(_, e) => 1 + ast_size(e),
+        Ast::Or(e1, e2) => 1_usize
+            .saturating_add(ast_size(e1))
+            .saturating_add(ast_size(e2)),
+        Ast::Seq(seq) => seq
+            .iter()
+            .map(ast_size)
+            .fold(1, |sum, size| sum.saturating_add(size)),
+        _ => 1,
+    }
+}
+
+/// `{n}`,`{n,}`,`{n,m}`をAstに変換する
+///
+/// `pos`は`{`の位置。展開したノード数を`total`に足し、`limit`を超えた場合はエラー
+fn parse_repeat(
+    seq: &mut Vec<Ast>,
+    min: usize,
+    max: Option<usize>,
+    is_lazy: bool,
+    pos: usize,
+    total: &mut usize,
+    limit: usize,
+) -> Result<(), ParseError> {
+    // `a{4,2}`のように上限と下限が逆転している
+    if max.is_some_and(|max| max < min) {
+        return Err(ParseError::InvalidRepeat(pos));
+    }
+
+    let Some(prev) = seq.pop() else {
+        return Err(ParseError::NoPrev(pos));
+    };
+
+    // コード生成で展開したときに、巨大にならないよう大きさを確かめておく
+    let size = repeat_copies(min, max).saturating_mul(ast_size(&prev));
+    *total = total.saturating_add(size);
+    if *total > limit {
+        return Err(ParseError::RepeatTooLarge(pos));
+    }
+    let prev = Box::new(prev);
+    if is_lazy {
+        seq.push(Ast::RepeatLazy(prev, min, max));
+    } else {
+        seq.push(Ast::Repeat(prev, min, max));
+    }
+    Ok(())
+}
+
+/// `(?i)`のようなフラグ指定を読み取り、`flags`に反映する
+///
+/// `(?`は読み込み済みとする
+fn parse_flags(chars: &mut CharIter, flags: &mut Flags) -> Result<(), ParseError> {
+    loop {
+        match chars.next() {
+            Some((_, ')')) => return Ok(()),
+            Some((_, 'i')) => flags.case_insensitive = true,
+            Some((_, 'x')) => flags.extended = true,
+            Some((_, 's')) => flags.dot_all = true,
+            Some((_, 'm')) => flags.multi_line = true,
+            Some((idx, _)) => return Err(ParseError::InvalidFlag(idx)),
+            None => return Err(ParseError::NoRightParen),
+        }
+    }
+}
+
 /// `|`をAstに変換する
 fn fold_or(mut seq_or: Vec<Ast>) -> Option<Ast> {
     if seq_or.len() > 1 {
@@ -122,6 +799,24 @@ fn fold_or(mut seq_or: Vec<Ast>) -> Option<Ast> {
     }
 }
 
+/// `(`で始まるグループの種類
+enum Group {
+    /// `(...)`。`usize`番目のキャプチャグループ
+    Capture(usize),
+    /// `(?:...)`。キャプチャしない
+    NonCapture,
+    /// `(?>...)`
+    Atomic,
+    /// `(?=...)`
+    LookAhead,
+    /// `(?!...)`
+    NegLookAhead,
+    /// `(?<=...)`
+    LookBehind,
+    /// `(?<!...)`
+    NegLookBehind,
+}
+
 /// `parse`の内部状態を示す型
 enum ParseState {
     /// 文字列処理中
@@ -130,27 +825,176 @@ enum ParseState {
     Escape,
 }
 
+/// パースの際の上限などの設定
+///
+/// ```
+/// use regex_machine::engine::ParseConfig;
+/// let config = ParseConfig::default().max_repeat(1000);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseConfig {
+    max_repeat: usize,
+    max_nesting: usize,
+}
+
+impl Default for ParseConfig {
+    fn default() -> Self {
+        ParseConfig {
+            max_repeat: 100000,
+            max_nesting: 250,
+        }
+    }
+}
+
+impl ParseConfig {
+    /// `{n}`などの繰り返しで展開するAstのノード数の、パターン全体での上限
+    pub fn max_repeat(mut self, max_repeat: usize) -> Self {
+        self.max_repeat = max_repeat;
+        self
+    }
+
+    /// `(`の入れ子の深さの上限。コード生成時の再帰が深くなりすぎないようにする
+    pub fn max_nesting(mut self, max_nesting: usize) -> Self {
+        self.max_nesting = max_nesting;
+        self
+    }
+}
+
 pub fn parse(expr: &str) -> Result<Ast, ParseError> {
+    parse_with_flags(expr, &mut Flags::default())
+}
+
+/// 正規表現をパースする
+///
+/// パターンの先頭に`(?i)`のようなフラグ指定があった場合、`flags`に反映する
+pub fn parse_with_flags(expr: &str, flags: &mut Flags) -> Result<Ast, ParseError> {
+    parse_with_config(expr, flags, &ParseConfig::default())
+}
+
+/// `config`の設定で正規表現をパースする
+pub fn parse_with_config(
+    expr: &str,
+    flags: &mut Flags,
+    config: &ParseConfig,
+) -> Result<Ast, ParseError> {
+    parse_with_names(expr, flags, config).map(|(ast, _)| ast)
+}
+
+/// `parse_with_config`と同じだが、名前付きグループの名前と番号の対応も返す
+pub fn parse_with_names(
+    expr: &str,
+    flags: &mut Flags,
+    config: &ParseConfig,
+) -> Result<(Ast, HashMap<String, usize>), ParseError> {
     let mut seq = Vec::new();
     let mut seq_or = Vec::new();
     // `()`が出てきたときに、それ以前の値を取っておく場所
     let mut stack = Vec::new();
     let mut state = ParseState::Char;
+    let mut chars = expr.chars().enumerate().peekable();
+    // これまでに開いたキャプチャグループの数
+    let mut captures = 0;
+    // 名前付きグループの名前と番号
+    let mut names = HashMap::new();
+    // `{n}`で展開したノード数
+    let mut repeat_total = 0;
 
-    for (idx, c) in expr.chars().enumerate() {
+    while let Some((idx, c)) = chars.next() {
         match state {
             ParseState::Char => match c {
-                '+' => parse_plus_star_question(&mut seq, Psq::Plus, idx)?,
-                '*' => parse_plus_star_question(&mut seq, Psq::Star, idx)?,
-                '?' => parse_plus_star_question(&mut seq, Psq::Question, idx)?,
+                '+' | '*' | '?' => {
+                    let mut ast_type = match c {
+                        '+' => Psq::Plus,
+                        '*' => Psq::Star,
+                        _ => Psq::Question,
+                    };
+                    // `*?`のように`?`が続くときは最短一致
+                    if chars.next_if(|(_, c)| *c == '?').is_some() {
+                        ast_type = ast_type.lazy();
+                        parse_plus_star_question(&mut seq, ast_type, idx)?
+                    } else if chars.next_if(|(_, c)| *c == '+').is_some() {
+                        // `*+`のように`+`が続くときは、バックトラックしない繰り返し
+                        parse_plus_star_question(&mut seq, ast_type, idx)?;
+                        if let Some(prev) = seq.pop() {
+                            seq.push(Ast::Atomic(Box::new(prev)));
+                        }
+                    } else {
+                        parse_plus_star_question(&mut seq, ast_type, idx)?
+                    }
+                }
+                '{' => {
+                    let mut ahead = chars.clone();
+                    if let Some((min, max)) = parse_repeat_range(&mut ahead) {
+                        chars = ahead;
+                        // `{n,m}?`のように`?`が続くときは最短一致
+                        let is_lazy = chars.next_if(|(_, c)| *c == '?').is_some();
+                        parse_repeat(
+                            &mut seq,
+                            min,
+                            max,
+                            is_lazy,
+                            idx,
+                            &mut repeat_total,
+                            config.max_repeat,
+                        )?
+                    } else {
+                        // 繰り返し指定として読めない`{`は、ただの文字として扱う
+                        seq.push(Ast::Char('{'));
+                    }
+                }
                 '(' => {
+                    let group = if chars.next_if(|(_, c)| *c == '?').is_some() {
+                        let group = match chars.peek() {
+                            Some((_, ':')) => Group::NonCapture,
+                            Some((_, '>')) => Group::Atomic,
+                            Some((_, '=')) => Group::LookAhead,
+                            Some((_, '!')) => Group::NegLookAhead,
+                            Some((_, '<')) => {
+                                chars.next();
+                                match chars.peek() {
+                                    Some((_, '=')) => Group::LookBehind,
+                                    Some((_, '!')) => Group::NegLookBehind,
+                                    // `(?<name>...)`は名前付きのキャプチャグループ
+                                    _ => match parse_group_name(&mut chars) {
+                                        Some(name)
+                                            if matches!(chars.peek(), Some((_, '>')))
+                                                && !names.contains_key(&name) =>
+                                        {
+                                            captures += 1;
+                                            names.insert(name, captures);
+                                            Group::Capture(captures)
+                                        }
+                                        _ => return Err(ParseError::InvalidGroup(idx)),
+                                    },
+                                }
+                            }
+                            _ => {
+                                // フラグはパターンの先頭でのみ指定できる
+                                if !(seq.is_empty() && seq_or.is_empty() && stack.is_empty()) {
+                                    return Err(ParseError::InvalidFlag(idx));
+                                }
+                                parse_flags(&mut chars, flags)?;
+                                continue;
+                            }
+                        };
+                        chars.next();
+                        group
+                    } else {
+                        captures += 1;
+                        Group::Capture(captures)
+                    };
+
+                    if stack.len() >= config.max_nesting {
+                        return Err(ParseError::NestingTooDeep(idx));
+                    }
+
                     // 現在の状態をスタックに避難させる
                     let prev = take(&mut seq);
                     let prev_or = take(&mut seq_or);
-                    stack.push((prev, prev_or));
+                    stack.push((prev, prev_or, group));
                 }
                 ')' => {
-                    let Some((mut prev, prev_or)) = stack.pop() else {
+                    let Some((mut prev, prev_or, group)) = stack.pop() else {
                         return Err(ParseError::InvalidRightParen(idx));
                     };
 
@@ -160,9 +1004,18 @@ pub fn parse(expr: &str) -> Result<Ast, ParseError> {
                         seq_or.push(Ast::Seq(seq));
                     }
 
-                    if let Some(ast) = fold_or(seq_or) {
-                        prev.push(ast);
-                    }
+                    // `()`や`(?!)`のような空のグループも、空文字列にマッチするものとして残しておく
+                    let ast = fold_or(seq_or).unwrap_or(Ast::Seq(Vec::new()));
+                    let ast = match group {
+                        Group::Capture(n) => Ast::Capture(n, Box::new(ast)),
+                        Group::NonCapture => ast,
+                        Group::Atomic => Ast::Atomic(Box::new(ast)),
+                        Group::LookAhead => Ast::LookAhead(Box::new(ast)),
+                        Group::NegLookAhead => Ast::NegLookAhead(Box::new(ast)),
+                        Group::LookBehind => Ast::LookBehind(Box::new(ast)),
+                        Group::NegLookBehind => Ast::NegLookBehind(Box::new(ast)),
+                    };
+                    prev.push(ast);
 
                     // 過去の状態を復元する
                     seq = prev;
@@ -177,21 +1030,71 @@ pub fn parse(expr: &str) -> Result<Ast, ParseError> {
                     }
                 }
                 '\\' => state = ParseState::Escape,
+                '[' => {
+                    let class = parse_class(&mut chars, idx)?;
+                    seq.push(class);
+                }
                 '.' => {
                     seq.push(Ast::Any);
                 }
+                // `(?m)`のときは各行の先頭と末尾にもマッチする
+                '^' if flags.multi_line => {
+                    seq.push(Ast::LineStart);
+                }
+                '$' if flags.multi_line => {
+                    seq.push(Ast::LineEnd);
+                }
                 '^' => {
                     seq.push(Ast::Start);
                 }
                 '$' => {
                     seq.push(Ast::End);
                 }
+                // `(?x)`のときはエスケープされていない空白を無視する
+                _ if flags.extended && c.is_whitespace() => {}
                 _ => {
                     seq.push(Ast::Char(c));
                 }
             },
             ParseState::Escape => {
-                let ast = parse_escape(idx, c)?;
+                let ast = match c {
+                    'x' => Ast::Char(parse_hex_escape(&mut chars, idx)?),
+                    'u' => Ast::Char(parse_unicode_escape(&mut chars, idx)?),
+                    'p' | 'P' => Ast::Property(parse_property(&mut chars, idx, c)?),
+                    '1'..='9' => {
+                        let mut n = c.to_digit(10).unwrap() as usize;
+                        if n > captures {
+                            return Err(ParseError::InvalidBackRef(idx));
+                        }
+                        // 開いているグループの番号になる間だけ続く数字を読む。残りの数字は文字として扱う
+                        while let Some(d) = chars.peek().and_then(|(_, c)| c.to_digit(10)) {
+                            match n.checked_mul(10).and_then(|n| n.checked_add(d as usize)) {
+                                Some(next) if next <= captures => {
+                                    n = next;
+                                    chars.next();
+                                }
+                                _ => break,
+                            }
+                        }
+                        Ast::BackRef(n)
+                    }
+                    'k' => {
+                        let Some((_, '<')) = chars.next() else {
+                            return Err(ParseError::InvalidEscape(idx, c));
+                        };
+                        let Some(name) = parse_group_name(&mut chars) else {
+                            return Err(ParseError::InvalidEscape(idx, c));
+                        };
+                        let Some((_, '>')) = chars.next() else {
+                            return Err(ParseError::InvalidEscape(idx, c));
+                        };
+                        let Some(n) = names.get(&name) else {
+                            return Err(ParseError::UnknownGroupName(idx, name));
+                        };
+                        Ast::BackRef(*n)
+                    }
+                    _ => parse_escape(idx, c)?,
+                };
                 seq.push(ast);
                 state = ParseState::Char
             }
@@ -208,11 +1111,9 @@ pub fn parse(expr: &str) -> Result<Ast, ParseError> {
         seq_or.push(Ast::Seq(seq));
     };
 
-    if let Some(ast) = fold_or(seq_or) {
-        Ok(ast)
-    } else {
-        Err(ParseError::Empty)
-    }
+    // 空のパターンは、空文字列にマッチする
+    let ast = fold_or(seq_or).unwrap_or(Ast::Seq(Vec::new()));
+    Ok((ast, names))
 }
 
 #[cfg(test)]
@@ -222,7 +1123,12 @@ mod tests {
     #[test]
     fn valid_parse_escape() {
         assert_eq!(parse_escape(3, '+').unwrap(), Ast::Char('+'));
-        assert_eq!(parse_escape(1, '|').unwrap(), Ast::Char('|'))
+        assert_eq!(parse_escape(1, '|').unwrap(), Ast::Char('|'));
+        assert_eq!(parse_escape(1, 'b').unwrap(), Ast::WordBoundary);
+        assert_eq!(parse_escape(1, '[').unwrap(), Ast::Char('['));
+        assert_eq!(parse_escape(1, ']').unwrap(), Ast::Char(']'));
+        assert_eq!(parse_escape(1, '{').unwrap(), Ast::Char('{'));
+        assert_eq!(parse_escape(1, '}').unwrap(), Ast::Char('}'))
     }
 
     #[test]
@@ -232,9 +1138,16 @@ mod tests {
             ParseError::InvalidEscape(3, 'a')
         );
         assert_eq!(
-            parse_escape(123, 'b').err().unwrap(),
-            ParseError::InvalidEscape(123, 'b')
-        )
+            parse_escape(123, 'q').err().unwrap(),
+            ParseError::InvalidEscape(123, 'q')
+        );
+        // `\d`のような省略形はなく、`[[:digit:]]`などを使う
+        for c in ['d', 'w', 's', 'D', 'W', 'S'] {
+            assert_eq!(
+                parse_escape(1, c).err().unwrap(),
+                ParseError::InvalidEscape(1, c)
+            );
+        }
     }
 
     #[test]
@@ -328,6 +1241,15 @@ mod tests {
         )
     }
 
+    #[test]
+    fn empty_regex() {
+        assert_eq!(parse("").unwrap(), Ast::Seq(Vec::new()));
+        assert_eq!(
+            parse("()").unwrap(),
+            Ast::Seq(vec![Ast::Capture(1, Box::new(Ast::Seq(Vec::new())))])
+        );
+    }
+
     #[test]
     fn escaped_regex() {
         let regex = r"1\?\*23";
@@ -347,33 +1269,106 @@ mod tests {
     }
 
     #[test]
-    fn plus_star_question_regex() {
-        let regex = r"b?+*";
+    fn hex_escaped_regex() {
+        let regex = r"\x41\x7e";
 
         let ast = parse(regex).unwrap();
 
+        assert_eq!(ast, Ast::Seq(vec![Ast::Char('A'), Ast::Char('~')]))
+    }
+
+    #[test]
+    fn invalid_hex_escape() {
         assert_eq!(
-            ast,
-            Ast::Seq(vec![Ast::Star(Box::new(Ast::Plus(Box::new(
-                Ast::Question(Box::new(Ast::Char('b')))
-            ))))])
-        )
+            parse(r"a\xZZ").err().unwrap(),
+            ParseError::InvalidEscape(2, 'x')
+        );
+        assert_eq!(
+            parse(r"\x4").err().unwrap(),
+            ParseError::InvalidEscape(1, 'x')
+        );
     }
 
     #[test]
-    fn or_regex() {
-        let regex = r"abc|123";
+    fn unicode_escaped_regex() {
+        let regex = r"\u{3042}\u{1F600}";
 
         let ast = parse(regex).unwrap();
 
+        assert_eq!(ast, Ast::Seq(vec![Ast::Char('あ'), Ast::Char('😀')]))
+    }
+
+    #[test]
+    fn invalid_unicode_escape() {
+        // サロゲート
         assert_eq!(
-            ast,
-            Ast::Or(
-                Box::new(Ast::Seq(vec![
-                    Ast::Char('a'),
-                    Ast::Char('b'),
-                    Ast::Char('c'),
-                ])),
+            parse(r"\u{D800}").err().unwrap(),
+            ParseError::InvalidEscape(1, 'u')
+        );
+        // 範囲外
+        assert_eq!(
+            parse(r"\u{110000}").err().unwrap(),
+            ParseError::InvalidEscape(1, 'u')
+        );
+        assert_eq!(
+            parse(r"\u{41").err().unwrap(),
+            ParseError::InvalidEscape(1, 'u')
+        );
+        assert_eq!(
+            parse(r"\u41").err().unwrap(),
+            ParseError::InvalidEscape(1, 'u')
+        );
+        assert_eq!(
+            parse(r"\u{}").err().unwrap(),
+            ParseError::InvalidEscape(1, 'u')
+        );
+    }
+
+    #[test]
+    fn plus_star_question_regex() {
+        // `?+`はバックトラックしない`?`
+        let regex = r"b?+*";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Star(Box::new(Ast::Atomic(Box::new(
+                Ast::Question(Box::new(Ast::Char('b')))
+            ))))])
+        )
+    }
+
+    #[test]
+    fn possessive_regex() {
+        let regex = r"a++b*+c?+";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Atomic(Box::new(Ast::Plus(Box::new(Ast::Char('a'))))),
+                Ast::Atomic(Box::new(Ast::Star(Box::new(Ast::Char('b'))))),
+                Ast::Atomic(Box::new(Ast::Question(Box::new(Ast::Char('c'))))),
+            ])
+        )
+    }
+
+    #[test]
+    fn or_regex() {
+        let regex = r"abc|123";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Or(
+                Box::new(Ast::Seq(vec![
+                    Ast::Char('a'),
+                    Ast::Char('b'),
+                    Ast::Char('c'),
+                ])),
                 Box::new(Ast::Seq(vec![
                     Ast::Char('1'),
                     Ast::Char('2'),
@@ -383,6 +1378,22 @@ mod tests {
         )
     }
 
+    #[test]
+    fn lazy_regex() {
+        let regex = r"a+?b*?c??";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::PlusLazy(Box::new(Ast::Char('a'))),
+                Ast::StarLazy(Box::new(Ast::Char('b'))),
+                Ast::QuestionLazy(Box::new(Ast::Char('c')))
+            ])
+        )
+    }
+
     #[test]
     fn nested_paren_regex() {
         let regex = r"(abc(123)def)";
@@ -391,18 +1402,241 @@ mod tests {
 
         assert_eq!(
             ast,
-            Ast::Seq(vec![Ast::Seq(vec![
+            Ast::Seq(vec![Ast::Capture(
+                1,
+                Box::new(Ast::Seq(vec![
+                    Ast::Char('a'),
+                    Ast::Char('b'),
+                    Ast::Char('c'),
+                    Ast::Capture(
+                        2,
+                        Box::new(Ast::Seq(vec![
+                            Ast::Char('1'),
+                            Ast::Char('2'),
+                            Ast::Char('3'),
+                        ]))
+                    ),
+                    Ast::Char('d'),
+                    Ast::Char('e'),
+                    Ast::Char('f')
+                ]))
+            ),])
+        )
+    }
+
+    #[test]
+    fn back_ref() {
+        let regex = r"(a)()\1\2";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Capture(1, Box::new(Ast::Seq(vec![Ast::Char('a')]))),
+                Ast::Capture(2, Box::new(Ast::Seq(vec![]))),
+                Ast::BackRef(1),
+                Ast::BackRef(2),
+            ])
+        );
+
+        // 開いていないグループは参照できない
+        assert_eq!(
+            parse(r"(a)\2").err().unwrap(),
+            ParseError::InvalidBackRef(4)
+        );
+        // `(?>...)`などはキャプチャしない
+        assert_eq!(
+            parse(r"(?>a)\1").err().unwrap(),
+            ParseError::InvalidBackRef(6)
+        );
+
+        // 続く数字は、開いているグループの番号になる間だけ番号として読む
+        let groups = "()".repeat(10);
+        assert_eq!(
+            parse(&format!(r"{groups}\10")).unwrap(),
+            Ast::Seq(
+                (1..=10)
+                    .map(|i| Ast::Capture(i, Box::new(Ast::Seq(vec![]))))
+                    .chain([Ast::BackRef(10)])
+                    .collect()
+            )
+        );
+        assert_eq!(
+            parse(r"(a)\10").unwrap(),
+            Ast::Seq(vec![
+                Ast::Capture(1, Box::new(Ast::Seq(vec![Ast::Char('a')]))),
+                Ast::BackRef(1),
+                Ast::Char('0'),
+            ])
+        );
+    }
+
+    #[test]
+    fn atomic_regex() {
+        let regex = r"(?>a|ab)c";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Atomic(Box::new(Ast::Or(
+                    Box::new(Ast::Seq(vec![Ast::Char('a')])),
+                    Box::new(Ast::Seq(vec![Ast::Char('a'), Ast::Char('b')]))
+                ))),
+                Ast::Char('c')
+            ])
+        )
+    }
+
+    #[test]
+    fn look_ahead_regex() {
+        let regex = r"a(?=b)(?!c)";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
                 Ast::Char('a'),
-                Ast::Char('b'),
-                Ast::Char('c'),
-                Ast::Seq(vec![Ast::Char('1'), Ast::Char('2'), Ast::Char('3'),]),
-                Ast::Char('d'),
-                Ast::Char('e'),
-                Ast::Char('f')
-            ]),])
+                Ast::LookAhead(Box::new(Ast::Seq(vec![Ast::Char('b')]))),
+                Ast::NegLookAhead(Box::new(Ast::Seq(vec![Ast::Char('c')]))),
+            ])
+        )
+    }
+
+    #[test]
+    fn look_behind_regex() {
+        let regex = r"(?<=a)(?<!b)";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::LookBehind(Box::new(Ast::Seq(vec![Ast::Char('a')]))),
+                Ast::NegLookBehind(Box::new(Ast::Seq(vec![Ast::Char('b')]))),
+            ])
+        );
+
+        assert_eq!(parse(r"a(?<x)").err().unwrap(), ParseError::InvalidGroup(1));
+
+        // 空のグループも残る
+        assert_eq!(
+            parse(r"(?!)(?<!)").unwrap(),
+            Ast::Seq(vec![
+                Ast::NegLookAhead(Box::new(Ast::Seq(vec![]))),
+                Ast::NegLookBehind(Box::new(Ast::Seq(vec![]))),
+            ])
+        );
+    }
+
+    #[test]
+    fn non_capture() {
+        let regex = r"(?:ab)(c)\1";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Seq(vec![Ast::Char('a'), Ast::Char('b')]),
+                Ast::Capture(1, Box::new(Ast::Seq(vec![Ast::Char('c')]))),
+                Ast::BackRef(1),
+            ])
+        );
+    }
+
+    #[test]
+    fn named_back_ref() {
+        let regex = r"(a)(?<x>b)\k<x>\2";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Capture(1, Box::new(Ast::Seq(vec![Ast::Char('a')]))),
+                Ast::Capture(2, Box::new(Ast::Seq(vec![Ast::Char('b')]))),
+                Ast::BackRef(2),
+                Ast::BackRef(2),
+            ])
+        );
+
+        assert_eq!(
+            parse(r"(?<x>a)\k<y>").err().unwrap(),
+            ParseError::UnknownGroupName(8, "y".to_string())
+        );
+        assert_eq!(
+            parse(r"(?<x>a)\k<x").err().unwrap(),
+            ParseError::InvalidEscape(8, 'k')
+        );
+        // 同じ名前は使えない
+        assert_eq!(
+            parse(r"(?<x>a)(?<x>b)").err().unwrap(),
+            ParseError::InvalidGroup(7)
+        );
+        assert_eq!(
+            parse(r"(?<1x>a)").err().unwrap(),
+            ParseError::InvalidGroup(0)
+        );
+    }
+
+    #[test]
+    fn absolute_anchor_regex() {
+        let regex = r"\Aa\z\Z";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Start,
+                Ast::Char('a'),
+                Ast::End,
+                Ast::LookAhead(Box::new(Ast::Seq(vec![
+                    Ast::Question(Box::new(Ast::Char('\n'))),
+                    Ast::End
+                ]))),
+            ])
         )
     }
 
+    #[test]
+    fn property_regex() {
+        let regex = r"\p{L}\P{Number}";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Property(Property {
+                    category: Category::Letter,
+                    negated: false
+                }),
+                Ast::Property(Property {
+                    category: Category::Number,
+                    negated: true
+                }),
+            ])
+        );
+
+        assert_eq!(
+            parse(r"\p{Foo}").err().unwrap(),
+            ParseError::UnknownClass(1, "Foo".to_string())
+        );
+        assert_eq!(
+            parse(r"\pL").err().unwrap(),
+            ParseError::InvalidEscape(1, 'p')
+        );
+        assert_eq!(
+            parse(r"\P{L").err().unwrap(),
+            ParseError::InvalidEscape(1, 'P')
+        );
+    }
+
     #[test]
     fn any_regex() {
         let regex = r"a.";
@@ -430,6 +1664,254 @@ mod tests {
         assert_eq!(ast, Ast::Seq(vec![Ast::Char('a'), Ast::End]))
     }
 
+    #[test]
+    fn repeat_regex() {
+        let regex = r"ab{3}";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Char('a'),
+                Ast::Repeat(Box::new(Ast::Char('b')), 3, Some(3))
+            ])
+        )
+    }
+
+    #[test]
+    fn unbounded_repeat_regex() {
+        let regex = r"a{2,}";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Repeat(Box::new(Ast::Char('a')), 2, None)])
+        )
+    }
+
+    #[test]
+    fn bounded_repeat_regex() {
+        let regex = r"a{1,3}";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Repeat(Box::new(Ast::Char('a')), 1, Some(3))])
+        )
+    }
+
+    #[test]
+    fn lazy_repeat_regex() {
+        let regex = r"a{1,3}?b{2,}?";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::RepeatLazy(Box::new(Ast::Char('a')), 1, Some(3)),
+                Ast::RepeatLazy(Box::new(Ast::Char('b')), 2, None)
+            ])
+        )
+    }
+
+    #[test]
+    fn literal_brace_regex() {
+        let regex = r"a{b";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Char('a'), Ast::Char('{'), Ast::Char('b')])
+        );
+
+        let regex = r"{}";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(ast, Ast::Seq(vec![Ast::Char('{'), Ast::Char('}')]));
+
+        for regex in [r"a{", r"a{}", r"a{3", r"a{3,", r"a{,3}"] {
+            assert!(parse(regex).is_ok(), "{regex}");
+        }
+    }
+
+    #[test]
+    fn invalid_repeat() {
+        assert_eq!(
+            parse(r"a{4,2}").err().unwrap(),
+            ParseError::InvalidRepeat(1)
+        );
+        assert_eq!(parse(r"{3}").err().unwrap(), ParseError::NoPrev(0));
+    }
+
+    #[test]
+    fn flag_regex() {
+        let regex = r"(?i)ab";
+
+        let mut flags = Flags::default();
+        let ast = parse_with_flags(regex, &mut flags).unwrap();
+
+        assert_eq!(ast, Ast::Seq(vec![Ast::Char('a'), Ast::Char('b')]));
+        assert!(flags.case_insensitive);
+
+        let mut flags = Flags::default();
+        parse_with_flags(r"(?si)a", &mut flags).unwrap();
+        assert!(flags.case_insensitive && flags.dot_all)
+    }
+
+    #[test]
+    fn multi_line_regex() {
+        let regex = r"(?m)^a$\A\z";
+
+        let mut flags = Flags::default();
+        let ast = parse_with_flags(regex, &mut flags).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::LineStart,
+                Ast::Char('a'),
+                Ast::LineEnd,
+                Ast::Start,
+                Ast::End
+            ])
+        );
+        assert!(flags.multi_line);
+    }
+
+    #[test]
+    fn extended_regex() {
+        let regex = "(?x) a\\  b\n[ ]";
+
+        let mut flags = Flags::default();
+        let ast = parse_with_flags(regex, &mut flags).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Char('a'),
+                Ast::Char(' '),
+                Ast::Char('b'),
+                Ast::Class(CharClass {
+                    ranges: vec![(' ', ' ')],
+                    negated: false
+                })
+            ])
+        );
+        assert!(flags.extended)
+    }
+
+    #[test]
+    fn invalid_flag() {
+        assert_eq!(parse(r"(?q)a").err().unwrap(), ParseError::InvalidFlag(2));
+        assert_eq!(parse(r"a(?i)b").err().unwrap(), ParseError::InvalidFlag(1));
+        assert_eq!(parse(r"(?i").err().unwrap(), ParseError::NoRightParen);
+    }
+
+    #[test]
+    fn class_regex() {
+        let regex = r"[a-c_\]][^x-]";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Class(CharClass {
+                    ranges: vec![('a', 'c'), ('_', '_'), (']', ']')],
+                    negated: false
+                }),
+                Ast::Class(CharClass {
+                    ranges: vec![('x', 'x'), ('-', '-')],
+                    negated: true
+                })
+            ])
+        )
+    }
+
+    #[test]
+    fn posix_class_regex() {
+        let regex = r"[[:digit:]x]";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Class(CharClass {
+                ranges: vec![('0', '9'), ('x', 'x')],
+                negated: false
+            })])
+        )
+    }
+
+    #[test]
+    fn class_intersection_regex() {
+        let regex = r"[a-f&&[^b-d]][^a-z&&[:lower:]]";
+
+        let ast = parse(regex).unwrap();
+
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![
+                Ast::Class(CharClass {
+                    ranges: vec![('a', 'a'), ('e', 'f')],
+                    negated: false
+                }),
+                Ast::Class(CharClass {
+                    ranges: vec![('a', 'z')],
+                    negated: true
+                }),
+            ])
+        );
+
+        // `&`が1つだけのときは文字
+        let ast = parse(r"[a&]").unwrap();
+        assert_eq!(
+            ast,
+            Ast::Seq(vec![Ast::Class(CharClass {
+                ranges: vec![('a', 'a'), ('&', '&')],
+                negated: false
+            })])
+        );
+
+        assert_eq!(
+            parse(r"[a-z&&[^aeiou]").err().unwrap(),
+            ParseError::UnclosedClass(0)
+        );
+    }
+
+    #[test]
+    fn invalid_class() {
+        assert_eq!(parse(r"[z-a]").err().unwrap(), ParseError::InvalidClass(1));
+        assert_eq!(
+            parse(r"a[[:foo:]]").err().unwrap(),
+            ParseError::UnknownClass(2, "foo".to_string())
+        );
+        assert_eq!(
+            parse(r"[[:alpha]").err().unwrap(),
+            ParseError::InvalidClass(1)
+        );
+    }
+
+    #[test]
+    fn unclosed_class() {
+        assert_eq!(
+            parse(r"abc[de").err().unwrap(),
+            ParseError::UnclosedClass(3)
+        );
+        assert_eq!(parse(r"[a-").err().unwrap(), ParseError::UnclosedClass(0));
+        assert_eq!(parse(r"a[b\").err().unwrap(), ParseError::UnclosedClass(1));
+        assert_eq!(
+            parse(r"[[:alpha").err().unwrap(),
+            ParseError::UnclosedClass(0)
+        );
+    }
+
     #[test]
     fn invalid_right_paren() {
         let regex = r"abc)";
@@ -438,6 +1920,161 @@ mod tests {
         assert_eq!(err, ParseError::InvalidRightParen(3))
     }
 
+    #[test]
+    fn render_error() {
+        let regex = r"abc)";
+
+        let err = parse(regex).err().unwrap();
+        let rendered = err.render(regex);
+        let lines = rendered.lines().collect::<Vec<_>>();
+
+        assert_eq!(
+            lines,
+            vec![
+                "ParseError: invalid right parenthesis: pos = 3",
+                "abc)",
+                "   ^"
+            ]
+        );
+
+        let regex = r"(ab";
+
+        let err = parse(regex).err().unwrap();
+        assert!(err.render(regex).ends_with("(ab\n   ^"))
+    }
+
+    #[test]
+    fn repeat_too_large() {
+        assert_eq!(
+            parse(r"a{10000000}").err().unwrap(),
+            ParseError::RepeatTooLarge(1)
+        );
+        assert_eq!(
+            parse(r"a{2,}{1000000}").err().unwrap(),
+            ParseError::RepeatTooLarge(5)
+        );
+        // `usize`に収まらない回数も、ただの文字にはしない
+        for regex in [
+            r"a{99999999999999999999}",
+            r"a{0,99999999999999999999}",
+            r"a{99999999999999999999,}",
+        ] {
+            assert_eq!(
+                parse(regex).err().unwrap(),
+                ParseError::RepeatTooLarge(1),
+                "{regex}"
+            );
+        }
+        // 入れ子になっている場合は、展開後の大きさで数える
+        assert!(parse(r"(a{1000})").is_ok());
+        assert_eq!(
+            parse(r"(a{1000}){1000}").err().unwrap(),
+            ParseError::RepeatTooLarge(9)
+        );
+
+        let config = ParseConfig::default().max_repeat(10);
+        let mut flags = Flags::default();
+        assert!(parse_with_config(r"a{4}", &mut flags, &config).is_ok());
+        assert_eq!(
+            parse_with_config(r"a{4}b{7}", &mut flags, &config)
+                .err()
+                .unwrap(),
+            ParseError::RepeatTooLarge(5)
+        );
+    }
+
+    #[test]
+    fn nesting_too_deep() {
+        let regex = format!("{}a{}", "(".repeat(5000), ")".repeat(5000));
+        assert_eq!(
+            parse(&regex).err().unwrap(),
+            ParseError::NestingTooDeep(250)
+        );
+
+        let config = ParseConfig::default().max_nesting(2);
+        let mut flags = Flags::default();
+        assert!(parse_with_config(r"((a))(b)", &mut flags, &config).is_ok());
+        assert_eq!(
+            parse_with_config(r"((a)(?>(b)))", &mut flags, &config)
+                .err()
+                .unwrap(),
+            ParseError::NestingTooDeep(7)
+        );
+    }
+
+    #[test]
+    fn pattern_error() {
+        let regex = r"a(b";
+
+        let err = parse(regex).err().unwrap().with_pattern(regex);
+
+        assert_eq!(err.error, ParseError::NoRightParen);
+        assert_eq!(
+            err.to_string(),
+            "ParseError: no right parenthesis\na(b\n   ^"
+        );
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(parse("a(b|c)+").unwrap().to_string(), "a(b|c)+");
+        assert_eq!(parse("a(?:b|c)+").unwrap().to_string(), "a(?:b|c)+");
+        assert_eq!(parse(r"a\+\(").unwrap().to_string(), r"a\+\(");
+        assert_eq!(parse(r"[^a-c\]-]").unwrap().to_string(), r"[^a-c\]\-]");
+        assert_eq!(parse(r"[[:digit:]]{2,}").unwrap().to_string(), "[0-9]{2,}");
+        assert_eq!(
+            parse(r"(?<y>[[:word:]])\k<y>").unwrap().to_string(),
+            r"([0-9A-Z_a-z])\1"
+        );
+        assert_eq!(parse("").unwrap().to_string(), "");
+    }
+
+    #[test]
+    fn display_round_trip() {
+        let patterns = [
+            "a(b|c)+",
+            "abc|(de|cd)+",
+            "(?:a|b)|c",
+            "a|(?:b|c)",
+            "(?:ab)*?c",
+            "a{3}b{2,}c{1,4}?",
+            "(?:a{2})?b{1,}?",
+            "a+*",
+            "(?:a+)?",
+            "a*+b++c?+",
+            "(?>a+)",
+            r"^[[:word:]]+\b[[:space:]]*$",
+            "[a-z&&[^aeiou]]",
+            r"[\[\]\^\-&]",
+            r"\p{Lu}\P{N}",
+            "(?=a)(?!b)(?<=c)(?<!d).",
+            r"(a)(b)\2\1",
+            r"()\1",
+            r"(a)\1\x30",
+            r"()()()()()()()()()()\10\1\x30",
+            r"\{\}\|\.\$",
+            "(?i)é",
+            r"\Z",
+            "(?!)(?<!)(?:)*b",
+        ];
+        for pattern in patterns {
+            let ast = parse(pattern).unwrap();
+            let rendered = ast.to_string();
+            assert_eq!(parse(&rendered), Ok(ast), "{pattern} -> {rendered}");
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_round_trip() {
+        let ast = parse(r"a(?<x>[^b-d]|\p{Lu})+?\1{2,}").unwrap();
+        let json = serde_json::to_string(&ast).unwrap();
+        assert_eq!(serde_json::from_str::<Ast>(&json).unwrap(), ast);
+
+        let json = serde_json::to_string(&parse("ab").unwrap()).unwrap();
+        assert_eq!(json, r#"{"Seq":[{"Char":"a"},{"Char":"b"}]}"#);
+    }
+
     #[test]
     fn missing_right_paren() {
         let regex = r"(abc(123)";
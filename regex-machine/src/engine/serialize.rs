@@ -0,0 +1,651 @@
+use super::{Category, CharClass, Flags, Instruction, Property};
+use crate::helper::HashMap;
+use alloc::{string::String, vec, vec::Vec};
+
+/// 先頭に置く目印
+const MAGIC: &[u8; 4] = b"RGXM";
+/// `Regex::to_bytes`で作ったバイト列の先頭に置く目印
+const REGEX_MAGIC: &[u8; 4] = b"RGXP";
+/// 形式のバージョン。形式を変えたら上げる
+const VERSION: u8 = 1;
+
+/// 読み込み時のエラー
+#[derive(Debug, PartialEq)]
+pub enum DeserializeError {
+    /// 先頭の目印が違う
+    InvalidMagic,
+    /// 対応していないバージョン
+    UnsupportedVersion(u8),
+    /// 途中で終わっている
+    Truncated,
+    /// 存在しない命令の種類
+    InvalidTag(u8),
+    /// 文字として不正な値
+    InvalidChar(u32),
+    /// `usize`に収まらない値や、不正な`bool`など
+    InvalidValue,
+    /// 命令列の外を指すアドレス
+    InvalidAddress(usize),
+    /// 先読みなどの中身が`match`で終わっていないか、中身の外へ飛ぶ命令がある。値は不正な命令の位置
+    InvalidSubProgram(usize),
+    /// 入力を読まずにジャンプだけで元の位置に戻ってくる。値はその輪に含まれる命令の位置
+    EmptyLoop(usize),
+    /// 命令列の後ろに余分なバイトがある
+    TrailingBytes,
+}
+
+impl core::fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "DeserializeError: {self:?}")
+    }
+}
+
+impl core::error::Error for DeserializeError {}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, n: u8) {
+        self.bytes.push(n);
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.u8(b as u8);
+    }
+
+    fn u32(&mut self, n: u32) {
+        self.bytes.extend(n.to_le_bytes());
+    }
+
+    /// 環境によって`usize`の幅が違うので、常に8バイトで書く
+    fn usize(&mut self, n: usize) {
+        self.bytes.extend((n as u64).to_le_bytes());
+    }
+
+    fn char(&mut self, c: char) {
+        self.u32(c as u32);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.usize(s.len());
+        self.bytes.extend(s.as_bytes());
+    }
+
+    fn insts(&mut self, insts: &[Instruction]) {
+        self.usize(insts.len());
+        for inst in insts {
+            self.inst(inst);
+        }
+    }
+
+    fn inst(&mut self, inst: &Instruction) {
+        match inst {
+            Instruction::Char(c) => {
+                self.u8(0);
+                self.char(*c);
+            }
+            Instruction::Match => self.u8(1),
+            Instruction::Jump(addr) => {
+                self.u8(2);
+                self.usize(*addr);
+            }
+            Instruction::Split(addr1, addr2) => {
+                self.u8(3);
+                self.usize(*addr1);
+                self.usize(*addr2);
+            }
+            Instruction::Any => self.u8(4),
+            Instruction::Start => self.u8(5),
+            Instruction::End => self.u8(6),
+            Instruction::LineStart => self.u8(7),
+            Instruction::LineEnd => self.u8(8),
+            Instruction::WordBoundary => self.u8(9),
+            Instruction::CharClass(class) => {
+                self.u8(10);
+                self.bool(class.negated);
+                self.usize(class.ranges.len());
+                for (lo, hi) in &class.ranges {
+                    self.char(*lo);
+                    self.char(*hi);
+                }
+            }
+            Instruction::RangeChar(lo, hi) => {
+                self.u8(11);
+                self.char(*lo);
+                self.char(*hi);
+            }
+            Instruction::Property(prop) => {
+                self.u8(12);
+                let category = match prop.category {
+                    Category::Letter => 0,
+                    Category::UppercaseLetter => 1,
+                    Category::LowercaseLetter => 2,
+                    Category::Number => 3,
+                };
+                self.u8(category);
+                self.bool(prop.negated);
+            }
+            Instruction::Atomic(addr) => {
+                self.u8(13);
+                self.usize(*addr);
+            }
+            Instruction::LookAhead(addr) => {
+                self.u8(14);
+                self.usize(*addr);
+            }
+            Instruction::NegLookAhead(addr) => {
+                self.u8(15);
+                self.usize(*addr);
+            }
+            Instruction::LookBehind(width, addr) => {
+                self.u8(16);
+                self.usize(*width);
+                self.usize(*addr);
+            }
+            Instruction::NegLookBehind(width, addr) => {
+                self.u8(17);
+                self.usize(*width);
+                self.usize(*addr);
+            }
+            Instruction::Save(n) => {
+                self.u8(18);
+                self.usize(*n);
+            }
+            Instruction::BackRef(n) => {
+                self.u8(19);
+                self.usize(*n);
+            }
+            Instruction::StringMatch(s) => {
+                self.u8(20);
+                self.usize(s.chars().count());
+                for c in s.chars() {
+                    self.char(c);
+                }
+            }
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], DeserializeError> {
+        let Some((head, rest)) = self.bytes.split_first_chunk::<N>() else {
+            return Err(DeserializeError::Truncated);
+        };
+        self.bytes = rest;
+        Ok(*head)
+    }
+
+    fn u8(&mut self) -> Result<u8, DeserializeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn bool(&mut self) -> Result<bool, DeserializeError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(DeserializeError::InvalidValue),
+        }
+    }
+
+    fn u32(&mut self) -> Result<u32, DeserializeError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn usize(&mut self) -> Result<usize, DeserializeError> {
+        let n = u64::from_le_bytes(self.take()?);
+        usize::try_from(n).map_err(|_| DeserializeError::InvalidValue)
+    }
+
+    fn char(&mut self) -> Result<char, DeserializeError> {
+        let n = self.u32()?;
+        char::from_u32(n).ok_or(DeserializeError::InvalidChar(n))
+    }
+
+    fn str(&mut self) -> Result<String, DeserializeError> {
+        let len = self.usize()?;
+        let Some((head, rest)) = self.bytes.split_at_checked(len) else {
+            return Err(DeserializeError::Truncated);
+        };
+        self.bytes = rest;
+        String::from_utf8(head.to_vec()).map_err(|_| DeserializeError::InvalidValue)
+    }
+
+    /// 先頭の目印とバージョンを確かめる
+    fn header(&mut self, magic: &[u8; 4]) -> Result<(), DeserializeError> {
+        if &self.take::<4>()? != magic {
+            return Err(DeserializeError::InvalidMagic);
+        }
+        let version = self.u8()?;
+        if version != VERSION {
+            return Err(DeserializeError::UnsupportedVersion(version));
+        }
+        Ok(())
+    }
+
+    /// 命令列を読み込み、構造が正しいか確かめる
+    fn insts(&mut self) -> Result<Vec<Instruction>, DeserializeError> {
+        let len = self.usize()?;
+        // 命令は最低でも1バイトある
+        if len > self.bytes.len() {
+            return Err(DeserializeError::Truncated);
+        }
+        let mut insts = Vec::with_capacity(len);
+        for _ in 0..len {
+            insts.push(self.inst()?);
+        }
+        validate(&insts)?;
+        Ok(insts)
+    }
+
+    /// 全部読み終わっているか確かめる
+    fn finish(&self) -> Result<(), DeserializeError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(DeserializeError::TrailingBytes)
+        }
+    }
+
+    fn inst(&mut self) -> Result<Instruction, DeserializeError> {
+        let inst = match self.u8()? {
+            0 => Instruction::Char(self.char()?),
+            1 => Instruction::Match,
+            2 => Instruction::Jump(self.usize()?),
+            3 => Instruction::Split(self.usize()?, self.usize()?),
+            4 => Instruction::Any,
+            5 => Instruction::Start,
+            6 => Instruction::End,
+            7 => Instruction::LineStart,
+            8 => Instruction::LineEnd,
+            9 => Instruction::WordBoundary,
+            10 => {
+                let negated = self.bool()?;
+                let len = self.usize()?;
+                // 壊れた長さで巨大な領域を確保しないよう、残りのバイト数で確かめる
+                if len > self.bytes.len() / 8 {
+                    return Err(DeserializeError::Truncated);
+                }
+                let mut ranges = Vec::with_capacity(len);
+                for _ in 0..len {
+                    ranges.push((self.char()?, self.char()?));
+                }
+                Instruction::CharClass(CharClass { ranges, negated })
+            }
+            11 => Instruction::RangeChar(self.char()?, self.char()?),
+            12 => {
+                let category = match self.u8()? {
+                    0 => Category::Letter,
+                    1 => Category::UppercaseLetter,
+                    2 => Category::LowercaseLetter,
+                    3 => Category::Number,
+                    _ => return Err(DeserializeError::InvalidValue),
+                };
+                let negated = self.bool()?;
+                Instruction::Property(Property { category, negated })
+            }
+            13 => Instruction::Atomic(self.usize()?),
+            14 => Instruction::LookAhead(self.usize()?),
+            15 => Instruction::NegLookAhead(self.usize()?),
+            16 => Instruction::LookBehind(self.usize()?, self.usize()?),
+            17 => Instruction::NegLookBehind(self.usize()?, self.usize()?),
+            18 => Instruction::Save(self.usize()?),
+            19 => Instruction::BackRef(self.usize()?),
+            20 => {
+                let len = self.usize()?;
+                if len > self.bytes.len() / 4 {
+                    return Err(DeserializeError::Truncated);
+                }
+                let s = (0..len)
+                    .map(|_| self.char())
+                    .collect::<Result<String, _>>()?;
+                Instruction::StringMatch(s)
+            }
+            tag => return Err(DeserializeError::InvalidTag(tag)),
+        };
+        Ok(inst)
+    }
+}
+
+/// コンパイルした命令列をバイト列にする
+///
+/// ```
+/// use regex_machine::engine::{deserialize, serialize, Instruction};
+/// let insts = vec![Instruction::Char('a'), Instruction::Match];
+/// assert_eq!(deserialize(&serialize(&insts)).unwrap(), insts);
+/// ```
+pub fn serialize(insts: &[Instruction]) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend(MAGIC);
+    writer.u8(VERSION);
+    writer.insts(insts);
+    writer.bytes
+}
+
+/// `serialize`で作ったバイト列から命令列を読み込む
+///
+/// 途中で終わっていたり、命令列の外へ飛ぶアドレスや評価が終わらなくなる構造がある場合はエラー
+pub fn deserialize(bytes: &[u8]) -> Result<Vec<Instruction>, DeserializeError> {
+    let mut reader = Reader { bytes };
+    reader.header(MAGIC)?;
+    let insts = reader.insts()?;
+    reader.finish()?;
+    Ok(insts)
+}
+
+/// `Regex`を作るのに必要なもの。元のパターン、命令列、フラグ、名前付きグループの名前と番号
+pub(crate) type RegexParts = (String, Vec<Instruction>, Flags, HashMap<String, usize>);
+
+/// `Regex`の中身をバイト列にする。名前付きグループは番号順に書くので、同じ`Regex`からは同じバイト列になる
+pub(crate) fn serialize_regex(
+    expr: &str,
+    insts: &[Instruction],
+    flags: &Flags,
+    names: &HashMap<String, usize>,
+) -> Vec<u8> {
+    let mut writer = Writer { bytes: Vec::new() };
+    writer.bytes.extend(REGEX_MAGIC);
+    writer.u8(VERSION);
+    writer.str(expr);
+    writer.insts(insts);
+    for flag in [
+        flags.case_insensitive,
+        flags.extended,
+        flags.dot_all,
+        flags.multi_line,
+    ] {
+        writer.bool(flag);
+    }
+    let mut names = names.iter().collect::<Vec<_>>();
+    names.sort_by_key(|(_, n)| **n);
+    writer.usize(names.len());
+    for (name, n) in names {
+        writer.str(name);
+        writer.usize(*n);
+    }
+    writer.bytes
+}
+
+/// `serialize_regex`で作ったバイト列から`Regex`の中身を読み込む
+pub(crate) fn deserialize_regex(bytes: &[u8]) -> Result<RegexParts, DeserializeError> {
+    let mut reader = Reader { bytes };
+    reader.header(REGEX_MAGIC)?;
+    let expr = reader.str()?;
+    let insts = reader.insts()?;
+    let flags = Flags {
+        case_insensitive: reader.bool()?,
+        extended: reader.bool()?,
+        dot_all: reader.bool()?,
+        multi_line: reader.bool()?,
+    };
+    let len = reader.usize()?;
+    // 名前は最低でも長さの8バイトある
+    if len > reader.bytes.len() / 8 {
+        return Err(DeserializeError::Truncated);
+    }
+    let mut names = HashMap::new();
+    for _ in 0..len {
+        let name = reader.str()?;
+        let n = reader.usize()?;
+        // 0番目のグループはマッチ全体なので、名前は付かない
+        if n == 0 || names.insert(name, n).is_some() {
+            return Err(DeserializeError::InvalidValue);
+        }
+    }
+    reader.finish()?;
+    Ok((expr, insts, flags, names))
+}
+
+/// 先読みなどの中身を評価する命令の、中身の次の位置
+fn sub_program_end(inst: &Instruction) -> Option<usize> {
+    match inst {
+        Instruction::Atomic(addr)
+        | Instruction::LookAhead(addr)
+        | Instruction::NegLookAhead(addr)
+        | Instruction::LookBehind(_, addr)
+        | Instruction::NegLookBehind(_, addr) => Some(*addr),
+        _ => None,
+    }
+}
+
+/// 命令列の構造が、評価器で評価できるものか確かめる
+///
+/// - 飛び先が命令列の中にある
+/// - 先読みなどの中身は次の行から始まって`match`で終わり、中身の命令は中身の外へ飛ばない
+/// - 入力を読まずにジャンプだけで元の位置に戻ってくる輪がない
+fn validate(insts: &[Instruction]) -> Result<(), DeserializeError> {
+    for inst in insts {
+        let addrs = match inst {
+            Instruction::Jump(addr) => vec![*addr],
+            Instruction::Split(addr1, addr2) => vec![*addr1, *addr2],
+            inst => sub_program_end(inst).into_iter().collect(),
+        };
+        if let Some(addr) = addrs.into_iter().find(|addr| *addr >= insts.len()) {
+            return Err(DeserializeError::InvalidAddress(addr));
+        }
+    }
+
+    // 各命令がどの中身に含まれるか。0は一番外側で、中身には1から順に番号を振る
+    let mut regions = vec![0; insts.len()];
+    // 評価中の中身の`(番号, 終わりの次の位置)`
+    let mut stack = vec![(0, insts.len())];
+    let mut count = 0;
+    for (pc, inst) in insts.iter().enumerate() {
+        while stack.last().is_some_and(|(_, end)| pc >= *end) {
+            stack.pop();
+        }
+        let Some(&(region, outer_end)) = stack.last() else {
+            return Err(DeserializeError::InvalidSubProgram(pc));
+        };
+        regions[pc] = region;
+        if let Some(end) = sub_program_end(inst) {
+            // 中身は1行以上あり、外側の中身の`match`より前で終わる
+            if end <= pc + 1 || end >= outer_end || !matches!(insts[end - 1], Instruction::Match) {
+                return Err(DeserializeError::InvalidSubProgram(pc));
+            }
+            count += 1;
+            stack.push((count, end));
+        }
+    }
+    for (pc, inst) in insts.iter().enumerate() {
+        let addrs = match inst {
+            Instruction::Jump(addr) => vec![*addr],
+            Instruction::Split(addr1, addr2) => vec![*addr1, *addr2],
+            _ => vec![],
+        };
+        if addrs.into_iter().any(|addr| regions[addr] != regions[pc]) {
+            return Err(DeserializeError::InvalidSubProgram(pc));
+        }
+    }
+
+    // `Split`を通らずにたどる次の位置。入力を読む命令や分岐する命令、`match`ではたどるのをやめる
+    let next = |pc: usize| match &insts[pc] {
+        Instruction::Jump(addr) => Some(*addr),
+        Instruction::Save(_)
+        | Instruction::Start
+        | Instruction::End
+        | Instruction::LineStart
+        | Instruction::LineEnd
+        | Instruction::WordBoundary => Some(pc + 1).filter(|next| *next < insts.len()),
+        inst => sub_program_end(inst),
+    };
+    // 0は未訪問、1はたどっている途中、2は輪がないと分かった位置
+    let mut state = vec![0_u8; insts.len()];
+    for start in 0..insts.len() {
+        let mut path = Vec::new();
+        let mut pc = Some(start);
+        while let Some(p) = pc.filter(|p| state[*p] != 2) {
+            if state[p] == 1 {
+                return Err(DeserializeError::EmptyLoop(p));
+            }
+            state[p] = 1;
+            path.push(p);
+            pc = next(p);
+        }
+        for p in path {
+            state[p] = 2;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        codegen::get_code,
+        evaluator::{eval, EvalConfig},
+        parser::parse,
+        Flags, Strategy,
+    };
+    use alloc::string::ToString;
+
+    #[test]
+    fn round_trip() {
+        let regex = r"^(?<y>[0-9]{4})-([0-9][0-9])?(?=x|[^a-c]+)(?<!\p{Lu})\b(.*?)\k<y>$";
+        let insts = get_code(&parse(regex).unwrap()).unwrap();
+
+        let bytes = serialize(&insts);
+        let loaded = deserialize(&bytes).unwrap();
+
+        assert_eq!(loaded, insts);
+
+        let line = "2024-01xyz2024".chars().collect::<Vec<_>>();
+        assert!(eval(
+            &loaded,
+            &line,
+            Strategy::Depth,
+            &Flags::default(),
+            &EvalConfig::default()
+        )
+        .unwrap());
+
+        let insts = vec![Instruction::StringMatch("héllo".to_string())];
+        assert_eq!(deserialize(&serialize(&insts)).unwrap(), insts);
+
+        // 入れ子になった先読みや、空文字列にマッチする繰り返しも読み込める
+        for regex in [
+            r"(?=a(?!b(?<=c)))d",
+            r"(?>a*)+b",
+            r"(?:)*b",
+            r"(?:a?)*?",
+            r"(?!)",
+        ] {
+            let insts = get_code(&parse(regex).unwrap()).unwrap();
+            assert_eq!(deserialize(&serialize(&insts)).unwrap(), insts, "{regex}");
+        }
+    }
+
+    #[test]
+    fn invalid_structure() {
+        use Instruction::*;
+
+        // 中身の外へ飛ぶ
+        let bytes = serialize(&[Atomic(3), Jump(0), Match, Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::InvalidSubProgram(1)
+        );
+        // 外から中身へ飛ぶ
+        let bytes = serialize(&[Jump(2), LookAhead(4), Char('a'), Match, Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::InvalidSubProgram(0)
+        );
+        // 中身が後ろを指していない
+        let bytes = serialize(&[Char('a'), LookAhead(0), Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::InvalidSubProgram(1)
+        );
+        // 中身が`match`で終わっていない
+        let bytes = serialize(&[NegLookAhead(2), Char('a'), Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::InvalidSubProgram(0)
+        );
+        // 中身が外側の中身からはみ出している
+        let bytes = serialize(&[Atomic(4), LookAhead(4), Match, Match, Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::InvalidSubProgram(1)
+        );
+
+        // 入力を読まずに回り続ける
+        let bytes = serialize(&[Save(0), Jump(0), Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::EmptyLoop(0)
+        );
+        let bytes = serialize(&[Jump(0)]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::EmptyLoop(0)
+        );
+        // `Split`を挟む輪は、評価器が打ち切れる
+        let bytes = serialize(&[Split(1, 2), Jump(0), Match]);
+        assert!(deserialize(&bytes).is_ok());
+    }
+
+    #[test]
+    fn invalid_input() {
+        let insts = get_code(&parse(r"a|[bc]d").unwrap()).unwrap();
+        let bytes = serialize(&insts);
+
+        // どこで切っても、途中で終わっていることが分かる
+        for len in 5..bytes.len() {
+            assert_eq!(
+                deserialize(&bytes[..len]).err().unwrap(),
+                DeserializeError::Truncated,
+                "{len}"
+            );
+        }
+
+        let mut extra = bytes.clone();
+        extra.push(0);
+        assert_eq!(
+            deserialize(&extra).err().unwrap(),
+            DeserializeError::TrailingBytes
+        );
+
+        assert_eq!(
+            deserialize(b"XXXX\x01").err().unwrap(),
+            DeserializeError::InvalidMagic
+        );
+
+        let mut version = bytes.clone();
+        version[4] = 99;
+        assert_eq!(
+            deserialize(&version).err().unwrap(),
+            DeserializeError::UnsupportedVersion(99)
+        );
+
+        let bytes = serialize(&[Instruction::Jump(5), Instruction::Match]);
+        assert_eq!(
+            deserialize(&bytes).err().unwrap(),
+            DeserializeError::InvalidAddress(5)
+        );
+
+        let mut tag = serialize(&[Instruction::Match]);
+        let last = tag.len() - 1;
+        tag[last] = 200;
+        assert_eq!(
+            deserialize(&tag).err().unwrap(),
+            DeserializeError::InvalidTag(200)
+        );
+
+        let mut surrogate = serialize(&[Instruction::Char('a')]);
+        let last = surrogate.len() - 4;
+        surrogate[last..].copy_from_slice(&0xD800_u32.to_le_bytes());
+        assert_eq!(
+            deserialize(&surrogate).err().unwrap(),
+            DeserializeError::InvalidChar(0xD800)
+        );
+    }
+}
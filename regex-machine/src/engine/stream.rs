@@ -0,0 +1,188 @@
+use super::{
+    class::is_word_char,
+    dfa::closure,
+    evaluator::{eq_char, in_class, in_property, in_range, is_any, EvalError},
+    Flags, Instruction,
+};
+use alloc::{vec, vec::Vec};
+
+/// 入力を前から1文字ずつ読むときの、今の位置の前後の文字
+struct Context {
+    prev: Option<char>,
+    next: Option<char>,
+}
+
+/// 前の文字を覚えておくだけで評価できるプログラムか
+///
+/// 先読み・後読み、アトミックグループ、後方参照は入力を戻って読む必要があるので使えない
+fn is_supported(insts: &[Instruction]) -> bool {
+    insts.iter().all(|inst| {
+        !matches!(
+            inst,
+            Instruction::Atomic(_)
+                | Instruction::LookAhead(_)
+                | Instruction::NegLookAhead(_)
+                | Instruction::LookBehind(..)
+                | Instruction::NegLookBehind(..)
+                | Instruction::BackRef(_)
+        )
+    })
+}
+
+impl Context {
+    /// 位置の条件`inst`を、この位置で満たすか
+    fn holds(&self, inst: &Instruction) -> bool {
+        match inst {
+            Instruction::Start => self.prev.is_none(),
+            Instruction::End => self.next.is_none(),
+            Instruction::LineStart => matches!(self.prev, None | Some('\n')),
+            Instruction::LineEnd => matches!(self.next, None | Some('\n')),
+            Instruction::WordBoundary => {
+                self.prev.as_ref().is_some_and(is_word_char)
+                    != self.next.as_ref().is_some_and(is_word_char)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// 入力を`Vec`に集めずに、イテレータから1文字ずつ読みながらPike VMで評価する
+///
+/// 読んだ文字は前の1文字しか覚えておかないので、大きな入力や少しずつ届く入力にも使える。
+/// マッチした時点で読むのをやめる
+pub fn eval_iter(
+    insts: &[Instruction],
+    line: impl IntoIterator<Item = char>,
+    flags: &Flags,
+) -> Result<bool, EvalError> {
+    if !is_supported(insts) {
+        return Err(EvalError::StreamNotSupported);
+    }
+
+    // 文字を読むたびに文字列を数え直さないよう、先に文字の列にしておく
+    let literals: Vec<Vec<char>> = insts
+        .iter()
+        .map(|inst| match inst {
+            Instruction::StringMatch(s) => s.chars().collect(),
+            _ => Vec::new(),
+        })
+        .collect();
+    // `StringMatch`の途中のスレッドを重複なく持つため、各行の文字列の開始位置を決めておく
+    let mut bases = Vec::with_capacity(literals.len());
+    let mut total = 0;
+    for literal in &literals {
+        bases.push(total);
+        total += literal.len();
+    }
+
+    let mut line = line.into_iter().peekable();
+    let mut context = Context {
+        prev: None,
+        next: line.peek().copied(),
+    };
+    let mut threads = Vec::new();
+    if closure(
+        insts,
+        0,
+        &mut vec![false; insts.len()],
+        &mut threads,
+        |inst| context.holds(inst),
+    )? {
+        return Ok(true);
+    }
+
+    while let Some(c) = line.next() {
+        if threads.is_empty() {
+            return Ok(false);
+        }
+        context = Context {
+            prev: Some(c),
+            next: line.peek().copied(),
+        };
+        let mut visited = vec![false; insts.len()];
+        let mut advanced = vec![false; total];
+        let mut next = Vec::new();
+
+        for (pc, offset) in threads {
+            let is_next = match &insts[pc] {
+                Instruction::Char(expected) => eq_char(expected, &c, flags),
+                Instruction::StringMatch(_) => {
+                    let literal = &literals[pc];
+                    let Some(expected) = literal.get(offset) else {
+                        return Err(EvalError::InvalidContext);
+                    };
+                    if !eq_char(expected, &c, flags) {
+                        continue;
+                    }
+                    // 文字列の途中なら、次の文字へ進むだけ
+                    if offset + 1 < literal.len() {
+                        if !core::mem::replace(&mut advanced[bases[pc] + offset + 1], true) {
+                            next.push((pc, offset + 1));
+                        }
+                        continue;
+                    }
+                    true
+                }
+                Instruction::CharClass(class) => in_class(class, &c, flags),
+                Instruction::RangeChar(lo, hi) => in_range(lo, hi, &c, flags),
+                Instruction::Property(prop) => in_property(prop, &c, flags),
+                Instruction::Any => is_any(&c, flags),
+                _ => return Err(EvalError::InvalidContext),
+            };
+            if is_next
+                && closure(insts, pc + 1, &mut visited, &mut next, |inst| {
+                    context.holds(inst)
+                })?
+            {
+                return Ok(true);
+            }
+        }
+        threads = next;
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::engine::testing::{assert_same_as_pike, to_insts, PATTERNS};
+
+    use super::*;
+
+    #[test]
+    fn same_as_pike() {
+        let patterns = PATTERNS.into_iter().chain([r"\bé+\b"]);
+        assert_same_as_pike(patterns, |insts, line, flags| {
+            eval_iter(insts, line.chars(), flags)
+        });
+    }
+
+    #[test]
+    fn iterator_adapter() {
+        let flags = Flags::default();
+        let insts = to_insts("(ab)+c$");
+
+        let line = "ab".chars().cycle().take(100000).chain(['c']);
+        assert_eq!(eval_iter(&insts, line, &flags), Ok(true));
+
+        let line = "ab".chars().cycle().take(100000).chain(['c', 'd']);
+        assert_eq!(eval_iter(&insts, line, &flags), Ok(false));
+
+        // マッチした時点で読むのをやめるので、終わらない入力でもよい
+        let insts = to_insts("a+b");
+        let line = "aab".chars().chain(core::iter::repeat('x'));
+        assert_eq!(eval_iter(&insts, line, &flags), Ok(true));
+    }
+
+    #[test]
+    fn not_supported() {
+        let flags = Flags::default();
+        for pattern in ["(?=a)a", "(?<=a)b", r"(a)\1", "(?>a)"] {
+            assert_eq!(
+                eval_iter(&to_insts(pattern), "ab".chars(), &flags),
+                Err(EvalError::StreamNotSupported),
+                "{pattern}"
+            );
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use alloc::vec::Vec;
+
+use super::{
+    codegen,
+    evaluator::{eval, EvalConfig, EvalError, Strategy},
+    optimize, parser, Flags, Instruction,
+};
+
+/// 評価器を比べるときに使うパターン
+pub const PATTERNS: [&str; 12] = [
+    "abc|(de|cd)+",
+    r"^[[:word:]]+ [0-9]{2,3}$",
+    "(?i)[a-c]+?D",
+    r"a.c\b",
+    "(?m)^b$",
+    "(?m)a$\n^b",
+    "(a|b)*abb",
+    "x(yz)*",
+    "(a*)*b",
+    r"\p{Lu}+[0-9]?",
+    "(?i)ab(cd)?",
+    "",
+];
+
+/// 評価器を比べるときに使う入力
+pub const LINES: [&str; 17] = [
+    "abc", "decddede", "foo 123", "AbD", "abc d", "abcd", "a\nb", "aab", "ababb", "ccd", "xyzyz",
+    "aaab", "AB1", "aBCd", "XY", "éé", "",
+];
+
+/// パターンを最適化してコードを生成する。パターン中のフラグは無視する
+pub fn to_insts(regex: &str) -> Vec<Instruction> {
+    let ast = optimize::optimize(parser::parse(regex).unwrap());
+    codegen::get_code(&ast).unwrap()
+}
+
+/// `patterns`と`LINES`のすべての組で、`eval_with`がPike VMと同じ結果を返すか確かめる
+///
+/// `eval_with`にはコードと入力と、パターン中で指定されたフラグを渡す
+pub fn assert_same_as_pike<'a>(
+    patterns: impl IntoIterator<Item = &'a str>,
+    mut eval_with: impl FnMut(&[Instruction], &str, &Flags) -> Result<bool, EvalError>,
+) {
+    for pattern in patterns {
+        let mut flags = Flags::default();
+        let ast = parser::parse_with_flags(pattern, &mut flags).unwrap();
+        let insts = codegen::get_code(&optimize::optimize(ast)).unwrap();
+        for line in LINES {
+            let chars = line.chars().collect::<Vec<_>>();
+            let expected = eval(
+                &insts,
+                &chars,
+                Strategy::Pike,
+                &flags,
+                &EvalConfig::default(),
+            );
+            assert_eq!(
+                eval_with(&insts, line, &flags),
+                expected,
+                "{pattern} {line:?}"
+            );
+        }
+    }
+}
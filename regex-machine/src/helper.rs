@@ -1,3 +1,9 @@
+// `std`がないときは、ハッシュの代わりに順序で引く`alloc`のコレクションを使う
+#[cfg(not(feature = "std"))]
+pub use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
+#[cfg(feature = "std")]
+pub use std::collections::{HashMap, HashSet};
+
 pub trait SafeAdd: Sized {
     fn safe_add(&self, n: &Self) -> Option<Self>;
 }
@@ -21,9 +27,6 @@ where
     }
 }
 
-// `Send`と`Sync`があるのでマルチスレッドで共有可能。かつ`static`ライフタイム境界なので、`static`でない参照を持たない
-pub type DynError = Box<dyn std::error::Error + Send + Sync + 'static>;
-
 #[cfg(test)]
 mod tests {
     use super::*;
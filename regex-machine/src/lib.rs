@@ -1,14 +1,23 @@
 //! 正規表現用エンジン
 //!
 //! ```
-//! use regex_machine::{do_matching,print};
+//! use regex_machine::do_matching;
 //! let expr = "abc|(de|cd)+";
 //! let line = "decddede";
 //! assert!(do_matching(expr,line,true).unwrap());
-//! assert!(print(expr).is_ok());
+//! #[cfg(feature = "std")]
+//! assert!(regex_machine::print(expr).is_ok());
 //! ```
+//!
+//! `std`フィーチャを無効にすると、`no_std`と`alloc`だけで使える
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
 pub mod engine;
 mod helper;
 
-pub use engine::{do_matching, print};
+#[cfg(feature = "std")]
+pub use engine::print;
+pub use engine::{do_matching, search, Regex, RegexError};